@@ -0,0 +1,472 @@
+use std::{collections::HashSet, f64::consts::PI};
+
+use nalgebra::{Matrix3, Vector2, Vector3};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+use crate::{
+    geometry::{wrap_angle, Transform2d},
+    kdtree::KdTree2d,
+    occupancy_grid::{OccupancyGrid, FREE_THRESHOLD, OCCUPIED_THRESHOLD},
+};
+
+/// mostly the same parameters (and defaults) as ros amcl
+#[derive(Debug, Clone)]
+pub struct AmclParams {
+    pub min_particles: usize,
+    pub max_particles: usize,
+    /// max error between the true distribution and the particle approximation for kld sampling
+    pub kld_error: f64,
+    /// upper standard normal quantile for the kld bound, 2.326 is 99%
+    pub kld_z: f64,
+    /// histogram bin sizes used to count how spread out the particles are for kld sampling
+    pub kld_bin_meters: f64,
+    pub kld_bin_radians: f64,
+    /// rotation noise from rotation
+    pub alpha1: f64,
+    /// rotation noise from translation
+    pub alpha2: f64,
+    /// translation noise from translation
+    pub alpha3: f64,
+    /// translation noise from rotation
+    pub alpha4: f64,
+    pub z_hit: f64,
+    pub z_rand: f64,
+    pub sigma_hit: f64,
+    pub max_range_meters: f64,
+    /// only this many evenly spaced beams of every scan are used
+    pub max_beams: usize,
+    /// distances in the likelihood field are capped at this, further away cells all look the same
+    pub likelihood_max_distance: f64,
+    /// decay rates of the long and short term average likelihoods. random particles get injected when the short term one drops below the long term one, which is what lets us recover from kidnapping.
+    /// alpha_slow is 10x the ros default, otherwise it takes tens of meters of driving before a kidnapping gets noticed
+    pub alpha_slow: f64,
+    pub alpha_fast: f64,
+    /// the filter only updates after the robot moved this much
+    pub update_min_meters: f64,
+    pub update_min_radians: f64,
+    pub resample_interval: usize,
+}
+
+impl Default for AmclParams {
+    fn default() -> Self {
+        Self {
+            min_particles: 500,
+            max_particles: 5000,
+            kld_error: 0.01,
+            kld_z: 2.326,
+            kld_bin_meters: 0.5,
+            kld_bin_radians: 10.0_f64.to_radians(),
+            alpha1: 0.2,
+            alpha2: 0.2,
+            alpha3: 0.2,
+            alpha4: 0.2,
+            z_hit: 0.95,
+            z_rand: 0.05,
+            sigma_hit: 0.2,
+            max_range_meters: 12.0,
+            max_beams: 60,
+            likelihood_max_distance: 2.0,
+            alpha_slow: 0.01,
+            alpha_fast: 0.1,
+            update_min_meters: 0.05,
+            update_min_radians: 0.1,
+            resample_interval: 1,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Particle {
+    pub pose: Transform2d,
+    pub weight: f64,
+}
+
+#[derive(Debug, Clone)]
+pub struct AmclEstimate {
+    pub pose: Transform2d,
+    /// weighted covariance of the particles in x, y, theta
+    pub covariance: Matrix3<f64>,
+}
+
+impl AmclEstimate {
+    /// (x, y, theta) std devs for PoseEstimator::add_vision_measurement, which ignores the correlations
+    pub fn std_devs(&self) -> (f64, f64, f64) {
+        (self.covariance[(0, 0)].sqrt(), self.covariance[(1, 1)].sqrt(), self.covariance[(2, 2)].sqrt())
+    }
+}
+
+/// distance from every cell to the closest occupied cell, precomputed once per map
+pub struct LikelihoodField {
+    origin: Vector2<f64>,
+    resolution_meters: f64,
+    width: usize,
+    height: usize,
+    distances: Vec<f32>,
+    max_distance: f64,
+    /// centers of the cells that are known to be free, global localization samples from these
+    free_cells: Vec<Vector2<f64>>,
+}
+
+impl LikelihoodField {
+    pub fn new(grid: &OccupancyGrid, max_distance: f64) -> Self {
+        let mut occupied = Vec::new();
+        let mut free_cells = Vec::new();
+        for y in 0..grid.height() as i64 {
+            for x in 0..grid.width() as i64 {
+                if !grid.is_known(x, y) {
+                    continue;
+                }
+                let probability = grid.probability(x, y);
+                if probability > OCCUPIED_THRESHOLD {
+                    occupied.push(grid.cell_to_world(x, y));
+                } else if probability < FREE_THRESHOLD {
+                    free_cells.push(grid.cell_to_world(x, y));
+                }
+            }
+        }
+        let tree = KdTree2d::new(&occupied);
+        let mut distances = Vec::with_capacity(grid.width() * grid.height());
+        for y in 0..grid.height() as i64 {
+            for x in 0..grid.width() as i64 {
+                let distance = tree.nearest_within(&grid.cell_to_world(x, y), max_distance).map_or(max_distance, |(_, distance)| distance);
+                distances.push(distance as f32);
+            }
+        }
+        Self {
+            origin: Vector2::new(grid.config.origin_x_meters, grid.config.origin_y_meters),
+            resolution_meters: grid.config.resolution_meters,
+            width: grid.width(),
+            height: grid.height(),
+            distances,
+            max_distance,
+            free_cells,
+        }
+    }
+
+    /// distance to the closest obstacle, max_distance outside of the map
+    pub fn distance(&self, point: &Vector2<f64>) -> f64 {
+        let cell = (point - self.origin) / self.resolution_meters;
+        let (x, y) = (cell[0].floor(), cell[1].floor());
+        if x < 0.0 || y < 0.0 || x >= self.width as f64 || y >= self.height as f64 {
+            self.max_distance
+        } else {
+            self.distances[y as usize * self.width + x as usize] as f64
+        }
+    }
+}
+
+/// adaptive monte carlo localization against a fixed occupancy grid
+pub struct Amcl {
+    pub params: AmclParams,
+    field: LikelihoodField,
+    particles: Vec<Particle>,
+    w_slow: f64,
+    w_fast: f64,
+    /// odometry pose at the last filter update
+    last_odom: Option<Transform2d>,
+    updates_since_resample: usize,
+    rng: StdRng,
+}
+
+impl Amcl {
+    /// particles start spread over all of the free space in the map, use set_pose if the starting pose is roughly known
+    pub fn new(grid: &OccupancyGrid, params: AmclParams, seed: u64) -> Self {
+        let mut res = Self {
+            field: LikelihoodField::new(grid, params.likelihood_max_distance),
+            params,
+            particles: Vec::new(),
+            w_slow: 0.0,
+            w_fast: 0.0,
+            last_odom: None,
+            updates_since_resample: 0,
+            rng: StdRng::seed_from_u64(seed),
+        };
+        res.global_localization();
+        res
+    }
+
+    pub fn particles(&self) -> &[Particle] {
+        &self.particles
+    }
+
+    /// throws away everything we know and spreads max_particles over the free space
+    pub fn global_localization(&mut self) {
+        let weight = 1.0 / self.params.max_particles as f64;
+        self.particles = (0..self.params.max_particles).map(|_| Particle { pose: self.random_free_pose(), weight }).collect();
+    }
+
+    /// gaussian cloud of particles around pose
+    pub fn set_pose(&mut self, pose: &Transform2d, covariance: &Matrix3<f64>) {
+        let l = covariance.cholesky().map_or(Matrix3::from_diagonal(&covariance.diagonal().map(f64::sqrt)), |cholesky| cholesky.l());
+        let weight = 1.0 / self.params.max_particles as f64;
+        self.particles = (0..self.params.max_particles)
+            .map(|_| {
+                let noise = l * Vector3::new(sample_normal(&mut self.rng, 1.0), sample_normal(&mut self.rng, 1.0), sample_normal(&mut self.rng, 1.0));
+                Particle { pose: Transform2d::new(pose.x_meters + noise[0], pose.y_meters + noise[1], wrap_angle(pose.theta_radians + noise[2])), weight }
+            })
+            .collect();
+    }
+
+    /// odom_pose is the raw odometry pose when the scan (in the robot frame) was taken.
+    /// returns the new estimate if the robot moved far enough for the filter to update.
+    pub fn update(&mut self, odom_pose: &Transform2d, scan: &[Vector2<f64>]) -> Option<AmclEstimate> {
+        let Some(last_odom) = self.last_odom.clone() else {
+            self.last_odom = Some(odom_pose.clone());
+            self.sensor_update(scan);
+            return Some(self.estimate());
+        };
+        let delta = -last_odom.clone() + odom_pose.clone();
+        if delta.norm() < self.params.update_min_meters && wrap_angle(delta.theta_radians).abs() < self.params.update_min_radians {
+            return None;
+        }
+        self.motion_update(&last_odom, odom_pose);
+        self.last_odom = Some(odom_pose.clone());
+        self.sensor_update(scan);
+        // estimate before resampling, otherwise freshly injected random particles count as much as the ones that match the scan
+        let estimate = self.estimate();
+        self.updates_since_resample += 1;
+        if self.updates_since_resample >= self.params.resample_interval {
+            self.resample();
+            self.updates_since_resample = 0;
+        }
+        Some(estimate)
+    }
+
+    /// sample_motion_model_odometry from probabilistic robotics
+    fn motion_update(&mut self, prev_odom: &Transform2d, odom: &Transform2d) {
+        let dx = odom.x_meters - prev_odom.x_meters;
+        let dy = odom.y_meters - prev_odom.y_meters;
+        let trans = dx.hypot(dy);
+        // turning in place doesn't have a meaningful direction of travel
+        let rot1 = if trans < 0.01 { 0.0 } else { wrap_angle(dy.atan2(dx) - prev_odom.theta_radians) };
+        let rot2 = wrap_angle(odom.theta_radians - prev_odom.theta_radians - rot1);
+        // driving backwards shouldn't count as turning around twice
+        let rot1_noise = rot1.abs().min(wrap_angle(rot1 - PI).abs());
+        let rot2_noise = rot2.abs().min(wrap_angle(rot2 - PI).abs());
+        let AmclParams { alpha1, alpha2, alpha3, alpha4, .. } = self.params;
+        for particle in &mut self.particles {
+            let hat_rot1 = rot1 - sample_normal(&mut self.rng, (alpha1 * rot1_noise * rot1_noise + alpha2 * trans * trans).sqrt());
+            let hat_trans = trans - sample_normal(&mut self.rng, (alpha3 * trans * trans + alpha4 * (rot1_noise * rot1_noise + rot2_noise * rot2_noise)).sqrt());
+            let hat_rot2 = rot2 - sample_normal(&mut self.rng, (alpha1 * rot2_noise * rot2_noise + alpha2 * trans * trans).sqrt());
+            let pose = &mut particle.pose;
+            pose.x_meters += hat_trans * (pose.theta_radians + hat_rot1).cos();
+            pose.y_meters += hat_trans * (pose.theta_radians + hat_rot1).sin();
+            pose.theta_radians = wrap_angle(pose.theta_radians + hat_rot1 + hat_rot2);
+        }
+    }
+
+    /// likelihood field model from probabilistic robotics. the beams are multiplied together in log space so nothing underflows.
+    fn sensor_update(&mut self, scan: &[Vector2<f64>]) {
+        let step = (scan.len() / self.params.max_beams.max(1)).max(1);
+        let beams = scan.iter().step_by(step).filter(|point| point.norm() <= self.params.max_range_meters).collect::<Vec<_>>();
+        if beams.is_empty() {
+            return;
+        }
+        let AmclParams { z_hit, z_rand, sigma_hit, max_range_meters, .. } = self.params;
+        let log_likelihoods = self.particles.iter().map(|particle| {
+            beams.iter().map(|beam| {
+                let world_point = particle.pose.clone() + Transform2d::new(beam[0], beam[1], 0.0);
+                let distance = self.field.distance(&Vector2::new(world_point.x_meters, world_point.y_meters));
+                (z_hit * (-distance * distance / (2.0 * sigma_hit * sigma_hit)).exp() + z_rand / max_range_meters).ln()
+            }).sum::<f64>()
+        }).collect::<Vec<_>>();
+        let max_log_likelihood = log_likelihoods.iter().cloned().fold(f64::NEG_INFINITY, f64::max);
+        let mut total = 0.0;
+        // the per beam (geometric mean) likelihood is what gets tracked by w_slow and w_fast, it doesn't depend on how many beams there were
+        let mut w_avg = 0.0;
+        for (particle, log_likelihood) in self.particles.iter_mut().zip(&log_likelihoods) {
+            w_avg += particle.weight * (log_likelihood / beams.len() as f64).exp();
+            particle.weight *= (log_likelihood - max_log_likelihood).exp();
+            total += particle.weight;
+        }
+        for particle in &mut self.particles {
+            particle.weight /= total;
+        }
+        if self.w_slow == 0.0 {
+            self.w_slow = w_avg;
+        } else {
+            self.w_slow += self.params.alpha_slow * (w_avg - self.w_slow);
+        }
+        if self.w_fast == 0.0 {
+            self.w_fast = w_avg;
+        } else {
+            self.w_fast += self.params.alpha_fast * (w_avg - self.w_fast);
+        }
+    }
+
+    /// kld sampling: keep drawing particles until there are enough to cover the bins they fall into
+    fn resample(&mut self) {
+        let random_probability = (1.0 - self.w_fast / self.w_slow).max(0.0);
+        let mut cumulative = Vec::with_capacity(self.particles.len());
+        let mut sum = 0.0;
+        for particle in &self.particles {
+            sum += particle.weight;
+            cumulative.push(sum);
+        }
+        let mut new_particles: Vec<Particle> = Vec::with_capacity(self.params.min_particles);
+        let mut bins = HashSet::new();
+        let mut limit = self.params.min_particles;
+        while new_particles.len() < limit {
+            let pose = if self.rng.random::<f64>() < random_probability {
+                self.random_free_pose()
+            } else {
+                let target = self.rng.random::<f64>() * sum;
+                let index = cumulative.partition_point(|c| *c < target).min(self.particles.len() - 1);
+                self.particles[index].pose.clone()
+            };
+            bins.insert((
+                (pose.x_meters / self.params.kld_bin_meters).floor() as i64,
+                (pose.y_meters / self.params.kld_bin_meters).floor() as i64,
+                (pose.theta_radians / self.params.kld_bin_radians).floor() as i64,
+            ));
+            new_particles.push(Particle { pose, weight: 1.0 });
+            limit = kld_limit(bins.len(), self.params.kld_error, self.params.kld_z).clamp(self.params.min_particles, self.params.max_particles);
+        }
+        let weight = 1.0 / new_particles.len() as f64;
+        for particle in &mut new_particles {
+            particle.weight = weight;
+        }
+        // unlike ros amcl the averages aren't reset after injecting random particles, so injection keeps going until the scans match as well as they used to
+        self.particles = new_particles;
+    }
+
+    fn random_free_pose(&mut self) -> Transform2d {
+        let theta = self.rng.random_range(-PI..PI);
+        if self.field.free_cells.is_empty() {
+            return Transform2d::new(0.0, 0.0, theta);
+        }
+        let cell = self.field.free_cells[self.rng.random_range(0..self.field.free_cells.len())];
+        let half = self.field.resolution_meters / 2.0;
+        Transform2d::new(cell[0] + self.rng.random_range(-half..half), cell[1] + self.rng.random_range(-half..half), theta)
+    }
+
+    /// weighted mean and covariance of the particles
+    pub fn estimate(&self) -> AmclEstimate {
+        let total = self.particles.iter().map(|particle| particle.weight).sum::<f64>();
+        let (mut x, mut y, mut cos, mut sin) = (0.0, 0.0, 0.0, 0.0);
+        for particle in &self.particles {
+            let w = particle.weight / total;
+            x += w * particle.pose.x_meters;
+            y += w * particle.pose.y_meters;
+            cos += w * particle.pose.theta_radians.cos();
+            sin += w * particle.pose.theta_radians.sin();
+        }
+        let theta = sin.atan2(cos);
+        let mut covariance = Matrix3::zeros();
+        for particle in &self.particles {
+            let diff = Vector3::new(particle.pose.x_meters - x, particle.pose.y_meters - y, wrap_angle(particle.pose.theta_radians - theta));
+            covariance += diff * diff.transpose() * (particle.weight / total);
+        }
+        AmclEstimate { pose: Transform2d::new(x, y, theta), covariance }
+    }
+}
+
+/// number of particles needed so the kl divergence to the true distribution stays below error with probability given by z (fox 2003)
+fn kld_limit(bins: usize, error: f64, z: f64) -> usize {
+    if bins <= 1 {
+        return 0;
+    }
+    let k = (bins - 1) as f64;
+    let b = 2.0 / (9.0 * k);
+    (k / (2.0 * error) * (1.0 - b + b.sqrt() * z).powi(3)).ceil() as usize
+}
+
+/// box-muller, so we don't need rand_distr just for this
+fn sample_normal(rng: &mut StdRng, std: f64) -> f64 {
+    if std == 0.0 {
+        return 0.0;
+    }
+    let u1 = 1.0 - rng.random::<f64>();
+    let u2 = rng.random::<f64>();
+    std * (-2.0 * u1.ln()).sqrt() * (2.0 * PI * u2).cos()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        occupancy_grid::OccupancyGridConfig,
+        sim::{room_walls, simulate_scan, Wall},
+    };
+
+    fn room_map(walls: &[Wall]) -> OccupancyGrid {
+        let mut grid = OccupancyGrid::new(OccupancyGridConfig { origin_x_meters: -4.0, origin_y_meters: -3.0, width_cells: 160, height_cells: 120, ..Default::default() });
+        for pose in [Transform2d::new(0.0, 0.0, 0.0), Transform2d::new(2.0, -1.0, 1.0), Transform2d::new(-2.0, 1.0, 2.0), Transform2d::new(1.0, 1.5, -1.0), Transform2d::new(-2.0, -1.5, 0.5)] {
+            grid.integrate_scan(&pose, &simulate_scan(walls, &pose, 720, 12.0));
+        }
+        grid
+    }
+
+    /// drives the true pose along a square loop, feeding odometry and scans into the filter. returns the last estimate.
+    fn drive(amcl: &mut Amcl, walls: &[Wall], world_to_robot: &mut Transform2d, odom: &mut Transform2d, steps: usize) -> AmclEstimate {
+        let mut estimate = None;
+        for i in 0..steps {
+            // forward with a turn every few steps so the robot stays inside the room
+            let step = if i % 6 == 5 { Transform2d::new(0.0, 0.0, 0.6) } else { Transform2d::new(0.08, 0.0, 0.0) };
+            let next = world_to_robot.clone() + step.clone();
+            if next.x_meters.abs() < 2.5 && next.y_meters.abs() < 1.6 && !(next.x_meters > 1.6 && next.y_meters > 0.7) && !(next.x_meters > -1.1 && next.x_meters < -0.4 && next.y_meters > -0.8 && next.y_meters < -0.1) {
+                *world_to_robot = next;
+                *odom = odom.clone() + step;
+            } else {
+                *world_to_robot = world_to_robot.clone() + Transform2d::new(0.0, 0.0, 0.6);
+                *odom = odom.clone() + Transform2d::new(0.0, 0.0, 0.6);
+            }
+            if let Some(new_estimate) = amcl.update(odom, &simulate_scan(walls, world_to_robot, 360, 12.0)) {
+                estimate = Some(new_estimate);
+            }
+        }
+        estimate.unwrap()
+    }
+
+    fn assert_close(estimate: &AmclEstimate, truth: &Transform2d, tolerance_meters: f64) {
+        let error = -truth.clone() + estimate.pose.clone();
+        assert!(error.norm() < tolerance_meters && wrap_angle(error.theta_radians).abs() < 0.1, "estimate {:?} too far from {:?}", estimate.pose, truth);
+    }
+
+    #[test]
+    fn test_kld_limit() {
+        assert_eq!(kld_limit(1, 0.01, 2.326), 0);
+        // more bins need more particles
+        assert!(kld_limit(10, 0.01, 2.326) < kld_limit(100, 0.01, 2.326));
+        assert_eq!(kld_limit(100, 0.01, 2.326), 6733);
+    }
+
+    #[test]
+    fn test_likelihood_field() {
+        let field = LikelihoodField::new(&room_map(&room_walls()), 2.0);
+        assert!(field.distance(&Vector2::new(2.99, 0.0)) < 0.1);
+        assert!((field.distance(&Vector2::new(2.0, -1.0)) - 0.9).abs() < 0.1);
+        assert_eq!(field.distance(&Vector2::new(50.0, 0.0)), 2.0);
+        assert!(!field.free_cells.is_empty());
+    }
+
+    #[test]
+    fn test_tracking() {
+        let walls = room_walls();
+        let mut amcl = Amcl::new(&room_map(&walls), AmclParams::default(), 1);
+        let mut world_to_robot = Transform2d::new(-2.0, -1.0, 0.3);
+        let mut odom = Transform2d::ZERO;
+        amcl.set_pose(&Transform2d::new(-1.8, -1.1, 0.4), &Matrix3::from_diagonal(&Vector3::new(0.1, 0.1, 0.05)));
+        let estimate = drive(&mut amcl, &walls, &mut world_to_robot, &mut odom, 40);
+        assert_close(&estimate, &world_to_robot, 0.1);
+        let (std_x, std_y, std_theta) = estimate.std_devs();
+        assert!(std_x < 0.2 && std_y < 0.2 && std_theta < 0.2);
+        // converged so kld sampling shouldn't need anywhere near max_particles
+        assert!(amcl.particles().len() < 2000);
+    }
+
+    #[test]
+    fn test_global_localization_and_kidnapping() {
+        let walls = room_walls();
+        let mut amcl = Amcl::new(&room_map(&walls), AmclParams::default(), 2);
+        let mut world_to_robot = Transform2d::new(1.0, -1.0, 2.0);
+        let mut odom = Transform2d::ZERO;
+        let estimate = drive(&mut amcl, &walls, &mut world_to_robot, &mut odom, 80);
+        assert_close(&estimate, &world_to_robot, 0.15);
+
+        // pick the robot up and put it somewhere else without odometry noticing
+        world_to_robot = Transform2d::new(-2.0, 1.2, -0.5);
+        let estimate = drive(&mut amcl, &walls, &mut world_to_robot, &mut odom, 60);
+        assert_close(&estimate, &world_to_robot, 0.15);
+    }
+}
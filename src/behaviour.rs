@@ -0,0 +1,678 @@
+//! what the robot is doing, as an explicit state machine. the dashboard only queues requests, the main loop feeds them in along with
+//! everything else each frame, and every state change goes through one guarded transition so they all end up in the log.
+//! nothing in here talks to the dashboard directly, it collects messages for the main loop to send once it's done.
+
+use std::collections::VecDeque;
+
+use nalgebra::Vector2;
+use serde::Serialize;
+use tokio::time::Duration;
+
+use crate::{
+    controllers::{LtvUnicycleController, PathFollower, RamseteController},
+    costmap::Costmap,
+    geometry::{Transform2d, Twist2d},
+    goal::{AbortReason, GoalChecker, GoalStatus, GoalTolerance, NavigationResult},
+    local_planner::{path_in_robot_frame, LocalPlanner, LocalPlannerParams},
+    mission::{Mission, MissionControl, MissionParams, MissionProgress, MissionWaypoint},
+    paths::{Path, PurePursuitConfig},
+    planner::{plan_path, InflatedGrid, PlannerError, PlannerParams},
+    recovery::{RecoveryAction, RecoveryOutcome, RecoveryParams, RecoveryReport, RunningRecovery, StallDetector, StallParams, StallReason, StallSpeeds},
+    safety::EStopReason,
+    spline::Spline,
+    trajectory::{time_parameterize_limited, Trajectory, TrajectoryConfig},
+    zones::Zones,
+};
+
+const TRAJECTORY_SAMPLE_SPACING_METERS: f64 = 0.02;
+/// how much of the global path around the robot the local planner tries to stay close to
+const LOCAL_PATH_DISTANCE_METERS: f64 = 2.0;
+/// how many transitions to keep around
+const MAX_LOG_LENGTH: usize = 100;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum BehaviourState {
+    Idle,
+    Teleop,
+    /// planning a path to the current goal, only ever lasts a frame for now
+    Planning,
+    Following,
+    /// doing a mission waypoint's actions
+    Acting,
+    /// a mission, paused from the dashboard
+    Paused,
+    /// following stopped getting anywhere, trying the recovery actions one at a time
+    Recovering,
+    /// the safety monitor tripped, the only way out is clearing it
+    EStopped,
+}
+
+/// sent to the dashboard for every state change
+#[derive(Debug, Clone, Serialize)]
+pub struct Transition {
+    /// since the program started
+    pub time_seconds: f64,
+    pub from: BehaviourState,
+    pub to: BehaviourState,
+    pub reason: String,
+}
+
+/// what the dashboard asks for. these get queued and handed over by the main loop.
+#[derive(Debug, Clone)]
+pub enum DriveRequest {
+    Teleop(Twist2d),
+    PathfindTo(Transform2d),
+    StartMission(Vec<MissionWaypoint>),
+    Mission(MissionControl),
+    Cancel,
+}
+
+/// things that happened during an update, for the main loop to send out (or save)
+#[derive(Debug, Clone)]
+pub enum BehaviourMessage {
+    Path(Vec<Transform2d>),
+    Trajectory(Trajectory),
+    PursuitPose(Transform2d),
+    PlanningError(PlannerError),
+    NavigationResult(NavigationResult),
+    MissionProgress(MissionProgress),
+    Transition(Transition),
+    /// a mission wants the latest scan saved under this name
+    SaveScan { name: String },
+    Recovery(RecoveryReport),
+    /// a recovery wants the obstacles the local planner remembers forgotten
+    ClearLocalCostmap,
+    /// a request or state change the guards didn't let through, and why
+    Refused(String),
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct BehaviourParams {
+    pub planner: PlannerParams,
+    pub goal_tolerance: GoalTolerance,
+    pub pure_pursuit: PurePursuitConfig,
+    pub trajectory: TrajectoryConfig,
+    pub ramsete: RamseteController,
+    pub ltv_unicycle: LtvUnicycleController,
+    pub local_planner: LocalPlannerParams,
+    pub mission: MissionParams,
+    pub stall: StallParams,
+    pub recovery: RecoveryParams,
+}
+
+/// everything from the rest of the robot an update needs
+pub struct BehaviourInputs<'a> {
+    pub pose: &'a Transform2d,
+    /// since the program started
+    pub now: Duration,
+    pub costmap: &'a Costmap,
+    /// keep-out zones are already in the costmap, speed limit zones slow down planned trajectories
+    pub zones: &'a Zones,
+    /// robot frame, the latest one
+    pub scan: &'a [Vector2<f64>],
+    /// what got commanded last frame, which is close enough to how fast the robot is going
+    pub current_speeds: &'a Twist2d,
+    /// how fast odometry says the robot is actually going
+    pub measured_speeds: &'a Twist2d,
+    pub estop: Option<EStopReason>,
+    /// what follows the next planned path
+    pub follower: PathFollower,
+}
+
+/// what the robot is navigating to
+enum Task {
+    Goal(Transform2d),
+    Mission(Mission),
+}
+
+/// the planned path and how it's being followed
+struct Follow {
+    path: Path,
+    goal: GoalChecker,
+    stall: StallDetector,
+    tracking: Tracking,
+}
+
+enum Tracking {
+    PurePursuit,
+    /// start is when the robot should have been at the first state of the trajectory, in time since the program started
+    Trajectory { trajectory: Trajectory, start: Duration },
+}
+
+pub struct Behaviour {
+    pub params: BehaviourParams,
+    local_planner: LocalPlanner,
+    state: BehaviourState,
+    task: Option<Task>,
+    follow: Option<Follow>,
+    recovery: Option<RunningRecovery>,
+    /// how many recoveries the current goal (or mission waypoint) has used up
+    recovery_attempts: usize,
+    teleop_speeds: Twist2d,
+    log: VecDeque<Transition>,
+    messages: Vec<BehaviourMessage>,
+    prev_mission_progress: Option<MissionProgress>,
+}
+
+/// the guards. anything not in here is refused.
+fn allowed(from: BehaviourState, to: BehaviourState) -> bool {
+    use BehaviourState::*;
+    match (from, to) {
+        // only clearing the e-stop gets out of it
+        (EStopped, Idle) => true,
+        (EStopped, _) => false,
+        (_, EStopped | Idle | Teleop | Planning) => true,
+        (Planning, Following) => true,
+        (Following, Recovering) => true,
+        (Following | Paused, Acting) => true,
+        (Planning | Following | Acting | Recovering, Paused) => true,
+        _ => false,
+    }
+}
+
+impl Behaviour {
+    pub fn new(params: BehaviourParams) -> Self {
+        Self {
+            local_planner: LocalPlanner::new(params.local_planner.clone()),
+            params,
+            state: BehaviourState::Idle,
+            task: None,
+            follow: None,
+            recovery: None,
+            recovery_attempts: 0,
+            teleop_speeds: Twist2d::ZERO,
+            log: VecDeque::new(),
+            messages: Vec::new(),
+            prev_mission_progress: None,
+        }
+    }
+
+    pub fn state(&self) -> BehaviourState {
+        self.state
+    }
+
+    /// the most recent transitions, oldest first
+    pub fn log(&self) -> impl Iterator<Item = &Transition> {
+        self.log.iter()
+    }
+
+    pub fn take_messages(&mut self) -> Vec<BehaviourMessage> {
+        std::mem::take(&mut self.messages)
+    }
+
+    pub fn request(&mut self, request: DriveRequest, now: Duration) {
+        match request {
+            DriveRequest::Teleop(speeds) => {
+                if self.transition(BehaviourState::Teleop, "teleop", now) {
+                    self.teleop_speeds = speeds;
+                }
+            }
+            DriveRequest::PathfindTo(goal) => self.start_task(Task::Goal(goal), now),
+            DriveRequest::StartMission(waypoints) => self.start_task(Task::Mission(Mission::new(waypoints, self.params.mission.clone())), now),
+            DriveRequest::Mission(control) => self.control_mission(control, now),
+            DriveRequest::Cancel => {
+                if self.task.is_some() {
+                    self.transition(BehaviourState::Idle, "cancelled", now);
+                }
+            }
+        }
+    }
+
+    /// runs the current state and returns the speeds it wants. these still have to go through the safety monitor.
+    pub fn update(&mut self, inputs: &BehaviourInputs) -> Twist2d {
+        let now = inputs.now;
+        match (inputs.estop, self.state) {
+            (Some(reason), state) if state != BehaviourState::EStopped => {
+                self.transition(BehaviourState::EStopped, format!("e-stop: {:?}", reason), now);
+            }
+            (None, BehaviourState::EStopped) => {
+                self.transition(BehaviourState::Idle, "e-stop cleared", now);
+            }
+            _ => {}
+        }
+        let speeds = match self.state {
+            BehaviourState::Idle | BehaviourState::Paused | BehaviourState::EStopped => Twist2d::ZERO,
+            BehaviourState::Teleop => self.teleop_speeds.clone(),
+            BehaviourState::Planning => {
+                self.plan(inputs);
+                Twist2d::ZERO
+            }
+            BehaviourState::Following => self.follow(inputs),
+            BehaviourState::Acting => self.act(inputs),
+            BehaviourState::Recovering => self.recover(inputs),
+        };
+        let progress = match &self.task {
+            Some(Task::Mission(mission)) => Some(mission.progress()),
+            _ => None,
+        };
+        if progress.is_some() && progress != self.prev_mission_progress {
+            self.messages.push(BehaviourMessage::MissionProgress(progress.clone().unwrap()));
+        }
+        self.prev_mission_progress = progress;
+        speeds
+    }
+
+    /// the one place the state changes. returns whether the guard let it through.
+    fn transition(&mut self, to: BehaviourState, reason: impl Into<String>, now: Duration) -> bool {
+        let from = self.state;
+        if from == to {
+            return true;
+        }
+        let reason = reason.into();
+        if !allowed(from, to) {
+            self.messages.push(BehaviourMessage::Refused(format!("refusing {:?} -> {:?} ({})", from, to, reason)));
+            return false;
+        }
+        self.exit(from);
+        self.state = to;
+        self.enter(to);
+        let transition = Transition { time_seconds: now.as_secs_f64(), from, to, reason };
+        if self.log.len() == MAX_LOG_LENGTH {
+            self.log.pop_front();
+        }
+        self.log.push_back(transition.clone());
+        self.messages.push(BehaviourMessage::Transition(transition));
+        true
+    }
+
+    fn exit(&mut self, state: BehaviourState) {
+        match state {
+            BehaviourState::Following => self.follow = None,
+            BehaviourState::Recovering => self.recovery = None,
+            BehaviourState::Teleop => self.teleop_speeds = Twist2d::ZERO,
+            _ => {}
+        }
+    }
+
+    fn enter(&mut self, state: BehaviourState) {
+        match state {
+            // whatever task is still around when getting here didn't finish on its own
+            BehaviourState::Idle | BehaviourState::Teleop => self.finish(NavigationResult::Cancelled),
+            BehaviourState::EStopped => self.finish(NavigationResult::Aborted(AbortReason::EStop)),
+            _ => {}
+        }
+    }
+
+    /// reports the result of the current task, if there is one
+    fn finish(&mut self, result: NavigationResult) {
+        if self.task.take().is_some() {
+            self.messages.push(BehaviourMessage::NavigationResult(result));
+        }
+    }
+
+    fn start_task(&mut self, task: Task, now: Duration) {
+        if !allowed(self.state, BehaviourState::Planning) {
+            self.messages.push(BehaviourMessage::Refused(format!("refusing a new goal while {:?}", self.state)));
+            // whoever sent it still has to hear that it's over
+            self.messages.push(BehaviourMessage::NavigationResult(NavigationResult::Aborted(AbortReason::Refused)));
+            return;
+        }
+        // a new goal preempts the old one
+        self.finish(NavigationResult::Cancelled);
+        self.follow = None;
+        self.recovery_attempts = 0;
+        self.task = Some(task);
+        self.continue_task("new goal", now);
+    }
+
+    /// picks what to do next for the current task
+    fn continue_task(&mut self, reason: &str, now: Duration) {
+        match &self.task {
+            Some(Task::Goal(_)) => {
+                self.transition(BehaviourState::Planning, reason, now);
+            }
+            Some(Task::Mission(mission)) if mission.is_done() => {
+                self.finish(NavigationResult::Succeeded);
+                self.transition(BehaviourState::Idle, "mission done", now);
+            }
+            Some(Task::Mission(mission)) => {
+                let next = if mission.current_goal().is_some() { BehaviourState::Planning } else { BehaviourState::Acting };
+                self.transition(next, reason, now);
+            }
+            None => {
+                self.transition(BehaviourState::Idle, reason, now);
+            }
+        }
+    }
+
+    fn control_mission(&mut self, control: MissionControl, now: Duration) {
+        let Some(Task::Mission(mission)) = &mut self.task else {
+            return;
+        };
+        match control {
+            MissionControl::Pause => {
+                if allowed(self.state, BehaviourState::Paused) {
+                    mission.control(control, now);
+                    self.transition(BehaviourState::Paused, "paused", now);
+                    // resuming replans from wherever the robot is then
+                    self.follow = None;
+                }
+            }
+            MissionControl::Resume => {
+                if self.state == BehaviourState::Paused {
+                    mission.control(control, now);
+                    self.continue_task("resumed", now);
+                }
+            }
+            MissionControl::Skip => {
+                mission.control(control, now);
+                self.follow = None;
+                if self.state != BehaviourState::Paused {
+                    self.continue_task("skipped", now);
+                }
+            }
+        }
+    }
+
+    fn plan(&mut self, inputs: &BehaviourInputs) {
+        let goal = match &self.task {
+            Some(Task::Goal(goal)) => goal.clone(),
+            Some(Task::Mission(mission)) if mission.current_goal().is_some() => mission.current_goal().unwrap().clone(),
+            _ => {
+                self.continue_task("nothing to plan for", inputs.now);
+                return;
+            }
+        };
+        match plan_path(&InflatedGrid::new(inputs.costmap, &self.params.planner), inputs.pose, &goal, &self.params.planner) {
+            Ok(path) => {
+                self.messages.push(BehaviourMessage::Path(path.waypoints.clone()));
+                let samples = Spline::through_path(&path).sample(TRAJECTORY_SAMPLE_SPACING_METERS);
+                let trajectory = time_parameterize_limited(&samples, &self.params.trajectory, |pose| {
+                    inputs.zones.speed_limit_at(&Vector2::new(pose.x_meters, pose.y_meters))
+                });
+                self.messages.push(BehaviourMessage::Trajectory(trajectory.clone()));
+                let checker = GoalChecker::new(path.waypoints.last().unwrap().clone(), self.params.goal_tolerance.clone());
+                self.transition(BehaviourState::Following, "planned a path", inputs.now);
+                let tracking = match inputs.follower {
+                    PathFollower::PurePursuit => Tracking::PurePursuit,
+                    _ => Tracking::Trajectory { trajectory, start: inputs.now },
+                };
+                self.follow = Some(Follow { path, goal: checker, stall: StallDetector::new(self.params.stall.clone(), inputs.now), tracking });
+            }
+            Err(e) => {
+                eprintln!("couldn't plan a path to {:?}: {:?}", goal, e);
+                self.messages.push(BehaviourMessage::PlanningError(e.clone()));
+                self.finish(NavigationResult::Aborted(AbortReason::PlanningFailed(e)));
+                self.transition(BehaviourState::Idle, "planning failed", inputs.now);
+            }
+        }
+    }
+
+    fn follow(&mut self, inputs: &BehaviourInputs) -> Twist2d {
+        let now = inputs.now;
+        let Some(follow) = &mut self.follow else {
+            self.continue_task("nothing to follow", now);
+            return Twist2d::ZERO;
+        };
+        let desired = match &follow.tracking {
+            Tracking::PurePursuit => {
+                let (pursuit_speeds, goal_pose) = follow.path.pure_pursuit(inputs.pose, inputs.current_speeds.dx, &self.params.pure_pursuit);
+                self.messages.push(BehaviourMessage::PursuitPose(goal_pose));
+                pursuit_speeds
+            }
+            Tracking::Trajectory { trajectory, start } => match trajectory.sample(now.saturating_sub(*start).as_secs_f64()) {
+                Some(reference) => {
+                    self.messages.push(BehaviourMessage::PursuitPose(reference.pose.clone()));
+                    match inputs.follower {
+                        PathFollower::LtvUnicycle => self.params.ltv_unicycle.calculate(inputs.pose, &reference),
+                        _ => self.params.ramsete.calculate(inputs.pose, &reference),
+                    }
+                }
+                // nothing to track, the goal checker decides whether it's already there
+                None => Twist2d::ZERO,
+            },
+        };
+        let local_path = match &follow.tracking {
+            Tracking::PurePursuit => path_in_robot_frame(inputs.pose, &follow.path.waypoints, LOCAL_PATH_DISTANCE_METERS),
+            Tracking::Trajectory { trajectory, .. } => path_in_robot_frame(inputs.pose, trajectory.states.iter().map(|state| &state.pose), LOCAL_PATH_DISTANCE_METERS),
+        };
+        let obstacles = inputs.costmap.obstacles_near(inputs.pose, self.local_planner.reach_meters());
+        let speeds = self.local_planner.plan(&obstacles, &local_path, &desired, inputs.current_speeds);
+        let stall_speeds = StallSpeeds { desired: &desired, planned: &speeds, commanded: inputs.current_speeds, measured: inputs.measured_speeds };
+        let stall = follow.stall.update(&follow.path, inputs.pose, &stall_speeds, now);
+        let status = follow.goal.update(inputs.pose, now);
+        if let (Some(stall), GoalStatus::Active) = (stall, status) {
+            self.start_recovery(stall, inputs);
+            return Twist2d::ZERO;
+        }
+        match status {
+            GoalStatus::Active => speeds,
+            GoalStatus::Succeeded => {
+                self.recovery_attempts = 0;
+                if let Some(Task::Mission(mission)) = &mut self.task {
+                    mission.arrived(now);
+                    self.continue_task("arrived at waypoint", now);
+                } else {
+                    self.finish(NavigationResult::Succeeded);
+                    self.transition(BehaviourState::Idle, "arrived", now);
+                }
+                Twist2d::ZERO
+            }
+        }
+    }
+
+    /// moves on to the next recovery action, or gives up if they've all been tried
+    fn start_recovery(&mut self, stall: StallReason, inputs: &BehaviourInputs) {
+        let now = inputs.now;
+        if !self.transition(BehaviourState::Recovering, format!("stalled: {:?}", stall), now) {
+            return;
+        }
+        let Some(action) = self.params.recovery.actions.get(self.recovery_attempts).cloned() else {
+            self.finish(NavigationResult::Aborted(AbortReason::Stalled));
+            self.transition(BehaviourState::Idle, "out of recoveries", now);
+            return;
+        };
+        self.recovery_attempts += 1;
+        if action == RecoveryAction::ClearLocalCostmap {
+            self.messages.push(BehaviourMessage::ClearLocalCostmap);
+        }
+        let recovery = RunningRecovery::new(self.recovery_attempts, action, stall, inputs.pose, now);
+        self.messages.push(BehaviourMessage::Recovery(recovery.report(RecoveryOutcome::Started)));
+        self.recovery = Some(recovery);
+    }
+
+    fn recover(&mut self, inputs: &BehaviourInputs) -> Twist2d {
+        let Some(recovery) = &mut self.recovery else {
+            self.continue_task("nothing to recover", inputs.now);
+            return Twist2d::ZERO;
+        };
+        let (speeds, outcome) = recovery.update(inputs.pose, inputs.scan, inputs.now, &self.params.recovery);
+        let Some(outcome) = outcome else {
+            return speeds;
+        };
+        let (stall, action) = (recovery.stall, recovery.action.clone());
+        self.messages.push(BehaviourMessage::Recovery(recovery.report(outcome.clone())));
+        self.recovery = None;
+        if outcome == RecoveryOutcome::Succeeded {
+            self.continue_task(&format!("recovered with {:?}", action), inputs.now);
+        } else {
+            self.start_recovery(stall, inputs);
+        }
+        Twist2d::ZERO
+    }
+
+    fn act(&mut self, inputs: &BehaviourInputs) -> Twist2d {
+        let Some(Task::Mission(mission)) = &mut self.task else {
+            self.continue_task("nothing to do", inputs.now);
+            return Twist2d::ZERO;
+        };
+        let waypoint = mission.progress().waypoint;
+        let output = mission.act(inputs.pose, inputs.now);
+        if output.save_scan {
+            self.messages.push(BehaviourMessage::SaveScan { name: format!("waypoint_{}_{}", waypoint, inputs.now.as_millis()) });
+        }
+        if mission.current_goal().is_some() || mission.is_done() {
+            self.continue_task("actions done", inputs.now);
+        }
+        output.speeds
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{mission::MissionAction, sim::empty_costmap};
+
+    /// drives a perfect unicycle with whatever the behaviour asks for
+    fn run(behaviour: &mut Behaviour, pose: &mut Transform2d, speeds: &mut Twist2d, start: Duration, frames: u32) -> Duration {
+        let costmap = empty_costmap();
+        let dt = Duration::from_millis(10);
+        let mut now = start;
+        for _ in 0..frames {
+            now += dt;
+            *speeds = behaviour.update(&BehaviourInputs { pose, now, costmap: &costmap, zones: &Zones::default(), scan: &[], current_speeds: speeds, measured_speeds: speeds, estop: None, follower: PathFollower::PurePursuit });
+            *pose += Transform2d::from(speeds.clone() * dt.as_secs_f64());
+        }
+        now
+    }
+
+    fn results(messages: &[BehaviourMessage]) -> Vec<NavigationResult> {
+        messages
+            .iter()
+            .filter_map(|message| match message {
+                BehaviourMessage::NavigationResult(result) => Some(result.clone()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn states(behaviour: &Behaviour) -> Vec<BehaviourState> {
+        behaviour.log().map(|transition| transition.to).collect()
+    }
+
+    #[test]
+    fn test_goal_succeeds() {
+        let mut behaviour = Behaviour::new(BehaviourParams::default());
+        let (mut pose, mut speeds) = (Transform2d::ZERO, Twist2d::ZERO);
+        behaviour.request(DriveRequest::PathfindTo(Transform2d::new(1.0, 0.0, 0.0)), Duration::ZERO);
+        run(&mut behaviour, &mut pose, &mut speeds, Duration::ZERO, 1500);
+        assert_eq!(states(&behaviour), vec![BehaviourState::Planning, BehaviourState::Following, BehaviourState::Idle]);
+        assert_eq!(results(&behaviour.take_messages()), vec![NavigationResult::Succeeded]);
+        assert!((pose.x_meters - 1.0).abs() < 0.08, "{:?}", pose);
+    }
+
+    #[test]
+    fn test_estop_guards() {
+        let mut behaviour = Behaviour::new(BehaviourParams::default());
+        let costmap = empty_costmap();
+        let zones = Zones::default();
+        let pose = Transform2d::ZERO;
+        let inputs = |now: u64, estop| BehaviourInputs {
+            pose: &pose,
+            now: Duration::from_millis(now),
+            costmap: &costmap,
+            zones: &zones,
+            scan: &[],
+            current_speeds: &Twist2d::ZERO,
+            measured_speeds: &Twist2d::ZERO,
+            estop,
+            follower: PathFollower::PurePursuit,
+        };
+        behaviour.request(DriveRequest::PathfindTo(Transform2d::new(1.0, 0.0, 0.0)), Duration::ZERO);
+        behaviour.update(&inputs(10, None));
+        assert_eq!(behaviour.state(), BehaviourState::Following);
+        assert_eq!(behaviour.update(&inputs(20, Some(EStopReason::ProtectiveField))).dx, 0.0);
+        assert_eq!(behaviour.state(), BehaviourState::EStopped);
+        assert_eq!(results(&behaviour.take_messages()), vec![NavigationResult::Aborted(AbortReason::EStop)]);
+        // nothing gets out of it until it's cleared
+        behaviour.request(DriveRequest::Teleop(Twist2d::new(0.2, 0.0, 0.0)), Duration::from_millis(25));
+        behaviour.request(DriveRequest::PathfindTo(Transform2d::new(1.0, 0.0, 0.0)), Duration::from_millis(25));
+        assert_eq!(behaviour.update(&inputs(30, Some(EStopReason::ProtectiveField))).dx, 0.0);
+        assert_eq!(behaviour.state(), BehaviourState::EStopped);
+        let messages = behaviour.take_messages();
+        assert_eq!(results(&messages), vec![NavigationResult::Aborted(AbortReason::Refused)]);
+        // both the teleop and the goal get told why
+        assert_eq!(messages.iter().filter(|message| matches!(message, BehaviourMessage::Refused(_))).count(), 2);
+        behaviour.update(&inputs(40, None));
+        assert_eq!(behaviour.state(), BehaviourState::Idle);
+        behaviour.request(DriveRequest::Teleop(Twist2d::new(0.2, 0.0, 0.0)), Duration::from_millis(45));
+        assert_eq!(behaviour.update(&inputs(50, None)).dx, 0.2);
+        let log = behaviour.log().collect::<Vec<_>>();
+        assert_eq!(log.len(), 5);
+        assert_eq!((log[2].from, log[2].to), (BehaviourState::Following, BehaviourState::EStopped));
+        assert_eq!(log[2].reason, "e-stop: ProtectiveField");
+        assert!(results(&behaviour.take_messages()).is_empty());
+    }
+
+    #[test]
+    fn test_mission_pause_resume_cancel() {
+        let mut behaviour = Behaviour::new(BehaviourParams::default());
+        let (mut pose, mut speeds) = (Transform2d::ZERO, Twist2d::ZERO);
+        let waypoints = vec![
+            MissionWaypoint { pose: Transform2d::new(0.5, 0.0, 0.0), actions: vec![MissionAction::SaveScan, MissionAction::Wait { seconds: 30.0 }] },
+            MissionWaypoint { pose: Transform2d::new(1.0, 0.0, 0.0), actions: vec![] },
+        ];
+        behaviour.request(DriveRequest::StartMission(waypoints), Duration::ZERO);
+        let now = run(&mut behaviour, &mut pose, &mut speeds, Duration::ZERO, 20);
+        behaviour.request(DriveRequest::Mission(MissionControl::Pause), now);
+        let paused_at = pose.clone();
+        let now = run(&mut behaviour, &mut pose, &mut speeds, now, 100);
+        assert_eq!(behaviour.state(), BehaviourState::Paused);
+        assert_eq!(pose.x_meters, paused_at.x_meters);
+        behaviour.request(DriveRequest::Mission(MissionControl::Resume), now);
+        let now = run(&mut behaviour, &mut pose, &mut speeds, now, 1000);
+        assert_eq!(behaviour.state(), BehaviourState::Acting);
+        let messages = behaviour.take_messages();
+        assert!(messages.iter().any(|message| matches!(message, BehaviourMessage::SaveScan { .. })));
+        behaviour.request(DriveRequest::Cancel, now);
+        assert_eq!(behaviour.state(), BehaviourState::Idle);
+        assert_eq!(
+            states(&behaviour),
+            vec![BehaviourState::Planning, BehaviourState::Following, BehaviourState::Paused, BehaviourState::Planning, BehaviourState::Following, BehaviourState::Acting, BehaviourState::Idle]
+        );
+        assert_eq!(results(&behaviour.take_messages()), vec![NavigationResult::Cancelled]);
+    }
+
+    #[test]
+    fn test_recovers_then_gives_up() {
+        let mut behaviour = Behaviour::new(BehaviourParams::default());
+        let costmap = empty_costmap();
+        // wedged on something, the wheels turn but odometry doesn't move
+        let pose = Transform2d::ZERO;
+        let mut speeds = Twist2d::ZERO;
+        behaviour.request(DriveRequest::PathfindTo(Transform2d::new(1.0, 0.0, 0.0)), Duration::ZERO);
+        let mut messages = Vec::new();
+        for frame in 1..6000 {
+            let inputs = BehaviourInputs {
+                pose: &pose,
+                now: Duration::from_millis(frame * 10),
+                costmap: &costmap,
+                zones: &Zones::default(),
+                scan: &[],
+                current_speeds: &speeds,
+                measured_speeds: &Twist2d::ZERO,
+                estop: None,
+                follower: PathFollower::PurePursuit,
+            };
+            speeds = behaviour.update(&inputs);
+            messages.extend(behaviour.take_messages());
+            if behaviour.state() == BehaviourState::Idle {
+                break;
+            }
+        }
+        let reports = messages
+            .iter()
+            .filter_map(|message| match message {
+                BehaviourMessage::Recovery(report) => Some((report.attempt, report.outcome.clone())),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        let failed = RecoveryOutcome::Failed(crate::recovery::RecoveryFailure::TimedOut);
+        assert_eq!(
+            reports,
+            vec![
+                (1, RecoveryOutcome::Started),
+                (1, RecoveryOutcome::Succeeded),
+                (2, RecoveryOutcome::Started),
+                (2, RecoveryOutcome::Succeeded),
+                (3, RecoveryOutcome::Started),
+                (3, failed.clone()),
+                (4, RecoveryOutcome::Started),
+                (4, failed),
+            ]
+        );
+        assert!(messages.iter().any(|message| matches!(message, BehaviourMessage::ClearLocalCostmap)));
+        assert_eq!(results(&messages), vec![NavigationResult::Aborted(AbortReason::Stalled)]);
+        assert_eq!(behaviour.log().last().unwrap().reason, "out of recoveries");
+    }
+}
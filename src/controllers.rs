@@ -0,0 +1,183 @@
+//! trajectory tracking controllers. both take where the trajectory says the robot should be right now and where it actually is,
+//! and return the chassis speeds that pull it back onto the trajectory. pure pursuit (in paths.rs) ignores timing and stays an option.
+
+use nalgebra::{Matrix2, Matrix2x3, Matrix3, Matrix3x2, Vector2, Vector3};
+use serde::Deserialize;
+
+use crate::{
+    geometry::{wrap_angle, Transform2d, Twist2d},
+    trajectory::TrajectoryState,
+};
+
+/// which controller follows planned paths
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+pub enum PathFollower {
+    PurePursuit,
+    Ramsete,
+    LtvUnicycle,
+}
+
+/// pose error in the robot frame: [along, sideways, heading]
+fn error_in_robot_frame(current: &Transform2d, reference: &Transform2d) -> Vector3<f64> {
+    let error = -current.clone() + reference.clone();
+    Vector3::new(error.x_meters, error.y_meters, wrap_angle(error.theta_radians))
+}
+
+/// the nonlinear controller from "control of wheeled mobile robots: an experimental overview" (samson), same as wpilib's
+#[derive(Debug, Clone)]
+pub struct RamseteController {
+    /// like a proportional gain, larger is more aggressive. must be positive.
+    pub b: f64,
+    /// damping, between 0 and 1
+    pub zeta: f64,
+}
+
+impl Default for RamseteController {
+    fn default() -> Self {
+        Self { b: 2.0, zeta: 0.7 }
+    }
+}
+
+/// sin(x) / x, without blowing up at 0
+fn sinc(x: f64) -> f64 {
+    if x.abs() < 1e-9 {
+        1.0 - x * x / 6.0
+    } else {
+        x.sin() / x
+    }
+}
+
+impl RamseteController {
+    pub fn calculate(&self, current: &Transform2d, reference: &TrajectoryState) -> Twist2d {
+        let error = error_in_robot_frame(current, &reference.pose);
+        let v_ref = reference.velocity_meters_per_second;
+        let omega_ref = v_ref * reference.curvature_radians_per_meter;
+        let k = 2.0 * self.zeta * (omega_ref * omega_ref + self.b * v_ref * v_ref).sqrt();
+        Twist2d::new(
+            v_ref * error[2].cos() + k * error[0],
+            0.0,
+            omega_ref + k * error[2] + self.b * v_ref * sinc(error[2]) * error[1],
+        )
+    }
+}
+
+/// lqr on the unicycle model linearized around the reference velocity, like wpilib's LTVUnicycleController.
+/// the gains get recomputed for every reference velocity, which is cheap for a 3x3 system.
+#[derive(Debug, Clone)]
+pub struct LtvUnicycleController {
+    /// how far off the robot is allowed to be before it really matters (along meters, sideways meters, heading radians)
+    pub q_tolerances: [f64; 3],
+    /// how much control effort is ok (meters per second, radians per second)
+    pub r_tolerances: [f64; 2],
+    /// how often the controller runs, for discretizing the model
+    pub dt_seconds: f64,
+}
+
+impl Default for LtvUnicycleController {
+    fn default() -> Self {
+        // wpilib's defaults
+        Self { q_tolerances: [0.0625, 0.125, 2.0], r_tolerances: [1.0, 2.0], dt_seconds: 0.01 }
+    }
+}
+
+impl LtvUnicycleController {
+    /// the lqr gain for driving at velocity
+    pub fn gain(&self, velocity: f64) -> Matrix2x3<f64> {
+        // sideways error isn't controllable when the robot stands still, so pretend it's barely moving
+        let velocity = if velocity.abs() < 1e-4 { 1e-4 } else { velocity };
+        // x' = v cos(theta), y' = v sin(theta), theta' = omega linearized around theta = 0 in the robot frame.
+        // a is nilpotent, so e^(a dt) = I + a dt exactly
+        let a = Matrix3::new(0.0, 0.0, 0.0, 0.0, 0.0, velocity, 0.0, 0.0, 0.0);
+        let b = Matrix3x2::new(1.0, 0.0, 0.0, 0.0, 0.0, 1.0);
+        let dt = self.dt_seconds;
+        let a_d = Matrix3::identity() + a * dt;
+        let b_d = (Matrix3::identity() * dt + a * dt * dt / 2.0) * b;
+        let q = Matrix3::from_diagonal(&Vector3::from(self.q_tolerances.map(|tolerance| 1.0 / (tolerance * tolerance))));
+        let r = Matrix2::from_diagonal(&Vector2::from(self.r_tolerances.map(|tolerance| 1.0 / (tolerance * tolerance))));
+        // solve the discrete algebraic riccati equation with the structure preserving doubling algorithm.
+        // just iterating the riccati equation takes forever at low speeds, this converges quadratically
+        let mut a_k = a_d;
+        let mut g_k = b_d * r.try_inverse().unwrap() * b_d.transpose();
+        let mut p = q;
+        for _ in 0..100 {
+            let w = (Matrix3::identity() + g_k * p).try_inverse().unwrap();
+            let next = p + a_k.transpose() * p * w * a_k;
+            g_k += a_k * w * g_k * a_k.transpose();
+            a_k = a_k * w * a_k;
+            let converged = (next - p).abs().max() <= 1e-10 * next.abs().max();
+            p = next;
+            if converged {
+                break;
+            }
+        }
+        (r + b_d.transpose() * p * b_d).try_inverse().unwrap() * b_d.transpose() * p * a_d
+    }
+
+    pub fn calculate(&self, current: &Transform2d, reference: &TrajectoryState) -> Twist2d {
+        let v_ref = reference.velocity_meters_per_second;
+        let u = self.gain(v_ref) * error_in_robot_frame(current, &reference.pose);
+        Twist2d::new(v_ref + u[0], 0.0, v_ref * reference.curvature_radians_per_meter + u[1])
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{spline::Spline, trajectory::{time_parameterize, Trajectory, TrajectoryConfig}};
+
+    fn s_curve() -> Trajectory {
+        let spline = Spline::quintic_hermite(&[Transform2d::new(0.0, 0.0, 0.0), Transform2d::new(1.5, 1.0, 0.0), Transform2d::new(3.0, 0.0, -1.0)]);
+        time_parameterize(&spline.sample(0.02), &TrajectoryConfig::default())
+    }
+
+    /// drives a perfect unicycle at 100hz starting from start, returns the pose error at the end and the largest error in the second half
+    fn simulate(trajectory: &Trajectory, start: Transform2d, controller: impl Fn(&Transform2d, &TrajectoryState) -> Twist2d) -> (f64, f64) {
+        let dt = 0.01;
+        let mut pose = start;
+        let mut late_error: f64 = 0.0;
+        let steps = (trajectory.total_time() / dt) as usize + 100;
+        for step in 0..steps {
+            let reference = trajectory.sample(step as f64 * dt).unwrap();
+            let speeds = controller(&pose, &reference);
+            pose += Transform2d::from(speeds * dt);
+            if step > steps / 2 {
+                let reference = trajectory.sample((step + 1) as f64 * dt).unwrap();
+                late_error = late_error.max((reference.pose.x_meters - pose.x_meters).hypot(reference.pose.y_meters - pose.y_meters));
+            }
+        }
+        let end = trajectory.states.last().unwrap().pose.clone();
+        ((end.x_meters - pose.x_meters).hypot(end.y_meters - pose.y_meters), late_error)
+    }
+
+    #[test]
+    fn test_controllers_converge_from_offset() {
+        let trajectory = s_curve();
+        let start = Transform2d::new(-0.1, -0.2, 0.3);
+        let open_loop = simulate(&trajectory, start.clone(), |_, reference| {
+            Twist2d::new(reference.velocity_meters_per_second, 0.0, reference.velocity_meters_per_second * reference.curvature_radians_per_meter)
+        });
+        let ramsete = RamseteController::default();
+        let ltv = LtvUnicycleController::default();
+        for (end_error, late_error) in [
+            simulate(&trajectory, start.clone(), |pose, reference| ramsete.calculate(pose, reference)),
+            simulate(&trajectory, start.clone(), |pose, reference| ltv.calculate(pose, reference)),
+        ] {
+            assert!(end_error < 0.02, "{}", end_error);
+            assert!(late_error < 0.05, "{}", late_error);
+        }
+        assert!(open_loop.0 > 0.2, "{:?}", open_loop);
+    }
+
+    #[test]
+    fn test_ltv_gains() {
+        let ltv = LtvUnicycleController::default();
+        for velocity in [0.0, 0.05, 0.3] {
+            let gain = ltv.gain(velocity);
+            // along track error only needs the velocity, sideways and heading error only the turn rate
+            assert!(gain[(0, 0)] > 0.0 && gain[(1, 1)] > 0.0 && gain[(1, 2)] > 0.0, "{}", gain);
+            assert!(gain[(0, 1)].abs() < 1e-9 && gain[(0, 2)].abs() < 1e-9 && gain[(1, 0)].abs() < 1e-9, "{}", gain);
+        }
+        // driving fast fixes sideways error on its own, so it doesn't have to turn as hard
+        assert!(ltv.gain(0.3)[(1, 1)] < ltv.gain(0.0)[(1, 1)]);
+    }
+}
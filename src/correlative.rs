@@ -0,0 +1,388 @@
+use nalgebra::{Matrix3, Rotation2, Vector2, Vector3};
+
+use crate::{
+    geometry::{wrap_angle, Transform2d},
+    icp::{icp, IcpParams, IcpResult},
+};
+
+/// a grid where every cell holds how likely it is that a lidar point lands in it, from 0.0 to 1.0
+#[derive(Debug, Clone)]
+pub struct LikelihoodGrid {
+    /// world position of the corner of cell (0, 0)
+    pub origin: Vector2<f64>,
+    pub resolution_meters: f64,
+    pub width: usize,
+    pub height: usize,
+    pub data: Vec<f32>,
+}
+
+impl LikelihoodGrid {
+    /// rasterizes the points into a grid, blurring each one with a gaussian so scores fall off smoothly around the walls
+    pub fn from_points(points: &[Vector2<f64>], resolution_meters: f64, sigma_meters: f64) -> Self {
+        if points.is_empty() {
+            return Self { origin: Vector2::zeros(), resolution_meters, width: 0, height: 0, data: Vec::new() };
+        }
+        let padding = 3.0 * sigma_meters + resolution_meters;
+        let (min, max) = points.iter().fold(
+            (Vector2::repeat(f64::INFINITY), Vector2::repeat(f64::NEG_INFINITY)),
+            |(min, max), point| (min.inf(point), max.sup(point)),
+        );
+        let origin = min - Vector2::repeat(padding);
+        let width = ((max[0] - min[0] + 2.0 * padding) / resolution_meters).ceil() as usize + 1;
+        let height = ((max[1] - min[1] + 2.0 * padding) / resolution_meters).ceil() as usize + 1;
+        let mut grid = Self { origin, resolution_meters, width, height, data: vec![0.0; width * height] };
+        let kernel_radius = (3.0 * sigma_meters / resolution_meters).ceil() as i64;
+        for point in points {
+            let (cx, cy) = grid.cell_of(point);
+            for y in (cy - kernel_radius)..=(cy + kernel_radius) {
+                for x in (cx - kernel_radius)..=(cx + kernel_radius) {
+                    let Some(index) = grid.index(x, y) else { continue };
+                    let d = grid.cell_center(x, y) - point;
+                    let value = (-d.norm_squared() / (2.0 * sigma_meters * sigma_meters)).exp() as f32;
+                    grid.data[index] = grid.data[index].max(value);
+                }
+            }
+        }
+        grid
+    }
+
+    pub fn cell_of(&self, point: &Vector2<f64>) -> (i64, i64) {
+        let cell = (point - self.origin) / self.resolution_meters;
+        (cell[0].floor() as i64, cell[1].floor() as i64)
+    }
+
+    pub fn cell_center(&self, x: i64, y: i64) -> Vector2<f64> {
+        self.origin + Vector2::new(x as f64 + 0.5, y as f64 + 0.5) * self.resolution_meters
+    }
+
+    fn index(&self, x: i64, y: i64) -> Option<usize> {
+        if x < 0 || y < 0 || x >= self.width as i64 || y >= self.height as i64 {
+            None
+        } else {
+            Some(y as usize * self.width + x as usize)
+        }
+    }
+
+    /// 0.0 outside of the grid
+    pub fn get(&self, x: i64, y: i64) -> f32 {
+        self.index(x, y).map_or(0.0, |index| self.data[index])
+    }
+
+    /// every cell becomes the max of the size x size block of cells starting at it. used as an upper bound when branching.
+    /// blocks that start outside the grid can still overlap it, so the result is padded by size - 1 cells on the low side:
+    /// cell (x, y) of the result is the block starting at cell (x - size + 1, y - size + 1) of this grid.
+    fn max_pooled(&self, size: usize) -> Self {
+        let pad = size - 1;
+        let (width, height) = (self.width + pad, self.height + pad);
+        let mut horizontal = vec![0.0f32; width * self.height];
+        for y in 0..self.height {
+            for x in 0..width {
+                horizontal[y * width + x] = (x.saturating_sub(pad)..(x + 1).min(self.width)).map(|i| self.data[y * self.width + i]).fold(0.0, f32::max);
+            }
+        }
+        let mut data = vec![0.0f32; width * height];
+        for y in 0..height {
+            for x in 0..width {
+                data[y * width + x] = (y.saturating_sub(pad)..(y + 1).min(self.height)).map(|i| horizontal[i * width + x]).fold(0.0, f32::max);
+            }
+        }
+        Self {
+            origin: self.origin - Vector2::repeat(pad as f64 * self.resolution_meters),
+            resolution_meters: self.resolution_meters,
+            width,
+            height,
+            data,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct CorrelativeMatcherParams {
+    /// how far from the initial guess to search in x and y
+    pub linear_window_meters: f64,
+    /// how far from the initial guess to search in theta
+    pub angular_window_radians: f64,
+    pub resolution_meters: f64,
+    /// how blurry the reference points are in the likelihood grid
+    pub sigma_meters: f64,
+    /// number of coarser grids used for branch and bound. the coarsest one covers 2^depth cells per candidate.
+    pub depth: usize,
+    /// matches that score below this are thrown away
+    pub min_score: f64,
+}
+
+impl Default for CorrelativeMatcherParams {
+    fn default() -> Self {
+        Self {
+            linear_window_meters: 1.0,
+            angular_window_radians: 0.5,
+            resolution_meters: 0.03,
+            sigma_meters: 0.03,
+            depth: 5,
+            min_score: 0.4,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct CorrelativeMatch {
+    /// maps points in the matched scan into the reference frame, just like the result of icp
+    pub pose: Transform2d,
+    /// average likelihood of the scan points, from 0.0 to 1.0
+    pub score: f64,
+    /// covariance of [x, y, theta] estimated from how the score falls off around the best pose
+    pub covariance: Matrix3<f64>,
+}
+
+impl CorrelativeMatch {
+    pub fn to_icp_guess(&self) -> Vector3<f64> {
+        Vector3::new(self.pose.x_meters, self.pose.y_meters, self.pose.theta_radians)
+    }
+}
+
+/// global scan matcher that searches a whole (x, y, theta) window at once, so it doesn't need a good initial guess like icp does.
+/// this is a branch and bound search over multi-resolution likelihood grids (like cartographer's fast correlative scan matcher).
+pub struct CorrelativeScanMatcher {
+    /// grids[d] is the likelihood grid max-pooled over 2^d cells
+    grids: Vec<LikelihoodGrid>,
+    params: CorrelativeMatcherParams,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Candidate {
+    angle_index: usize,
+    x_offset: i64,
+    y_offset: i64,
+    score: f64,
+}
+
+impl CorrelativeScanMatcher {
+    pub fn new(reference: &[Vector2<f64>], params: CorrelativeMatcherParams) -> Self {
+        Self::from_grid(LikelihoodGrid::from_points(reference, params.resolution_meters, params.sigma_meters), params)
+    }
+
+    pub fn from_grid(grid: LikelihoodGrid, params: CorrelativeMatcherParams) -> Self {
+        let grids = (0..=params.depth).map(|d| if d == 0 { grid.clone() } else { grid.max_pooled(1 << d) }).collect();
+        Self { grids, params }
+    }
+
+    pub fn params(&self) -> &CorrelativeMatcherParams {
+        &self.params
+    }
+
+    /// finds the pose that maps scan onto the reference, searching the window around initial_guess
+    pub fn match_scan(&self, scan: &[Vector2<f64>], initial_guess: &Transform2d) -> Option<CorrelativeMatch> {
+        if scan.is_empty() {
+            return None;
+        }
+        let grid = &self.grids[0];
+        let resolution = self.params.resolution_meters;
+
+        // step the angle so that the furthest point moves by about one cell per step
+        let max_range = scan.iter().map(|point| point.norm()).fold(resolution, f64::max);
+        let angular_step = (1.0 - resolution * resolution / (2.0 * max_range * max_range)).acos();
+        let angular_steps = (self.params.angular_window_radians / angular_step).ceil() as i64;
+        let angles = (-angular_steps..=angular_steps)
+            .map(|i| initial_guess.theta_radians + i as f64 * angular_step)
+            .collect::<Vec<_>>();
+        let translation = Vector2::new(initial_guess.x_meters, initial_guess.y_meters);
+        let discretized_scans = angles
+            .iter()
+            .map(|angle| {
+                let rotation = Rotation2::new(*angle);
+                scan.iter().map(|point| grid.cell_of(&(rotation * point + translation))).collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+
+        let linear_steps = (self.params.linear_window_meters / resolution).ceil() as i64;
+        let top = self.params.depth;
+        let coarse_step = 1 << top;
+        let mut candidates = Vec::new();
+        for angle_index in 0..angles.len() {
+            let mut x_offset = -linear_steps;
+            while x_offset <= linear_steps {
+                let mut y_offset = -linear_steps;
+                while y_offset <= linear_steps {
+                    candidates.push(self.score(top, &discretized_scans, angle_index, x_offset, y_offset));
+                    y_offset += coarse_step;
+                }
+                x_offset += coarse_step;
+            }
+        }
+        let mut best = Candidate { angle_index: 0, x_offset: 0, y_offset: 0, score: self.params.min_score };
+        let mut found = false;
+        self.branch_and_bound(top, candidates, &discretized_scans, linear_steps, &mut best, &mut found);
+        if !found {
+            return None;
+        }
+
+        Some(CorrelativeMatch {
+            pose: Transform2d::new(
+                translation[0] + best.x_offset as f64 * resolution,
+                translation[1] + best.y_offset as f64 * resolution,
+                wrap_angle(angles[best.angle_index]),
+            ),
+            score: best.score,
+            covariance: self.covariance(&best, &discretized_scans, linear_steps, angular_step),
+        })
+    }
+
+    fn score(&self, level: usize, discretized_scans: &[Vec<(i64, i64)>], angle_index: usize, x_offset: i64, y_offset: i64) -> Candidate {
+        let grid = &self.grids[level];
+        // the pooled grids are padded so that cell indices of the full resolution grid are shifted by this much
+        let pad = (1 << level) - 1;
+        let points = &discretized_scans[angle_index];
+        let sum: f64 = points.iter().map(|(x, y)| grid.get(x + x_offset + pad, y + y_offset + pad) as f64).sum();
+        Candidate { angle_index, x_offset, y_offset, score: sum / points.len() as f64 }
+    }
+
+    fn branch_and_bound(
+        &self,
+        level: usize,
+        mut candidates: Vec<Candidate>,
+        discretized_scans: &[Vec<(i64, i64)>],
+        linear_steps: i64,
+        best: &mut Candidate,
+        found: &mut bool,
+    ) {
+        candidates.sort_by(|a, b| b.score.total_cmp(&a.score));
+        for candidate in candidates {
+            // every score is an upper bound for the scores of its children, so nothing after this can beat the best one
+            if candidate.score <= best.score {
+                break;
+            }
+            if level == 0 {
+                *best = candidate;
+                *found = true;
+                continue;
+            }
+            let half = 1 << (level - 1);
+            let mut children = Vec::with_capacity(4);
+            for dx in [0, half] {
+                for dy in [0, half] {
+                    let (x_offset, y_offset) = (candidate.x_offset + dx, candidate.y_offset + dy);
+                    if x_offset > linear_steps || y_offset > linear_steps {
+                        continue;
+                    }
+                    children.push(self.score(level - 1, discretized_scans, candidate.angle_index, x_offset, y_offset));
+                }
+            }
+            self.branch_and_bound(level - 1, children, discretized_scans, linear_steps, best, found);
+        }
+    }
+
+    /// score-weighted covariance of the full resolution poses around the best one
+    fn covariance(&self, best: &Candidate, discretized_scans: &[Vec<(i64, i64)>], linear_steps: i64, angular_step: f64) -> Matrix3<f64> {
+        const NEIGHBORHOOD: i64 = 2;
+        let resolution = self.params.resolution_meters;
+        let mut samples = Vec::new();
+        for angle_index in best.angle_index.saturating_sub(NEIGHBORHOOD as usize)..=(best.angle_index + NEIGHBORHOOD as usize).min(discretized_scans.len() - 1) {
+            for dx in -NEIGHBORHOOD..=NEIGHBORHOOD {
+                for dy in -NEIGHBORHOOD..=NEIGHBORHOOD {
+                    let (x_offset, y_offset) = (best.x_offset + dx, best.y_offset + dy);
+                    if x_offset.abs() > linear_steps || y_offset.abs() > linear_steps {
+                        continue;
+                    }
+                    let candidate = self.score(0, discretized_scans, angle_index, x_offset, y_offset);
+                    let pose = Vector3::new(
+                        x_offset as f64 * resolution,
+                        y_offset as f64 * resolution,
+                        angle_index as f64 * angular_step,
+                    );
+                    samples.push((pose, candidate.score));
+                }
+            }
+        }
+        let total: f64 = samples.iter().map(|(_, score)| score).sum();
+        let mean = samples.iter().fold(Vector3::zeros(), |acc, (pose, score)| acc + pose * *score) / total;
+        let covariance = samples.iter().fold(Matrix3::zeros(), |acc, (pose, score)| {
+            let d = pose - mean;
+            acc + d * d.transpose() * *score
+        }) / total;
+        // the search can't tell poses apart that are closer than one step
+        covariance + Matrix3::from_diagonal(&Vector3::new(resolution * resolution / 12.0, resolution * resolution / 12.0, angular_step * angular_step / 12.0))
+    }
+}
+
+/// finds the transform that maps scan onto reference, even from a bad initial guess.
+/// the correlative matcher (built from the same reference points) finds the right basin and then icp refines the result.
+pub fn correlative_then_icp(
+    matcher: &CorrelativeScanMatcher,
+    reference: &[Vector2<f64>],
+    scan: &[Vector2<f64>],
+    initial_guess: &Transform2d,
+    icp_params: &IcpParams,
+) -> Option<(IcpResult, CorrelativeMatch)> {
+    let correlative_match = matcher.match_scan(scan, initial_guess)?;
+    let refined = icp(scan, reference, correlative_match.to_icp_guess(), icp_params);
+    Some((refined, correlative_match))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::sim::{room_walls, simulate_scan};
+
+    #[test]
+    fn test_max_pooled_is_upper_bound() {
+        let points = simulate_scan(&room_walls(), &Transform2d::ZERO, 360, 12.0);
+        let grid = LikelihoodGrid::from_points(&points, 0.05, 0.05);
+        let pooled = grid.max_pooled(4);
+        // including blocks that start outside of the grid
+        for y in -3..grid.height as i64 {
+            for x in -3..grid.width as i64 {
+                for (dx, dy) in [(0, 0), (3, 0), (0, 3), (3, 3), (1, 2)] {
+                    assert!(pooled.get(x + 3, y + 3) >= grid.get(x + dx, y + dy));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_match_far_from_initial_guess() {
+        let walls = room_walls();
+        let world_to_reference = Transform2d::new(-1.0, 0.5, 0.2);
+        let world_to_scan = Transform2d::new(-0.4, 0.1, 0.55);
+        let reference = simulate_scan(&walls, &world_to_reference, 720, 12.0);
+        let scan = simulate_scan(&walls, &world_to_scan, 720, 12.0);
+        let expected = -world_to_reference + world_to_scan;
+
+        let params = CorrelativeMatcherParams { angular_window_radians: 0.6, ..Default::default() };
+        let matcher = CorrelativeScanMatcher::new(&reference, params.clone());
+        let result = matcher.match_scan(&scan, &Transform2d::ZERO).unwrap();
+        assert!((result.pose.x_meters - expected.x_meters).abs() < 2.0 * params.resolution_meters, "{:?} {:?}", result, expected);
+        assert!((result.pose.y_meters - expected.y_meters).abs() < 2.0 * params.resolution_meters, "{:?} {:?}", result, expected);
+        assert!(wrap_angle(result.pose.theta_radians - expected.theta_radians).abs() < 0.02, "{:?} {:?}", result, expected);
+        assert!(result.score > 0.5);
+        assert!(result.covariance.symmetric_eigenvalues().iter().all(|eigenvalue| *eigenvalue > 0.0));
+
+        // the correlative result is close enough for icp to nail it
+        let (refined, _) = correlative_then_icp(&matcher, &reference, &scan, &Transform2d::ZERO, &IcpParams::default()).unwrap();
+        let error = refined.x - Vector3::new(expected.x_meters, expected.y_meters, expected.theta_radians);
+        assert!(error.xy().norm() < 0.005, "{:?}", refined);
+        assert!(wrap_angle(error[2]).abs() < 0.005, "{:?}", refined);
+    }
+
+    #[test]
+    fn test_branch_and_bound_matches_brute_force() {
+        let walls = room_walls();
+        let reference = simulate_scan(&walls, &Transform2d::ZERO, 360, 12.0);
+        let scan = simulate_scan(&walls, &Transform2d::new(0.2, -0.15, 0.1), 360, 12.0);
+        let params = CorrelativeMatcherParams { linear_window_meters: 0.3, angular_window_radians: 0.15, depth: 3, min_score: 0.0, ..Default::default() };
+        let matcher = CorrelativeScanMatcher::new(&reference, params.clone());
+        let result = matcher.match_scan(&scan, &Transform2d::ZERO).unwrap();
+
+        let brute_force = CorrelativeScanMatcher::new(&reference, CorrelativeMatcherParams { depth: 0, ..params });
+        let expected = brute_force.match_scan(&scan, &Transform2d::ZERO).unwrap();
+        assert!((result.score - expected.score).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_no_match_below_min_score() {
+        let reference = simulate_scan(&room_walls(), &Transform2d::ZERO, 360, 12.0);
+        // a wall that isn't anywhere in the room
+        let scan = (0..50).map(|i| Vector2::new(10.0, i as f64 * 0.05)).collect::<Vec<_>>();
+        let matcher = CorrelativeScanMatcher::new(&reference, CorrelativeMatcherParams::default());
+        assert!(matcher.match_scan(&scan, &Transform2d::ZERO).is_none());
+    }
+}
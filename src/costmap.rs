@@ -0,0 +1,433 @@
+//! layered 2d costmap, what both the global planner and the local planner look at.
+//! the static layer comes from the occupancy grid, the obstacle layer from live scans (cleared again by rays passing through),
+//! and the keep-out layer from polygons drawn in the dashboard. whatever is lethal in any of them gets inflated by the robot's radius,
+//! with the cost decaying further out so paths keep a bit away from walls when they can.
+
+use std::collections::VecDeque;
+
+use nalgebra::Vector2;
+
+use crate::{
+    drivetrain::XAVIERBOT_FOOTPRINT_RADIUS_METERS,
+    geometry::{point_in_polygon, Transform2d},
+    lidar::ROBOT_TO_LIDAR,
+    occupancy_grid::{bresenham, OccupancyGrid, OccupancyGridConfig, OCCUPIED_THRESHOLD},
+};
+
+/// same values as ros costmap_2d
+pub const FREE_COST: u8 = 0;
+/// the robot's center is closer than its radius (plus the margin) to something
+pub const INSCRIBED_COST: u8 = 253;
+pub const LETHAL_COST: u8 = 254;
+pub const NO_INFORMATION: u8 = 255;
+
+#[derive(Debug, Clone)]
+pub struct CostmapParams {
+    pub robot_radius_meters: f64,
+    /// extra space kept between the robot and obstacles on top of its radius
+    pub safety_margin_meters: f64,
+    /// cost decays out to this far from obstacles
+    pub inflation_radius_meters: f64,
+    /// how fast the cost decays past the inscribed radius, bigger is faster
+    pub cost_scaling_factor: f64,
+    /// whether cells that were never observed are fine to drive through
+    pub allow_unknown: bool,
+    /// scan points further away than this don't mark obstacles
+    pub obstacle_range_meters: f64,
+    /// rays clear obstacles up to this far
+    pub raytrace_range_meters: f64,
+    /// scan points this close to the center are the robot seeing itself
+    pub self_filter_radius_meters: f64,
+}
+
+impl Default for CostmapParams {
+    fn default() -> Self {
+        Self {
+            robot_radius_meters: XAVIERBOT_FOOTPRINT_RADIUS_METERS,
+            safety_margin_meters: 0.05,
+            inflation_radius_meters: 0.5,
+            cost_scaling_factor: 10.0,
+            allow_unknown: true,
+            obstacle_range_meters: 2.5,
+            raytrace_range_meters: 3.0,
+            self_filter_radius_meters: 0.1,
+        }
+    }
+}
+
+impl CostmapParams {
+    /// closer than this to something and the robot hits it
+    pub fn inscribed_radius_meters(&self) -> f64 {
+        self.robot_radius_meters + self.safety_margin_meters
+    }
+}
+
+pub struct Costmap {
+    pub params: CostmapParams,
+    /// same size and place as the occupancy grid it was made for
+    pub config: OccupancyGridConfig,
+    /// LETHAL_COST, FREE_COST or NO_INFORMATION
+    static_layer: Vec<u8>,
+    obstacle_layer: Vec<bool>,
+    keep_out_layer: Vec<bool>,
+    costs: Vec<u8>,
+    /// (min_x, min_y, max_x, max_y) around the cells whose layers changed since costs were last worked out, if any did
+    dirty: Option<(i64, i64, i64, i64)>,
+}
+
+impl Costmap {
+    pub fn new(config: OccupancyGridConfig, params: CostmapParams) -> Self {
+        let cells = config.width_cells * config.height_cells;
+        let unknown = if params.allow_unknown { NO_INFORMATION } else { LETHAL_COST };
+        let mut costmap = Self {
+            params,
+            config,
+            static_layer: vec![unknown; cells],
+            obstacle_layer: vec![false; cells],
+            keep_out_layer: vec![false; cells],
+            costs: vec![NO_INFORMATION; cells],
+            dirty: None,
+        };
+        costmap.mark_all_dirty();
+        costmap
+    }
+
+    /// a costmap with just the static layer from this grid
+    pub fn from_grid(grid: &OccupancyGrid, params: CostmapParams) -> Self {
+        let mut costmap = Self::new(grid.config.clone(), params);
+        costmap.set_static(grid);
+        costmap.update();
+        costmap
+    }
+
+    pub fn width(&self) -> usize {
+        self.config.width_cells
+    }
+
+    pub fn height(&self) -> usize {
+        self.config.height_cells
+    }
+
+    pub fn index(&self, x: i64, y: i64) -> Option<usize> {
+        if x < 0 || y < 0 || x >= self.width() as i64 || y >= self.height() as i64 {
+            None
+        } else {
+            Some(y as usize * self.width() + x as usize)
+        }
+    }
+
+    /// the cell a point is in, if it's in the costmap at all
+    pub fn cell_of(&self, point: &Vector2<f64>) -> Option<(i64, i64)> {
+        let x = ((point[0] - self.config.origin_x_meters) / self.config.resolution_meters).floor() as i64;
+        let y = ((point[1] - self.config.origin_y_meters) / self.config.resolution_meters).floor() as i64;
+        self.index(x, y).map(|_| (x, y))
+    }
+
+    pub fn cell_center(&self, x: i64, y: i64) -> Vector2<f64> {
+        Vector2::new(
+            self.config.origin_x_meters + (x as f64 + 0.5) * self.config.resolution_meters,
+            self.config.origin_y_meters + (y as f64 + 0.5) * self.config.resolution_meters,
+        )
+    }
+
+    /// NO_INFORMATION outside of the costmap
+    pub fn cost(&self, x: i64, y: i64) -> u8 {
+        self.index(x, y).map_or(NO_INFORMATION, |index| self.costs[index])
+    }
+
+    pub fn cost_at(&self, point: &Vector2<f64>) -> u8 {
+        self.cell_of(point).map_or(NO_INFORMATION, |(x, y)| self.cost(x, y))
+    }
+
+    /// replaces the static layer with the occupancy grid, which has to be the same size as the costmap.
+    /// only the cells that changed get re-inflated, a new keyframe usually just touches the area around the robot.
+    pub fn set_static(&mut self, grid: &OccupancyGrid) {
+        assert_eq!((grid.width(), grid.height()), (self.width(), self.height()));
+        let width = self.width();
+        for y in 0..self.height() as i64 {
+            for x in 0..width as i64 {
+                let cost = if grid.probability(x, y) > OCCUPIED_THRESHOLD {
+                    LETHAL_COST
+                } else if !grid.is_known(x, y) {
+                    if self.params.allow_unknown { NO_INFORMATION } else { LETHAL_COST }
+                } else {
+                    FREE_COST
+                };
+                let index = y as usize * width + x as usize;
+                if self.static_layer[index] != cost {
+                    self.static_layer[index] = cost;
+                    self.mark_dirty(x, y);
+                }
+            }
+        }
+    }
+
+    fn mark_dirty(&mut self, x: i64, y: i64) {
+        let dirty = self.dirty.get_or_insert((x, y, x, y));
+        *dirty = (dirty.0.min(x), dirty.1.min(y), dirty.2.max(x), dirty.3.max(y));
+    }
+
+    fn mark_all_dirty(&mut self) {
+        self.dirty = Some((0, 0, self.width() as i64 - 1, self.height() as i64 - 1));
+    }
+
+    fn set_obstacle(&mut self, x: i64, y: i64, obstacle: bool) {
+        if let Some(index) = self.index(x, y) {
+            if self.obstacle_layer[index] != obstacle {
+                self.obstacle_layer[index] = obstacle;
+                self.mark_dirty(x, y);
+            }
+        }
+    }
+
+    /// marks where a scan (robot frame) hit something and clears the obstacles its rays passed through
+    pub fn update_obstacles(&mut self, world_to_robot: &Transform2d, scan: &[Vector2<f64>]) {
+        let world_to_lidar = world_to_robot.clone() + Transform2d::new(ROBOT_TO_LIDAR.x_meters, ROBOT_TO_LIDAR.y_meters, 0.0);
+        let origin = Vector2::new(world_to_lidar.x_meters, world_to_lidar.y_meters);
+        let start = self.world_to_cell(&origin);
+        let self_filter_radius = self.params.self_filter_radius_meters;
+        for point in scan.iter().filter(|point| point.norm() > self_filter_radius) {
+            let world_point = world_to_robot.clone() + Transform2d::new(point[0], point[1], 0.0);
+            let mut end_point = Vector2::new(world_point.x_meters, world_point.y_meters);
+            let range = (end_point - origin).norm();
+            if range > self.params.raytrace_range_meters {
+                end_point = origin + (end_point - origin) * (self.params.raytrace_range_meters / range);
+            }
+            let end = self.world_to_cell(&end_point);
+            let ray = bresenham(start, end);
+            for (x, y) in &ray[..ray.len() - 1] {
+                self.set_obstacle(*x, *y, false);
+            }
+            self.set_obstacle(end.0, end.1, range <= self.params.obstacle_range_meters);
+        }
+    }
+
+    /// forgets everything the obstacle layer has seen
+    pub fn clear_obstacles(&mut self) {
+        self.obstacle_layer.fill(false);
+        self.mark_all_dirty();
+    }
+
+    /// polygons in the world frame the robot must never enter
+    pub fn set_keep_out_zones(&mut self, zones: &[Vec<Vector2<f64>>]) {
+        self.keep_out_layer.fill(false);
+        for zone in zones.iter().filter(|zone| zone.len() >= 3) {
+            let (min, max) = zone.iter().fold((zone[0], zone[0]), |(min, max), vertex| (min.inf(vertex), max.sup(vertex)));
+            let (min_x, min_y) = self.world_to_cell(&min);
+            let (max_x, max_y) = self.world_to_cell(&max);
+            for y in min_y.max(0)..=max_y.min(self.height() as i64 - 1) {
+                for x in min_x.max(0)..=max_x.min(self.width() as i64 - 1) {
+                    if point_in_polygon(zone, &self.cell_center(x, y)) {
+                        let index = self.index(x, y).unwrap();
+                        self.keep_out_layer[index] = true;
+                    }
+                }
+            }
+        }
+        self.mark_all_dirty();
+    }
+
+    /// combines the layers and inflates them around whatever changed since last time
+    pub fn update(&mut self) {
+        let Some((min_x, min_y, max_x, max_y)) = self.dirty.take() else {
+            return;
+        };
+        let resolution = self.config.resolution_meters;
+        let reach = (self.params.inflation_radius_meters / resolution).ceil() as i64;
+        let grow = |cells: i64| {
+            ((min_x - cells).max(0), (min_y - cells).max(0), (max_x + cells).min(self.width() as i64 - 1), (max_y + cells).min(self.height() as i64 - 1))
+        };
+        // costs change as far as inflation reaches from a changed cell. the lethal cells inflating into those are up to
+        // another inflation radius out, and the brushfire from them can go the long way round by one more.
+        let (region_min_x, region_min_y, region_max_x, region_max_y) = grow(reach);
+        let (x0, y0, x1, y1) = grow(3 * reach);
+        let window_width = (x1 - x0 + 1) as usize;
+        let window = |x: i64, y: i64| (y - y0) as usize * window_width + (x - x0) as usize;
+        let cells = window_width * (y1 - y0 + 1) as usize;
+        // brushfire out from every lethal cell, remembering which lethal cell is closest so the distances are euclidean
+        let mut nearest = vec![(0, 0); cells];
+        let mut distance = vec![f64::INFINITY; cells];
+        let mut queue = VecDeque::new();
+        for y in y0..=y1 {
+            for x in x0..=x1 {
+                let index = self.index(x, y).unwrap();
+                if self.static_layer[index] == LETHAL_COST || self.obstacle_layer[index] || self.keep_out_layer[index] {
+                    nearest[window(x, y)] = (x, y);
+                    distance[window(x, y)] = 0.0;
+                    queue.push_back((x, y));
+                }
+            }
+        }
+        while let Some((x, y)) = queue.pop_front() {
+            let source = nearest[window(x, y)];
+            for (dx, dy) in [(1, 0), (-1, 0), (0, 1), (0, -1), (1, 1), (1, -1), (-1, 1), (-1, -1)] {
+                let (nx, ny) = (x + dx, y + dy);
+                if nx < x0 || ny < y0 || nx > x1 || ny > y1 {
+                    continue;
+                }
+                let neighbour = window(nx, ny);
+                let d = (((nx - source.0).pow(2) + (ny - source.1).pow(2)) as f64).sqrt() * resolution;
+                if d < distance[neighbour] && d <= self.params.inflation_radius_meters {
+                    distance[neighbour] = d;
+                    nearest[neighbour] = source;
+                    queue.push_back((nx, ny));
+                }
+            }
+        }
+        let inscribed = self.params.inscribed_radius_meters();
+        for (x, y) in (region_min_y..=region_max_y).flat_map(|y| (region_min_x..=region_max_x).map(move |x| (x, y))) {
+            let (index, d) = (self.index(x, y).unwrap(), distance[window(x, y)]);
+            self.costs[index] = if d == 0.0 {
+                LETHAL_COST
+            } else if d <= inscribed {
+                INSCRIBED_COST
+            } else if d <= self.params.inflation_radius_meters {
+                ((INSCRIBED_COST - 1) as f64 * (-self.params.cost_scaling_factor * (d - inscribed)).exp()) as u8
+            } else {
+                FREE_COST
+            };
+            // decayed all the way to nothing, or never near anything
+            if self.costs[index] == FREE_COST && self.static_layer[index] == NO_INFORMATION {
+                self.costs[index] = NO_INFORMATION;
+            }
+        }
+    }
+
+    /// lethal cells within radius of the robot, as points in the robot frame. cells completely surrounded by other lethal cells are left out,
+    /// nothing can get to them without going through the ones around them first.
+    pub fn obstacles_near(&self, world_to_robot: &Transform2d, radius_meters: f64) -> Vec<Vector2<f64>> {
+        let center = Vector2::new(world_to_robot.x_meters, world_to_robot.y_meters);
+        let robot_to_world = -world_to_robot.clone();
+        let (min_x, min_y) = self.world_to_cell(&(center - Vector2::repeat(radius_meters)));
+        let (max_x, max_y) = self.world_to_cell(&(center + Vector2::repeat(radius_meters)));
+        let lethal = |x: i64, y: i64| self.cost(x, y) == LETHAL_COST;
+        let mut points = Vec::new();
+        for y in min_y.max(0)..=max_y.min(self.height() as i64 - 1) {
+            for x in min_x.max(0)..=max_x.min(self.width() as i64 - 1) {
+                if !lethal(x, y) || (lethal(x + 1, y) && lethal(x - 1, y) && lethal(x, y + 1) && lethal(x, y - 1)) {
+                    continue;
+                }
+                let world = self.cell_center(x, y);
+                if (world - center).norm() <= radius_meters {
+                    let local = robot_to_world.clone() + Transform2d::new(world[0], world[1], 0.0);
+                    points.push(Vector2::new(local.x_meters, local.y_meters));
+                }
+            }
+        }
+        points
+    }
+
+    fn world_to_cell(&self, point: &Vector2<f64>) -> (i64, i64) {
+        (
+            ((point[0] - self.config.origin_x_meters) / self.config.resolution_meters).floor() as i64,
+            ((point[1] - self.config.origin_y_meters) / self.config.resolution_meters).floor() as i64,
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn config() -> OccupancyGridConfig {
+        OccupancyGridConfig { origin_x_meters: -2.0, origin_y_meters: -2.0, width_cells: 80, height_cells: 80, ..Default::default() }
+    }
+
+    #[test]
+    fn test_inflation() {
+        // a single obstacle at the origin, seen from a meter away
+        let mut grid = OccupancyGrid::new(config());
+        grid.integrate_scan(&Transform2d::new(-1.0, 0.0, 0.0), &[Vector2::new(1.0, 0.0)]);
+        let costmap = Costmap::from_grid(&grid, CostmapParams::default());
+        let (x, y) = costmap.cell_of(&Vector2::new(0.0, 0.0)).unwrap();
+        assert_eq!(costmap.cost(x, y), LETHAL_COST);
+        assert_eq!(costmap.cost(x + 3, y), INSCRIBED_COST);
+        let decayed = (5..10).map(|dx| costmap.cost(x + dx, y)).collect::<Vec<_>>();
+        assert!(decayed.windows(2).all(|pair| pair[0] > pair[1] && pair[1] > FREE_COST), "{:?}", decayed);
+        // the ray to the obstacle saw free space, the rest was never seen
+        assert_eq!(costmap.cost_at(&Vector2::new(-0.8, -0.01)), FREE_COST);
+        assert_eq!(costmap.cost(x, y + 12), NO_INFORMATION);
+        assert_eq!(costmap.cost_at(&Vector2::new(10.0, 0.0)), NO_INFORMATION);
+    }
+
+    #[test]
+    fn test_rays_clear_obstacles() {
+        let mut costmap = Costmap::new(config(), CostmapParams::default());
+        let robot = Transform2d::ZERO;
+        // someone walking past
+        costmap.update_obstacles(&robot, &[Vector2::new(0.8, 0.0)]);
+        costmap.update();
+        assert_eq!(costmap.cost_at(&Vector2::new(0.8, 0.0)), LETHAL_COST);
+        assert_eq!(costmap.obstacles_near(&robot, 1.0).len(), 1);
+        // and gone, the lidar sees the wall behind them now
+        costmap.update_obstacles(&robot, &[Vector2::new(1.5, 0.0)]);
+        costmap.update();
+        assert_ne!(costmap.cost_at(&Vector2::new(0.8, 0.0)), LETHAL_COST);
+        assert_eq!(costmap.cost_at(&Vector2::new(1.5, 0.0)), LETHAL_COST);
+        assert!(costmap.obstacles_near(&robot, 1.0).is_empty());
+        // too far away to mark, or even past the raytrace range, but the rays still clear
+        costmap.update_obstacles(&robot, &[Vector2::new(1.0, -0.01), Vector2::new(-1.0, -0.01)]);
+        costmap.update();
+        assert_eq!(costmap.cost_at(&Vector2::new(1.0, -0.01)), LETHAL_COST);
+        costmap.update_obstacles(&robot, &[Vector2::new(3.0, -0.01), Vector2::new(-3.5, -0.01)]);
+        costmap.update();
+        assert_ne!(costmap.cost_at(&Vector2::new(1.0, -0.01)), LETHAL_COST);
+        assert_ne!(costmap.cost_at(&Vector2::new(-1.0, -0.01)), LETHAL_COST);
+        costmap.update_obstacles(&robot, &[Vector2::new(1.0, 1.0)]);
+        costmap.clear_obstacles();
+        costmap.update();
+        assert_ne!(costmap.cost_at(&Vector2::new(1.0, 1.0)), LETHAL_COST);
+    }
+
+    #[test]
+    fn test_update_only_around_changes_matches_full_update() {
+        // big enough that the changes are nowhere near the edges
+        let mut grid = OccupancyGrid::new(OccupancyGridConfig { origin_x_meters: -5.0, origin_y_meters: -5.0, width_cells: 200, height_cells: 200, ..Default::default() });
+        grid.integrate_scan(&Transform2d::new(-1.0, 0.0, 0.0), &[Vector2::new(1.0, 0.0), Vector2::new(1.0, 0.5), Vector2::new(0.0, -1.5)]);
+        let mut costmap = Costmap::from_grid(&grid, CostmapParams::default());
+        let robot = Transform2d::new(-0.5, 0.3, 0.0);
+        for scan in [vec![Vector2::new(0.7, 0.0), Vector2::new(0.0, 0.9)], vec![Vector2::new(1.2, 0.1)], vec![Vector2::new(0.2, -1.4)]] {
+            costmap.update_obstacles(&robot, &scan);
+            let (min_x, _, max_x, _) = costmap.dirty.unwrap();
+            assert!(max_x - min_x < 100);
+            costmap.update();
+            let mut full = Costmap::from_grid(&grid, CostmapParams::default());
+            full.obstacle_layer = costmap.obstacle_layer.clone();
+            full.mark_all_dirty();
+            full.update();
+            assert!(costmap.costs == full.costs);
+        }
+        // nothing changed, nothing to do
+        costmap.update_obstacles(&robot, &[Vector2::new(0.2, -1.4)]);
+        assert_eq!(costmap.dirty, None);
+        // a new keyframe only dirties what it saw
+        grid.integrate_scan(&Transform2d::new(1.5, 1.5, 0.0), &[Vector2::new(0.5, 0.0)]);
+        costmap.set_static(&grid);
+        let (min_x, min_y, max_x, max_y) = costmap.dirty.unwrap();
+        assert!(max_x - min_x < 100 && max_y - min_y < 100);
+        costmap.update();
+        let mut full = Costmap::from_grid(&grid, CostmapParams::default());
+        full.obstacle_layer = costmap.obstacle_layer.clone();
+        full.mark_all_dirty();
+        full.update();
+        assert!(costmap.costs == full.costs);
+        costmap.set_static(&grid);
+        assert_eq!(costmap.dirty, None);
+    }
+
+    #[test]
+    fn test_keep_out_zones() {
+        let mut costmap = Costmap::new(config(), CostmapParams::default());
+        costmap.set_keep_out_zones(&[vec![Vector2::new(0.5, -0.5), Vector2::new(1.0, -0.5), Vector2::new(1.0, 0.5), Vector2::new(0.5, 0.5)]]);
+        costmap.update();
+        assert_eq!(costmap.cost_at(&Vector2::new(0.75, 0.0)), LETHAL_COST);
+        assert_eq!(costmap.cost_at(&Vector2::new(0.4, 0.0)), INSCRIBED_COST);
+        assert_eq!(costmap.cost_at(&Vector2::new(-0.2, 0.0)), NO_INFORMATION);
+        // only the edge of the zone, facing the robot this is the side at x = 0.5
+        let near = costmap.obstacles_near(&Transform2d::ZERO, 0.6);
+        assert!(!near.is_empty() && near.iter().all(|point| point[0] < 0.6), "{:?}", near);
+        costmap.set_keep_out_zones(&[]);
+        costmap.update();
+        assert_eq!(costmap.cost_at(&Vector2::new(0.75, 0.0)), NO_INFORMATION);
+    }
+}
@@ -0,0 +1,333 @@
+//! coverage planning for surveying rooms: boustrophedon (ox plowing) rows back and forth over the free space in a polygon.
+//! the rows get split into cells wherever an obstacle splits or merges them, and each cell is swept in one go.
+//! the tracker keeps count of how much of the area the lidar has actually seen while driving.
+
+use std::f64::consts::PI;
+
+use nalgebra::{Rotation2, Vector2};
+use serde::Serialize;
+
+use crate::{
+    costmap::{Costmap, INSCRIBED_COST, LETHAL_COST},
+    geometry::{point_in_polygon, Transform2d},
+    lidar::ROBOT_TO_LIDAR,
+    mission::MissionWaypoint,
+    occupancy_grid::bresenham,
+    paths::Path,
+};
+
+#[derive(Debug, Clone)]
+pub struct CoverageParams {
+    pub row_spacing_meters: f64,
+    /// which way the rows run, 0 is along x in the world frame
+    pub sweep_angle_radians: f64,
+    /// free stretches of a row shorter than this aren't worth driving
+    pub min_row_length_meters: f64,
+    /// the lidar footprint: cells count as covered once a ray has passed through them this close to the robot
+    pub sensor_range_meters: f64,
+}
+
+impl Default for CoverageParams {
+    fn default() -> Self {
+        Self { row_spacing_meters: 0.4, sweep_angle_radians: 0.0, min_row_length_meters: 0.1, sensor_range_meters: 1.0 }
+    }
+}
+
+/// a free stretch of a row, in the sweep frame where rows run along x
+#[derive(Debug, Clone, Copy)]
+struct RowSegment {
+    y: f64,
+    start_x: f64,
+    end_x: f64,
+}
+
+impl RowSegment {
+    fn overlaps(&self, other: &RowSegment) -> bool {
+        self.start_x <= other.end_x && other.start_x <= self.end_x
+    }
+}
+
+/// segments in consecutive rows that nothing splits or merges, the robot can sweep these without leaving
+struct Cell {
+    segments: Vec<RowSegment>,
+}
+
+impl Cell {
+    /// the sweep starting from one of the cell's four corners, as (sweep frame position, heading) pairs
+    fn sweep(&self, reverse_rows: bool, forwards_first: bool) -> Vec<(Vector2<f64>, f64)> {
+        let mut segments = self.segments.clone();
+        if reverse_rows {
+            segments.reverse();
+        }
+        segments
+            .iter()
+            .enumerate()
+            .flat_map(|(i, segment)| {
+                let (start, end) = (Vector2::new(segment.start_x, segment.y), Vector2::new(segment.end_x, segment.y));
+                if (i % 2 == 0) == forwards_first { [(start, 0.0), (end, 0.0)] } else { [(end, PI), (start, PI)] }
+            })
+            .collect()
+    }
+}
+
+/// whether the robot's center can be in a cell with this cost
+fn traversable(cost: u8) -> bool {
+    cost != INSCRIBED_COST && cost != LETHAL_COST
+}
+
+/// free stretches of every row in the polygon, first row first. rows are spaced evenly and start half a row in from the edge.
+fn rows(costmap: &Costmap, polygon: &[Vector2<f64>], params: &CoverageParams) -> Vec<Vec<RowSegment>> {
+    let to_world = Rotation2::new(params.sweep_angle_radians);
+    let swept = polygon.iter().map(|vertex| to_world.inverse() * vertex).collect::<Vec<_>>();
+    let (min, max) = swept.iter().fold((swept[0], swept[0]), |(min, max), vertex| (min.inf(vertex), max.sup(vertex)));
+    // finer than the costmap, so diagonal rows don't skip cells
+    let step = costmap.config.resolution_meters / 2.0;
+    let mut rows = Vec::new();
+    let mut y = min[1] + params.row_spacing_meters / 2.0;
+    while y < max[1] {
+        let mut segments = Vec::new();
+        let mut current: Option<(f64, f64)> = None;
+        let mut x = min[0];
+        while x <= max[0] {
+            let point = Vector2::new(x, y);
+            let free = point_in_polygon(&swept, &point) && traversable(costmap.cost_at(&(to_world * point)));
+            match (free, &mut current) {
+                (true, Some((_, end))) => *end = x,
+                (true, None) => current = Some((x, x)),
+                (false, Some(_)) => segments.extend(current.take()),
+                (false, None) => {}
+            }
+            x += step;
+        }
+        segments.extend(current);
+        rows.push(
+            segments
+                .into_iter()
+                .filter(|(start_x, end_x)| end_x - start_x >= params.min_row_length_meters)
+                .map(|(start_x, end_x)| RowSegment { y, start_x, end_x })
+                .collect(),
+        );
+        y += params.row_spacing_meters;
+    }
+    rows
+}
+
+/// a new cell starts wherever a segment doesn't continue exactly one segment of the row before (something split or merged the rows)
+fn decompose(rows: Vec<Vec<RowSegment>>) -> Vec<Cell> {
+    let mut cells: Vec<Cell> = Vec::new();
+    // the previous row's segments and the cells they ended up in
+    let mut previous: Vec<(RowSegment, usize)> = Vec::new();
+    for row in rows {
+        let mut current = Vec::with_capacity(row.len());
+        for segment in &row {
+            let before = previous.iter().filter(|(other, _)| other.overlaps(segment)).collect::<Vec<_>>();
+            let cell = match before[..] {
+                [(other, cell)] if row.iter().filter(|next| next.overlaps(other)).count() == 1 => {
+                    cells[*cell].segments.push(*segment);
+                    *cell
+                }
+                _ => {
+                    cells.push(Cell { segments: vec![*segment] });
+                    cells.len() - 1
+                }
+            };
+            current.push((*segment, cell));
+        }
+        previous = current;
+    }
+    cells
+}
+
+/// back and forth paths covering the free space in a world frame polygon, one per cell, in the order to drive them.
+/// cells are picked greedily by whichever corner is closest to where the last one ended, starting from start.
+/// the paths only go along rows, getting from the end of one row to the start of the next is up to the planner.
+pub fn plan_coverage(costmap: &Costmap, polygon: &[Vector2<f64>], start: &Transform2d, params: &CoverageParams) -> Vec<Path> {
+    if polygon.len() < 3 || params.row_spacing_meters <= 0.0 {
+        return Vec::new();
+    }
+    let to_world = Rotation2::new(params.sweep_angle_radians);
+    let mut remaining = decompose(rows(costmap, polygon, params));
+    let mut position = to_world.inverse() * Vector2::new(start.x_meters, start.y_meters);
+    let mut paths = Vec::with_capacity(remaining.len());
+    while !remaining.is_empty() {
+        let (index, sweep) = remaining
+            .iter()
+            .enumerate()
+            .flat_map(|(index, cell)| [(false, true), (false, false), (true, true), (true, false)].map(|(reverse_rows, forwards_first)| (index, cell.sweep(reverse_rows, forwards_first))))
+            .min_by(|(_, a), (_, b)| (a[0].0 - position).norm().total_cmp(&(b[0].0 - position).norm()))
+            .unwrap();
+        remaining.swap_remove(index);
+        position = sweep.last().unwrap().0;
+        paths.push(Path {
+            waypoints: sweep
+                .into_iter()
+                .map(|(point, heading)| {
+                    let point = to_world * point;
+                    Transform2d::new(point[0], point[1], heading + params.sweep_angle_radians)
+                })
+                .collect(),
+        });
+    }
+    paths
+}
+
+/// drives the coverage paths as a mission, stopping at the end of every row
+pub fn to_mission(paths: &[Path]) -> Vec<MissionWaypoint> {
+    paths.iter().flat_map(|path| &path.waypoints).map(|pose| MissionWaypoint { pose: pose.clone(), actions: Vec::new() }).collect()
+}
+
+/// sent to the dashboard as the robot drives
+#[derive(Debug, Clone, Serialize)]
+pub struct CoverageProgress {
+    pub covered_square_meters: f64,
+    pub area_square_meters: f64,
+    pub percent: f64,
+}
+
+/// how much of a polygon the lidar has seen. uses the costmap's cells, the area is every cell in the polygon that isn't an obstacle.
+pub struct CoverageTracker {
+    pub params: CoverageParams,
+    origin: Vector2<f64>,
+    resolution_meters: f64,
+    width: usize,
+    height: usize,
+    area: Vec<bool>,
+    covered: Vec<bool>,
+    area_cells: usize,
+    covered_cells: usize,
+}
+
+impl CoverageTracker {
+    pub fn new(costmap: &Costmap, polygon: &[Vector2<f64>], params: CoverageParams) -> Self {
+        let (width, height) = (costmap.width(), costmap.height());
+        let area = (0..height as i64)
+            .flat_map(|y| (0..width as i64).map(move |x| (x, y)))
+            .map(|(x, y)| polygon.len() >= 3 && costmap.cost(x, y) != LETHAL_COST && point_in_polygon(polygon, &costmap.cell_center(x, y)))
+            .collect::<Vec<_>>();
+        Self {
+            params,
+            origin: Vector2::new(costmap.config.origin_x_meters, costmap.config.origin_y_meters),
+            resolution_meters: costmap.config.resolution_meters,
+            width,
+            height,
+            area_cells: area.iter().filter(|in_area| **in_area).count(),
+            covered: vec![false; area.len()],
+            area,
+            covered_cells: 0,
+        }
+    }
+
+    fn cell_of(&self, point: &Vector2<f64>) -> (i64, i64) {
+        let cell = ((point - self.origin) / self.resolution_meters).map(|coordinate| coordinate.floor() as i64);
+        (cell[0], cell[1])
+    }
+
+    fn index(&self, x: i64, y: i64) -> Option<usize> {
+        if x < 0 || y < 0 || x >= self.width as i64 || y >= self.height as i64 {
+            None
+        } else {
+            Some(y as usize * self.width + x as usize)
+        }
+    }
+
+    /// marks the cells a robot frame scan's rays passed through (up to the sensor range) as covered
+    pub fn update(&mut self, world_to_robot: &Transform2d, scan: &[Vector2<f64>]) {
+        let world_to_lidar = world_to_robot.clone() + Transform2d::new(ROBOT_TO_LIDAR.x_meters, ROBOT_TO_LIDAR.y_meters, 0.0);
+        let origin = Vector2::new(world_to_lidar.x_meters, world_to_lidar.y_meters);
+        let start = self.cell_of(&origin);
+        for point in scan {
+            let world_point = world_to_robot.clone() + Transform2d::new(point[0], point[1], 0.0);
+            let mut end_point = Vector2::new(world_point.x_meters, world_point.y_meters);
+            let range = (end_point - origin).norm();
+            if range > self.params.sensor_range_meters {
+                end_point = origin + (end_point - origin) * (self.params.sensor_range_meters / range);
+            }
+            for (x, y) in bresenham(start, self.cell_of(&end_point)) {
+                if let Some(index) = self.index(x, y) {
+                    if self.area[index] && !self.covered[index] {
+                        self.covered[index] = true;
+                        self.covered_cells += 1;
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn progress(&self) -> CoverageProgress {
+        let cell_area = self.resolution_meters.powi(2);
+        CoverageProgress {
+            covered_square_meters: self.covered_cells as f64 * cell_area,
+            area_square_meters: self.area_cells as f64 * cell_area,
+            percent: if self.area_cells == 0 { 100.0 } else { 100.0 * self.covered_cells as f64 / self.area_cells as f64 },
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::sim::{empty_costmap, square};
+    use assert_approx_eq::assert_approx_eq;
+
+    #[test]
+    fn test_sweeps_empty_room_back_and_forth() {
+        let paths = plan_coverage(&empty_costmap(), &square(0.0, 2.0), &Transform2d::ZERO, &CoverageParams::default());
+        assert_eq!(paths.len(), 1);
+        let waypoints = &paths[0].waypoints;
+        // 5 rows 0.4m apart, starting in the corner closest to the robot
+        assert_eq!(waypoints.len(), 10);
+        assert_approx_eq!(waypoints[0].y_meters, 0.2, 1e-9);
+        assert_approx_eq!(waypoints[9].y_meters, 1.8, 1e-9);
+        assert!(waypoints[0].x_meters < 0.05 && waypoints[1].x_meters > 1.95, "{:?}", waypoints);
+        assert_eq!(waypoints[0].theta_radians, 0.0);
+        assert_eq!(waypoints[2].theta_radians, PI);
+        assert!(waypoints[3].x_meters < 0.05);
+
+        // rows along y instead
+        let params = CoverageParams { sweep_angle_radians: PI / 2.0, ..Default::default() };
+        let paths = plan_coverage(&empty_costmap(), &square(0.0, 2.0), &Transform2d::ZERO, &params);
+        assert_approx_eq!(paths[0].waypoints[0].x_meters, 0.2, 1e-9);
+        assert!(paths[0].waypoints[1].y_meters > 1.95);
+        assert_eq!(to_mission(&paths).len(), 10);
+    }
+
+    #[test]
+    fn test_splits_around_obstacle() {
+        let mut costmap = empty_costmap();
+        costmap.set_keep_out_zones(&[square(0.9, 1.1)]);
+        costmap.update();
+        let paths = plan_coverage(&costmap, &square(0.0, 2.0), &Transform2d::ZERO, &CoverageParams::default());
+        // below, left of, right of and above the pillar
+        assert_eq!(paths.len(), 4);
+        for pose in paths.iter().flat_map(|path| &path.waypoints) {
+            assert!(traversable(costmap.cost_at(&Vector2::new(pose.x_meters, pose.y_meters))), "{:?}", pose);
+        }
+        // the row through the pillar got split in two
+        assert_eq!(paths.iter().map(|path| path.waypoints.len()).sum::<usize>(), 2 * 6);
+    }
+
+    #[test]
+    fn test_tracks_coverage_with_lidar_footprint() {
+        let costmap = empty_costmap();
+        let mut tracker = CoverageTracker::new(&costmap, &square(0.0, 2.0), CoverageParams::default());
+        assert_approx_eq!(tracker.progress().area_square_meters, 4.0, 1e-9);
+        assert_eq!(tracker.progress().percent, 0.0);
+        let scan = (0..360).map(|i| {
+            let angle = (i as f64).to_radians();
+            Vector2::new(5.0 * angle.cos(), 5.0 * angle.sin())
+        }).collect::<Vec<_>>();
+        tracker.update(&Transform2d::new(1.0, 1.0, 0.0), &scan);
+        // a circle with the sensor range as its radius, everything else is too far away
+        let progress = tracker.progress();
+        assert!((progress.covered_square_meters - PI).abs() < 0.3, "{:?}", progress);
+        tracker.update(&Transform2d::new(1.0, 1.0, 0.0), &scan);
+        assert_eq!(tracker.progress().covered_square_meters, progress.covered_square_meters);
+        for x in [0.5, 1.5] {
+            for y in [0.5, 1.5] {
+                tracker.update(&Transform2d::new(x, y, 0.0), &scan);
+            }
+        }
+        // all the rays from one pose start in the same cell, so the odd cell between them gets missed
+        assert!(tracker.progress().percent > 99.5, "{:?}", tracker.progress());
+    }
+}
@@ -16,7 +16,9 @@ use crate::{geometry::Twist2d, odometry::DifferentialDriveWheelPositions};
 
 const XAVIERBOT_METERS_PER_ENCODER_CLICK: f64 = 2.0 * PI * (65.0 / 2.0 / 1000.0) / 1632.0; // TODO real value
 pub const XAVIERBOT_WHEEL_SEPARATION_METERS: f64 = 0.2;
-const XAVIERBOT_MAX_SPEED_FEASIBLE: f64 = 0.5; // TODO real value
+/// radius of a circle around the robot center that contains the whole robot
+pub const XAVIERBOT_FOOTPRINT_RADIUS_METERS: f64 = 0.15; // TODO real value
+pub const XAVIERBOT_MAX_SPEED_FEASIBLE: f64 = 0.5; // TODO real value
 
 pub async fn start_drivetrain_thread(io: SocketIo) -> (
     Arc<Mutex<Twist2d>>,
@@ -0,0 +1,399 @@
+//! frontier based exploration: drive to the edge between known free space and unknown space until there's no edge left to drive to.
+//! frontier cells get clustered, and each cluster is scored by how much unknown space is around it minus how far the robot has to drive there.
+//! this only picks goals, the behaviour plans and follows them like any other goal while the pose graph keeps mapping.
+
+use std::{cmp::Ordering, collections::{BinaryHeap, VecDeque}};
+
+use nalgebra::Vector2;
+use serde::Serialize;
+use tokio::time::Duration;
+
+use crate::{
+    costmap::{Costmap, INSCRIBED_COST, LETHAL_COST},
+    geometry::Transform2d,
+    goal::{AbortReason, NavigationResult},
+    occupancy_grid::{OccupancyGrid, FREE_THRESHOLD},
+};
+
+#[derive(Debug, Clone)]
+pub struct ExplorationParams {
+    /// smaller clusters are usually noise (a gap between two scans), not somewhere new
+    pub min_frontier_cells: usize,
+    /// unknown space within this far of a frontier counts as what driving there would reveal
+    pub sensor_range_meters: f64,
+    /// score per square meter of unknown space around a frontier
+    pub information_gain_weight: f64,
+    /// score taken off per meter of driving
+    pub travel_cost_weight: f64,
+    /// frontiers this close to a goal that was already tried are skipped
+    pub tried_goal_radius_meters: f64,
+    /// frontiers are only looked for this far of driving away, the whole map only gets searched when there's nothing closer.
+    /// keeps the search cheap enough to run in the main loop.
+    pub search_radius_meters: f64,
+    /// how often the frontiers get looked at again while driving, the one being driven to may have been mapped already
+    pub replan_period: Duration,
+    /// only switch goals while driving if the best frontier moved further than this
+    pub min_goal_change_meters: f64,
+}
+
+impl Default for ExplorationParams {
+    fn default() -> Self {
+        Self {
+            min_frontier_cells: 5,
+            sensor_range_meters: 1.5,
+            information_gain_weight: 1.0,
+            travel_cost_weight: 0.5,
+            tried_goal_radius_meters: 0.3,
+            search_radius_meters: 4.0,
+            replan_period: Duration::from_secs(2),
+            min_goal_change_meters: 0.5,
+        }
+    }
+}
+
+/// a cluster of frontier cells, sent to the dashboard
+#[derive(Debug, Clone, Serialize)]
+pub struct Frontier {
+    pub centroid: [f64; 2],
+    /// the reachable frontier cell closest to the centroid, which is where the robot drives to
+    pub goal: [f64; 2],
+    pub cells: usize,
+    /// square meters of unknown space around the centroid
+    pub information_gain: f64,
+    /// meters of driving to the goal
+    pub travel_cost: f64,
+    pub score: f64,
+}
+
+/// sent to the dashboard whenever it changes
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub enum ExplorationStatus {
+    Exploring { frontiers: usize },
+    /// no reachable frontiers left
+    Done,
+    Stopped,
+}
+
+/// known free cells next to an unknown cell
+fn is_frontier(grid: &OccupancyGrid, x: i64, y: i64) -> bool {
+    grid.is_known(x, y)
+        && grid.probability(x, y) < FREE_THRESHOLD
+        && [(1, 0), (-1, 0), (0, 1), (0, -1)].iter().any(|(dx, dy)| grid.index(x + dx, y + dy).is_some() && !grid.is_known(x + dx, y + dy))
+}
+
+#[derive(PartialEq)]
+struct OpenCell {
+    distance: f64,
+    index: usize,
+}
+
+impl Eq for OpenCell {}
+
+impl Ord for OpenCell {
+    fn cmp(&self, other: &Self) -> Ordering {
+        // reversed so the binary heap pops the closest first
+        other.distance.total_cmp(&self.distance)
+    }
+}
+
+impl PartialOrd for OpenCell {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// how far the robot has to drive to every cell of the costmap, infinity where it can't get to or it's further than max_distance_meters.
+/// like the planner, it may drive out of inflated cells around where it starts.
+fn travel_distances(costmap: &Costmap, start: &Vector2<f64>, max_distance_meters: f64) -> Vec<f64> {
+    let (width, height) = (costmap.width(), costmap.height());
+    let mut distances = vec![f64::INFINITY; width * height];
+    let Some(start) = costmap.cell_of(start) else {
+        return distances;
+    };
+    let escape_radius_cells = costmap.params.inscribed_radius_meters() / costmap.config.resolution_meters;
+    let traversable = |x: i64, y: i64| match costmap.cost(x, y) {
+        LETHAL_COST => false,
+        INSCRIBED_COST => (((x - start.0).pow(2) + (y - start.1).pow(2)) as f64).sqrt() <= escape_radius_cells,
+        _ => true,
+    };
+    let mut open = BinaryHeap::new();
+    let start_index = costmap.index(start.0, start.1).unwrap();
+    distances[start_index] = 0.0;
+    open.push(OpenCell { distance: 0.0, index: start_index });
+    while let Some(OpenCell { distance, index }) = open.pop() {
+        if distance > distances[index] {
+            continue;
+        }
+        let (x, y) = ((index % width) as i64, (index / width) as i64);
+        for (dx, dy) in [(1, 0), (-1, 0), (0, 1), (0, -1), (1, 1), (1, -1), (-1, 1), (-1, -1)] {
+            let Some(neighbor) = costmap.index(x + dx, y + dy).filter(|_| traversable(x + dx, y + dy)) else {
+                continue;
+            };
+            let step = if dx != 0 && dy != 0 { std::f64::consts::SQRT_2 } else { 1.0 } * costmap.config.resolution_meters;
+            if distance + step <= max_distance_meters && distance + step < distances[neighbor] {
+                distances[neighbor] = distance + step;
+                open.push(OpenCell { distance: distance + step, index: neighbor });
+            }
+        }
+    }
+    distances
+}
+
+/// square meters of unknown cells within radius of a point
+fn unknown_area_around(grid: &OccupancyGrid, point: &Vector2<f64>, radius_meters: f64) -> f64 {
+    let radius_cells = (radius_meters / grid.config.resolution_meters).ceil() as i64;
+    let (center_x, center_y) = grid.world_to_cell(point);
+    let unknown = (-radius_cells..=radius_cells)
+        .flat_map(|dy| (-radius_cells..=radius_cells).map(move |dx| (dx, dy)))
+        .filter(|(dx, dy)| dx * dx + dy * dy <= radius_cells * radius_cells)
+        .filter(|(dx, dy)| grid.index(center_x + dx, center_y + dy).is_some() && !grid.is_known(center_x + dx, center_y + dy))
+        .count();
+    unknown as f64 * grid.config.resolution_meters.powi(2)
+}
+
+/// the frontier clusters in the grid that can be reached within max_travel_meters, best first.
+/// the costmap has to be the same size as the grid.
+pub fn find_frontiers(grid: &OccupancyGrid, costmap: &Costmap, pose: &Transform2d, params: &ExplorationParams, max_travel_meters: f64) -> Vec<Frontier> {
+    let (width, height) = (grid.width(), grid.height());
+    let distances = travel_distances(costmap, &Vector2::new(pose.x_meters, pose.y_meters), max_travel_meters);
+    let mut visited = vec![false; width * height];
+    let mut frontiers = Vec::new();
+    for start in 0..width * height {
+        let (x, y) = ((start % width) as i64, (start / width) as i64);
+        if visited[start] || !is_frontier(grid, x, y) {
+            continue;
+        }
+        // flood fill the cluster, diagonal neighbours count
+        let mut cluster = Vec::new();
+        let mut queue = VecDeque::from([(x, y)]);
+        visited[start] = true;
+        while let Some((x, y)) = queue.pop_front() {
+            cluster.push((x, y));
+            for dy in -1..=1 {
+                for dx in -1..=1 {
+                    let Some(index) = grid.index(x + dx, y + dy) else {
+                        continue;
+                    };
+                    if !visited[index] && is_frontier(grid, x + dx, y + dy) {
+                        visited[index] = true;
+                        queue.push_back((x + dx, y + dy));
+                    }
+                }
+            }
+        }
+        if cluster.len() < params.min_frontier_cells {
+            continue;
+        }
+        let centroid = cluster.iter().map(|(x, y)| grid.cell_to_world(*x, *y)).sum::<Vector2<f64>>() / cluster.len() as f64;
+        let goal = cluster
+            .iter()
+            .filter(|(x, y)| distances[grid.index(*x, *y).unwrap()].is_finite())
+            .map(|(x, y)| grid.cell_to_world(*x, *y))
+            .min_by(|a, b| (a - centroid).norm().total_cmp(&(b - centroid).norm()));
+        let Some(goal) = goal else {
+            continue;
+        };
+        let (goal_x, goal_y) = grid.world_to_cell(&goal);
+        let information_gain = unknown_area_around(grid, &centroid, params.sensor_range_meters);
+        let travel_cost = distances[grid.index(goal_x, goal_y).unwrap()];
+        frontiers.push(Frontier {
+            centroid: [centroid[0], centroid[1]],
+            goal: [goal[0], goal[1]],
+            cells: cluster.len(),
+            information_gain,
+            travel_cost,
+            score: params.information_gain_weight * information_gain - params.travel_cost_weight * travel_cost,
+        });
+    }
+    frontiers.sort_by(|a, b| b.score.total_cmp(&a.score));
+    frontiers
+}
+
+/// what the main loop should do next
+#[derive(Debug, Clone)]
+pub enum ExplorationUpdate {
+    /// keep doing what it's doing
+    Continue,
+    /// pathfind here
+    Goal(Transform2d),
+    Done,
+}
+
+/// picks frontier goals one after another. the main loop hands the goals to the behaviour and tells this how they went.
+pub struct Explorer {
+    pub params: ExplorationParams,
+    goal: Option<Vector2<f64>>,
+    /// goals that couldn't be reached, or were reached and may still look like a frontier.
+    /// either way driving there again won't map anything new.
+    tried: Vec<Vector2<f64>>,
+    last_update: Option<Duration>,
+    /// a new goal replaced one that was still being driven to, which reports the old one as cancelled
+    replaced_goal: bool,
+    frontiers: Vec<Frontier>,
+}
+
+impl Explorer {
+    pub fn new(params: ExplorationParams) -> Self {
+        Self { params, goal: None, tried: Vec::new(), last_update: None, replaced_goal: false, frontiers: Vec::new() }
+    }
+
+    /// the frontiers as of the last time they were looked at, best first
+    pub fn frontiers(&self) -> &[Frontier] {
+        &self.frontiers
+    }
+
+    /// the frontiers within max_travel_meters that haven't been tried yet
+    fn search(&self, grid: &OccupancyGrid, costmap: &Costmap, pose: &Transform2d, max_travel_meters: f64) -> Vec<Frontier> {
+        let mut frontiers = find_frontiers(grid, costmap, pose, &self.params, max_travel_meters);
+        frontiers.retain(|frontier| {
+            let goal = Vector2::new(frontier.goal[0], frontier.goal[1]);
+            !self.tried.iter().any(|tried| (tried - goal).norm() < self.params.tried_goal_radius_meters)
+        });
+        frontiers
+    }
+
+    /// looks for frontiers right after a goal is over, and every so often otherwise
+    pub fn update(&mut self, grid: &OccupancyGrid, costmap: &Costmap, pose: &Transform2d, now: Duration) -> ExplorationUpdate {
+        if self.last_update.is_some_and(|last| now.saturating_sub(last) < self.params.replan_period) {
+            return ExplorationUpdate::Continue;
+        }
+        self.last_update = Some(now);
+        self.frontiers = self.search(grid, costmap, pose, self.params.search_radius_meters);
+        if self.frontiers.is_empty() && self.goal.is_none() {
+            // nothing nearby, the whole map has to be looked at before calling it done
+            self.frontiers = self.search(grid, costmap, pose, f64::INFINITY);
+        }
+        let Some(best) = self.frontiers.first() else {
+            return if self.goal.is_some() { ExplorationUpdate::Continue } else { ExplorationUpdate::Done };
+        };
+        let best = Vector2::new(best.goal[0], best.goal[1]);
+        if let Some(goal) = self.goal {
+            if (goal - best).norm() < self.params.min_goal_change_meters {
+                return ExplorationUpdate::Continue;
+            }
+            self.replaced_goal = true;
+        }
+        self.goal = Some(best);
+        // facing into the unknown
+        let heading = (best[1] - pose.y_meters).atan2(best[0] - pose.x_meters);
+        ExplorationUpdate::Goal(Transform2d::new(best[0], best[1], heading))
+    }
+
+    /// returns whether to keep exploring. a goal getting cancelled by anything other than the next goal stops exploring.
+    pub fn navigation_result(&mut self, result: &NavigationResult) -> bool {
+        match result {
+            // the goal itself was fine, it gets picked again once the robot can drive
+            NavigationResult::Aborted(AbortReason::EStop | AbortReason::Refused) => {
+                self.goal = None;
+                true
+            }
+            // a frontier that's still there after the robot reached it (a window, glass) would get picked forever
+            NavigationResult::Succeeded | NavigationResult::Aborted(_) => {
+                self.tried.extend(self.goal.take());
+                self.last_update = None;
+                true
+            }
+            NavigationResult::Cancelled => std::mem::take(&mut self.replaced_goal),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{costmap::CostmapParams, occupancy_grid::OccupancyGridConfig};
+
+    fn config() -> OccupancyGridConfig {
+        OccupancyGridConfig { origin_x_meters: -3.0, origin_y_meters: -3.0, width_cells: 120, height_cells: 120, max_range_meters: 1.0, ..Default::default() }
+    }
+
+    /// a corridor along x with walls at y = +-0.5, seen from the origin by a lidar that only reaches 1m, everything else unknown
+    fn corridor() -> OccupancyGrid {
+        let mut grid = OccupancyGrid::new(config());
+        let scan = (0..720)
+            .map(|i| {
+                let angle = (i as f64 / 2.0).to_radians();
+                let range = (0.5 / angle.sin().abs()).min(5.0);
+                Vector2::new(range * angle.cos(), range * angle.sin())
+            })
+            .collect::<Vec<_>>();
+        for _ in 0..3 {
+            grid.integrate_scan(&Transform2d::ZERO, &scan);
+        }
+        grid
+    }
+
+    #[test]
+    fn test_finds_frontiers_at_corridor_ends() {
+        let grid = corridor();
+        let costmap = Costmap::from_grid(&grid, CostmapParams::default());
+        let frontiers = find_frontiers(&grid, &costmap, &Transform2d::ZERO, &ExplorationParams::default(), f64::INFINITY);
+        // one at either end of the corridor
+        assert_eq!(frontiers.len(), 2, "{:?}", frontiers);
+        for frontier in &frontiers {
+            assert!((0.7..1.2).contains(&frontier.centroid[0].abs()), "{:?}", frontier);
+            assert!(frontier.centroid[1].abs() < 0.2, "{:?}", frontier);
+            assert!(frontier.travel_cost > 0.7 && frontier.travel_cost < 1.2, "{:?}", frontier);
+            assert!(frontier.information_gain > 0.5, "{:?}", frontier);
+        }
+        assert!(frontiers[0].score >= frontiers[1].score);
+        // neither end is within half a meter of driving
+        assert!(find_frontiers(&grid, &costmap, &Transform2d::ZERO, &ExplorationParams::default(), 0.5).is_empty());
+
+        // further to drive to the right end, so the left one wins
+        let frontiers = find_frontiers(&grid, &costmap, &Transform2d::new(-0.5, 0.0, 0.0), &ExplorationParams::default(), f64::INFINITY);
+        assert!(frontiers[0].centroid[0] < 0.0, "{:?}", frontiers);
+    }
+
+    #[test]
+    fn test_explorer_moves_on_and_finishes() {
+        let grid = corridor();
+        let costmap = Costmap::from_grid(&grid, CostmapParams::default());
+        let mut explorer = Explorer::new(ExplorationParams::default());
+        let pose = Transform2d::new(-0.5, 0.0, 0.0);
+        let ExplorationUpdate::Goal(first) = explorer.update(&grid, &costmap, &pose, Duration::ZERO) else {
+            panic!("no goal");
+        };
+        assert!(first.x_meters < 0.0);
+        assert!(matches!(explorer.update(&grid, &costmap, &pose, Duration::from_millis(100)), ExplorationUpdate::Continue));
+        // couldn't get there, so the other end is next
+        assert!(explorer.navigation_result(&NavigationResult::Aborted(AbortReason::Stalled)));
+        let ExplorationUpdate::Goal(second) = explorer.update(&grid, &costmap, &pose, Duration::from_millis(200)) else {
+            panic!("no goal");
+        };
+        assert!(second.x_meters > 0.0);
+        assert!(explorer.navigation_result(&NavigationResult::Aborted(AbortReason::Stalled)));
+        assert!(matches!(explorer.update(&grid, &costmap, &pose, Duration::from_millis(300)), ExplorationUpdate::Done));
+        // someone else cancelling stops it
+        assert!(!explorer.navigation_result(&NavigationResult::Cancelled));
+
+        // nothing unknown anywhere near, nothing to do
+        let empty = OccupancyGrid::new(config());
+        let mut explorer = Explorer::new(ExplorationParams::default());
+        assert!(matches!(explorer.update(&empty, &Costmap::from_grid(&empty, CostmapParams::default()), &pose, Duration::ZERO), ExplorationUpdate::Done));
+    }
+
+    #[test]
+    fn test_explorer_retries_refused_goals_but_not_reached_ones() {
+        let grid = corridor();
+        let costmap = Costmap::from_grid(&grid, CostmapParams::default());
+        let mut explorer = Explorer::new(ExplorationParams::default());
+        let pose = Transform2d::new(-0.5, 0.0, 0.0);
+        let ExplorationUpdate::Goal(first) = explorer.update(&grid, &costmap, &pose, Duration::ZERO) else {
+            panic!("no goal");
+        };
+        // e-stopped, the same goal comes back after a while
+        assert!(explorer.navigation_result(&NavigationResult::Aborted(AbortReason::Refused)));
+        assert!(matches!(explorer.update(&grid, &costmap, &pose, Duration::from_millis(100)), ExplorationUpdate::Continue));
+        let ExplorationUpdate::Goal(again) = explorer.update(&grid, &costmap, &pose, Duration::from_secs(2)) else {
+            panic!("no goal");
+        };
+        assert_eq!(again.x_meters, first.x_meters);
+        // nothing got mapped on the way, so it's still a frontier, but it's been reached already
+        assert!(explorer.navigation_result(&NavigationResult::Succeeded));
+        let ExplorationUpdate::Goal(next) = explorer.update(&grid, &costmap, &pose, Duration::from_millis(2100)) else {
+            panic!("no goal");
+        };
+        assert!(next.x_meters > 0.0);
+    }
+}
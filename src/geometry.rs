@@ -1,7 +1,7 @@
 use std::{f64::consts::PI, fmt::Debug, ops::{Add, AddAssign, Mul, Neg}};
 
 // use apriltag::Pose;
-use nalgebra::{Matrix3, Rotation3, Vector3};
+use nalgebra::{Matrix3, Rotation3, Vector2, Vector3};
 
 use assert_approx_eq::assert_approx_eq;
 use serde::{Deserialize, Serialize};
@@ -33,6 +33,23 @@ impl Transform2d {
     pub const ZERO: Self = Transform2d::new(0.0, 0.0, 0.0);
 }
 
+/// even-odd rule
+pub fn point_in_polygon(polygon: &[Vector2<f64>], point: &Vector2<f64>) -> bool {
+    let mut inside = false;
+    for i in 0..polygon.len() {
+        let (a, b) = (polygon[i], polygon[(i + 1) % polygon.len()]);
+        if (a[1] > point[1]) != (b[1] > point[1]) && point[0] < a[0] + (point[1] - a[1]) / (b[1] - a[1]) * (b[0] - a[0]) {
+            inside = !inside;
+        }
+    }
+    inside
+}
+
+/// wraps an angle into [-pi, pi)
+pub fn wrap_angle(theta_radians: f64) -> f64 {
+    (theta_radians + PI).rem_euclid(2.0 * PI) - PI
+}
+
 impl Add for Transform2d {
     type Output = Self;
 
@@ -136,6 +153,14 @@ fn test_inverse_transform2d() {
     assert_eq!(inverse_transform + transform, Transform2d::ZERO);
 }
 
+#[test]
+fn test_wrap_angle() {
+    assert_approx_eq!(wrap_angle(0.5), 0.5);
+    assert_approx_eq!(wrap_angle(2.0 * PI + 0.5), 0.5);
+    assert_approx_eq!(wrap_angle(-2.0 * PI - 0.5), -0.5);
+    assert_approx_eq!(wrap_angle(3.0 * PI / 2.0), -PI / 2.0);
+}
+
 #[test]
 fn test_twist_conversion() {
     let tf = Transform2d::new(1.0, 0.3, 1.0);
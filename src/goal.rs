@@ -0,0 +1,96 @@
+//! deciding when following a path is done. the followers themselves keep correcting forever,
+//! this is what notices the robot has been sitting on the goal long enough and ends it. noticing that it stopped getting
+//! anywhere is recovery.rs's job.
+
+use serde::Serialize;
+use tokio::time::Duration;
+
+use crate::{
+    geometry::{wrap_angle, Transform2d},
+    planner::PlannerError,
+};
+
+#[derive(Debug, Clone)]
+pub struct GoalTolerance {
+    pub position_meters: f64,
+    pub heading_radians: f64,
+    /// the robot has to stay within tolerance this long, so driving through the goal doesn't count
+    pub settle_time: Duration,
+}
+
+impl Default for GoalTolerance {
+    fn default() -> Self {
+        Self { position_meters: 0.08, heading_radians: 0.1, settle_time: Duration::from_millis(500) }
+    }
+}
+
+/// what happened to a goal, sent to the dashboard once it's over
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub enum NavigationResult {
+    Succeeded,
+    Aborted(AbortReason),
+    Cancelled,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub enum AbortReason {
+    PlanningFailed(PlannerError),
+    EStop,
+    Stalled,
+    /// the goal came in while the behaviour couldn't take one, it never got started
+    Refused,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum GoalStatus {
+    Active,
+    Succeeded,
+}
+
+#[derive(Debug, Clone)]
+pub struct GoalChecker {
+    pub goal: Transform2d,
+    pub tolerance: GoalTolerance,
+    within_since: Option<Duration>,
+}
+
+impl GoalChecker {
+    pub fn new(goal: Transform2d, tolerance: GoalTolerance) -> Self {
+        Self { goal, tolerance, within_since: None }
+    }
+
+    pub fn update(&mut self, pose: &Transform2d, now: Duration) -> GoalStatus {
+        let error = -pose.clone() + self.goal.clone();
+        if error.norm() < self.tolerance.position_meters && wrap_angle(error.theta_radians).abs() < self.tolerance.heading_radians {
+            let within_since = *self.within_since.get_or_insert(now);
+            if now.saturating_sub(within_since) >= self.tolerance.settle_time {
+                return GoalStatus::Succeeded;
+            }
+            return GoalStatus::Active;
+        }
+        self.within_since = None;
+        GoalStatus::Active
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::sim::ms;
+
+    #[test]
+    fn test_succeeds_after_settling() {
+        let goal = Transform2d::new(1.0, 0.0, 0.0);
+        let mut checker = GoalChecker::new(goal.clone(), GoalTolerance::default());
+        assert_eq!(checker.update(&Transform2d::new(0.5, 0.0, 0.0), ms(0)), GoalStatus::Active);
+        // close enough but pointing the wrong way
+        assert_eq!(checker.update(&Transform2d::new(0.99, 0.0, 0.5), ms(1000)), GoalStatus::Active);
+        assert_eq!(checker.update(&Transform2d::new(0.98, 0.01, 0.02), ms(1100)), GoalStatus::Active);
+        // overshooting restarts the settle time
+        assert_eq!(checker.update(&Transform2d::new(1.1, 0.0, 0.0), ms(1300)), GoalStatus::Active);
+        assert_eq!(checker.update(&Transform2d::new(1.01, 0.0, 0.0), ms(1400)), GoalStatus::Active);
+        assert_eq!(checker.update(&Transform2d::new(1.0, 0.0, 0.0), ms(1800)), GoalStatus::Active);
+        assert_eq!(checker.update(&Transform2d::new(1.0, 0.0, 0.0), ms(1900)), GoalStatus::Succeeded);
+    }
+
+}
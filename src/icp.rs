@@ -1,54 +1,281 @@
-use lstsq::lstsq; // TODO just solve the system myself :/
-use nalgebra::{Matrix2, Matrix2x3, Matrix3, Rotation2, Vector2, Vector3};
-use std::f64::consts::PI;
+use nalgebra::{Matrix2, Matrix2x3, Matrix3, Rotation2, RowVector3, SymmetricEigen, Vector2, Vector3};
 
-pub fn icp_least_squares(
+use crate::{geometry::{wrap_angle, Transform2d}, kdtree::KdTree2d};
+
+/// how many times the levenberg-marquardt damping gets increased before giving up on an iteration
+const MAX_DAMPING_ATTEMPTS: usize = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum IcpVariant {
+    /// minimizes the euclidean distance between each point and its closest target point
+    PointToPoint,
+    /// minimizes the distance between each point and the line through its closest target point, using normals estimated from the neighbouring target points
+    PointToLine,
+}
+
+#[derive(Debug, Clone)]
+pub struct IcpParams {
+    pub variant: IcpVariant,
+    pub max_iterations: usize,
+    /// correspondences that are further apart than this are ignored
+    pub max_correspondence_distance: f64,
+    /// fraction of the closest correspondences that are kept every iteration. 1.0 keeps all of them.
+    pub trim_ratio: f64,
+    /// iteration stops once an update moves the estimate by less than this many meters...
+    pub translation_epsilon: f64,
+    /// ...and less than this many radians
+    pub rotation_epsilon: f64,
+    /// how many scan indices on either side of a point are used to estimate its normal
+    pub normal_window: usize,
+    /// neighbours further than this from a point aren't used for its normal, so normals don't get smeared across gaps in the scan
+    pub normal_max_neighbor_distance: f64,
+    /// starting levenberg-marquardt damping. it shrinks after every good step and grows after every bad one.
+    pub initial_damping: f64,
+    /// the match is flagged as degenerate when the smallest eigenvalue of the normalized hessian is below this.
+    /// 1.0 means every direction is independently constrained and 0.0 means some direction isn't constrained at all.
+    pub degeneracy_threshold: f64,
+}
+
+impl Default for IcpParams {
+    fn default() -> Self {
+        Self {
+            variant: IcpVariant::PointToLine,
+            max_iterations: 50,
+            max_correspondence_distance: 0.5,
+            trim_ratio: 0.9,
+            translation_epsilon: 1e-5,
+            rotation_epsilon: 1e-5,
+            normal_window: 2,
+            normal_max_neighbor_distance: 0.1,
+            initial_damping: 1e-4,
+            degeneracy_threshold: 0.02,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct IcpResult {
+    /// [x, y, theta] that maps p onto q
+    pub x: Vector3<f64>,
+    /// root mean square error of the inlier correspondences at the final estimate, in meters
+    pub residual: f64,
+    /// false if the iteration limit was hit or there weren't enough correspondences
+    pub converged: bool,
+    /// true when the scans don't constrain every direction, like in a long corridor or in front of a single wall.
+    /// the estimate along the unconstrained direction is basically just the initial guess.
+    pub degenerate: bool,
+}
+
+impl IcpResult {
+    pub fn to_transform(&self) -> Transform2d {
+        Transform2d::new(self.x[0], self.x[1], self.x[2])
+    }
+}
+
+/// finds x = [x, y, theta] that maps the points in p onto the points in q, starting from initial_guess.
+pub fn icp(
     p: &[Vector2<f64>],
     q: &[Vector2<f64>],
     initial_guess: Vector3<f64>,
-    iterations: usize,
-) -> Vector3<f64> {
+    params: &IcpParams,
+) -> IcpResult {
+    let normals = match params.variant {
+        IcpVariant::PointToPoint => Vec::new(),
+        IcpVariant::PointToLine => estimate_normals(q, params.normal_window, params.normal_max_neighbor_distance),
+    };
+    let tree = KdTree2d::new(q);
     let mut x = initial_guess;
-    let mut p_copy = p.to_vec();
-    for _ in 0..iterations {
-        let correspondences = get_correspondence_indices(&p_copy, q);
-        let (h, g) = prepare_system(&x, p, q, &correspondences);
-        let epsilon = 1e-6;
-        let dx = lstsq(&h, &-g, epsilon).unwrap().solution;
-        if dx.norm() < 1e-9 {
+    x[2] = wrap_angle(x[2]);
+    let mut damping = params.initial_damping;
+    let mut iterations = 0;
+    let mut converged = false;
+    while iterations < params.max_iterations {
+        iterations += 1;
+        let pairs = find_inliers(&x, p, &tree, &normals, params);
+        if pairs.len() < 3 {
             break;
         }
-        x += dx;
-        x[2] = x[2].rem_euclid(2.0 * PI);
-        let rotation = Rotation2::new(x[2]);
-        let translation = Vector2::new(x[0], x[1]);
-        p_copy = p
-            .iter()
-            .map(|point| (rotation * point) + translation)
-            .collect();
+        let (h, g) = match params.variant {
+            IcpVariant::PointToPoint => prepare_system(&x, p, q, &pairs),
+            IcpVariant::PointToLine => prepare_system_point_to_line(&x, p, q, &normals, &pairs),
+        };
+        let cost = total_cost(&x, p, q, &normals, &pairs);
+        // levenberg-marquardt: keep increasing the damping until the step actually reduces the cost
+        let mut step = None;
+        for _ in 0..MAX_DAMPING_ATTEMPTS {
+            // marquardt scaling, with a floor so directions that aren't constrained at all still get damped
+            let floor = 1e-6 * h.diagonal().max();
+            let damped = h + Matrix3::from_diagonal(&h.diagonal().map(|d| d.max(floor))) * damping;
+            if let Some(dx) = solve_ldlt(&damped, &-g) {
+                let mut candidate = x + dx;
+                candidate[2] = wrap_angle(candidate[2]);
+                if total_cost(&candidate, p, q, &normals, &pairs) <= cost {
+                    damping = (damping / 10.0).max(1e-12);
+                    step = Some((candidate, dx));
+                    break;
+                }
+            }
+            damping *= 10.0;
+        }
+        let Some((candidate, dx)) = step else {
+            // nothing reduces the cost, so we're already at the minimum for these correspondences
+            converged = true;
+            break;
+        };
+        x = candidate;
+        if dx.xy().norm() < params.translation_epsilon && dx[2].abs() < params.rotation_epsilon {
+            converged = true;
+            break;
+        }
+    }
+
+    // evaluate the final estimate with fresh correspondences
+    let pairs = find_inliers(&x, p, &tree, &normals, params);
+    if pairs.len() < 3 {
+        return IcpResult { x, residual: f64::INFINITY, converged: false, degenerate: true };
     }
+    let (h, _) = match params.variant {
+        IcpVariant::PointToPoint => prepare_system(&x, p, q, &pairs),
+        IcpVariant::PointToLine => prepare_system_point_to_line(&x, p, q, &normals, &pairs),
+    };
+    IcpResult {
+        x,
+        residual: (total_cost(&x, p, q, &normals, &pairs) / pairs.len() as f64).sqrt(),
+        converged,
+        degenerate: is_degenerate(&h, params.degeneracy_threshold),
+    }
+}
 
-    x
+/// correspondences for the current estimate after distance gating and trimming
+fn find_inliers(
+    x: &Vector3<f64>,
+    p: &[Vector2<f64>],
+    tree: &KdTree2d,
+    normals: &[Option<Vector2<f64>>],
+    params: &IcpParams,
+) -> Vec<(usize, usize)> {
+    let p_transformed = transform_points(x, p);
+    let mut correspondences = get_correspondences(&p_transformed, tree, params.max_correspondence_distance);
+    if params.variant == IcpVariant::PointToLine {
+        correspondences.retain(|(_, j, _)| normals[*j].is_some());
+    }
+    trim_correspondences(&mut correspondences, params.trim_ratio);
+    correspondences.iter().map(|(i, j, _)| (*i, *j)).collect()
 }
 
-fn get_correspondence_indices(p: &[Vector2<f64>], q: &[Vector2<f64>]) -> Vec<usize> {
+/// sum of squared errors. normals are empty for point to point.
+fn total_cost(
+    x: &Vector3<f64>,
+    p: &[Vector2<f64>],
+    q: &[Vector2<f64>],
+    normals: &[Option<Vector2<f64>>],
+    correspondences: &[(usize, usize)],
+) -> f64 {
+    correspondences
+        .iter()
+        .map(|(i, j)| {
+            let e = error(x, &p[*i], &q[*j]);
+            match normals.get(*j) {
+                Some(Some(normal)) => normal.dot(&e).powi(2),
+                _ => e.norm_squared(),
+            }
+        })
+        .sum()
+}
+
+/// the hessian is (nearly) singular when some direction isn't constrained by any correspondence.
+/// translation and rotation have different units, so the hessian is normalized to unit diagonal before looking at its eigenvalues.
+fn is_degenerate(h: &Matrix3<f64>, threshold: f64) -> bool {
+    let floor = 1e-12 * h.diagonal().max().max(f64::MIN_POSITIVE);
+    let scale = Matrix3::from_diagonal(&h.diagonal().map(|d| 1.0 / d.max(floor).sqrt()));
+    let normalized = scale * h * scale;
+    normalized.symmetric_eigenvalues().min() < threshold
+}
+
+/// solves a * x = b for a symmetric positive definite 3x3 matrix with an LDL^T decomposition.
+/// returns None if a isn't positive definite.
+fn solve_ldlt(a: &Matrix3<f64>, b: &Vector3<f64>) -> Option<Vector3<f64>> {
+    let mut l = Matrix3::identity();
+    let mut d = Vector3::zeros();
+    let tolerance = 1e-12 * a.diagonal().abs().max().max(f64::MIN_POSITIVE);
+    for j in 0..3 {
+        d[j] = a[(j, j)] - (0..j).map(|k| l[(j, k)] * l[(j, k)] * d[k]).sum::<f64>();
+        if d[j] <= tolerance {
+            return None;
+        }
+        for i in (j + 1)..3 {
+            l[(i, j)] = (a[(i, j)] - (0..j).map(|k| l[(i, k)] * l[(j, k)] * d[k]).sum::<f64>()) / d[j];
+        }
+    }
+    // L z = b, then D y = z, then L^T x = y
+    let mut x = Vector3::zeros();
+    for i in 0..3 {
+        x[i] = b[i] - (0..i).map(|k| l[(i, k)] * x[k]).sum::<f64>();
+    }
+    for i in 0..3 {
+        x[i] /= d[i];
+    }
+    for i in (0..3).rev() {
+        x[i] -= ((i + 1)..3).map(|k| l[(k, i)] * x[k]).sum::<f64>();
+    }
+    Some(x)
+}
+
+fn transform_points(x: &Vector3<f64>, points: &[Vector2<f64>]) -> Vec<Vector2<f64>> {
+    let rotation = Rotation2::new(x[2]);
+    let translation = Vector2::new(x[0], x[1]);
+    points
+        .iter()
+        .map(|point| (rotation * point) + translation)
+        .collect()
+}
+
+/// (index in p, index in q, distance) for every point in p that has a point in q within max_distance
+fn get_correspondences(p: &[Vector2<f64>], q: &KdTree2d, max_distance: f64) -> Vec<(usize, usize, f64)> {
     let mut correspondences = Vec::with_capacity(p.len());
     // closest point
-    for i in 0..p.len() {
-        let mut min_dist = f64::MAX;
-        let mut min_index = 0;
-        for j in 0..q.len() {
-            let dist = (p[i] - q[j]).norm();
-            if dist < min_dist {
-                min_dist = dist;
-                min_index = j;
-            }
+    for (i, p_point) in p.iter().enumerate() {
+        if let Some((j, dist)) = q.nearest_within(p_point, max_distance) {
+            correspondences.push((i, j, dist));
         }
-        correspondences.push(min_index);
     }
     correspondences
 }
 
+/// only keeps the closest trim_ratio of the correspondences
+fn trim_correspondences(correspondences: &mut Vec<(usize, usize, f64)>, trim_ratio: f64) {
+    if trim_ratio >= 1.0 {
+        return;
+    }
+    let keep = ((correspondences.len() as f64 * trim_ratio).ceil() as usize).min(correspondences.len());
+    correspondences.sort_by(|a, b| a.2.total_cmp(&b.2));
+    correspondences.truncate(keep);
+}
+
+/// estimates the normal of every point from its neighbours in scan order. points without enough close neighbours get None.
+pub fn estimate_normals(points: &[Vector2<f64>], window: usize, max_neighbor_distance: f64) -> Vec<Option<Vector2<f64>>> {
+    let mut normals = Vec::with_capacity(points.len());
+    for (i, point) in points.iter().enumerate() {
+        let neighbors = points[i.saturating_sub(window)..(i + window + 1).min(points.len())]
+            .iter()
+            .filter(|neighbor| (*neighbor - point).norm() <= max_neighbor_distance)
+            .collect::<Vec<_>>();
+        if neighbors.len() < 3 {
+            normals.push(None);
+            continue;
+        }
+        let mean = neighbors.iter().fold(Vector2::zeros(), |acc, neighbor| acc + *neighbor) / neighbors.len() as f64;
+        let covariance = neighbors.iter().fold(Matrix2::zeros(), |acc, neighbor| {
+            let d = *neighbor - mean;
+            acc + d * d.transpose()
+        });
+        let eigen = SymmetricEigen::new(covariance);
+        let smallest = if eigen.eigenvalues[0] < eigen.eigenvalues[1] { 0 } else { 1 };
+        normals.push(Some(eigen.eigenvectors.column(smallest).into_owned()));
+    }
+    normals
+}
+
 fn error(x: &Vector3<f64>, p_point: &Vector2<f64>, q_point: &Vector2<f64>) -> Vector2<f64> {
     let rotation = Rotation2::new(x[2]);
     let translation = Vector2::new(x[0], x[1]);
@@ -60,15 +287,15 @@ fn prepare_system(
     x: &Vector3<f64>,
     p: &[Vector2<f64>],
     q: &[Vector2<f64>],
-    correspondences: &Vec<usize>,
+    correspondences: &[(usize, usize)],
 ) -> (Matrix3<f64>, Vector3<f64>) {
     let mut h = Matrix3::zeros();
     let mut g = Vector3::zeros();
-    for (i, j) in correspondences.iter().enumerate() {
-        let p_point = p[i];
+    for (i, j) in correspondences {
+        let p_point = p[*i];
         let q_point = q[*j];
         let e = error(x, &p_point, &q_point);
-        let jacobian = jacobian(&p_point);
+        let jacobian = jacobian(x[2], &p_point);
         let transposed_jacobian = jacobian.transpose();
         h += transposed_jacobian * jacobian;
         g += transposed_jacobian * e;
@@ -76,6 +303,26 @@ fn prepare_system(
     (h, g)
 }
 
+fn prepare_system_point_to_line(
+    x: &Vector3<f64>,
+    p: &[Vector2<f64>],
+    q: &[Vector2<f64>],
+    normals: &[Option<Vector2<f64>>],
+    correspondences: &[(usize, usize)],
+) -> (Matrix3<f64>, Vector3<f64>) {
+    let mut h = Matrix3::zeros();
+    let mut g = Vector3::zeros();
+    for (i, j) in correspondences {
+        let Some(normal) = normals[*j] else { continue };
+        let p_point = p[*i];
+        let e = normal.dot(&error(x, &p_point, &q[*j]));
+        let jacobian: RowVector3<f64> = normal.transpose() * jacobian(x[2], &p_point);
+        h += jacobian.transpose() * jacobian;
+        g += jacobian.transpose() * e;
+    }
+    (h, g)
+}
+
 /// Returns the derivative of a rotation matrix with a given angle.
 fn d_r(theta: f64) -> Matrix2<f64> {
     let mut d_r = Matrix2::zeros();
@@ -86,12 +333,12 @@ fn d_r(theta: f64) -> Matrix2<f64> {
     d_r
 }
 
-fn jacobian(p: &Vector2<f64>) -> Matrix2x3<f64> {
+/// derivative of R(theta) * p + t with respect to [x, y, theta]
+fn jacobian(theta: f64, p: &Vector2<f64>) -> Matrix2x3<f64> {
     let mut j = Matrix2x3::zeros();
     j[(0, 0)] = 1.0;
     j[(1, 1)] = 1.0;
-    let dr_0 = d_r(0.0); // Not sure why this doesn't use theta but the python code doesn't either
-    let dot = dr_0 * p;
+    let dot = d_r(theta) * p;
     j[(0, 2)] = dot[0];
     j[(1, 2)] = dot[1];
     j
@@ -100,9 +347,66 @@ fn jacobian(p: &Vector2<f64>) -> Matrix2x3<f64> {
 #[cfg(test)]
 mod test {
     use super::*;
+    use std::f64::consts::PI;
+
+    /// the same curve that test_generate_data checks against the python reference
+    fn synthetic_curve() -> Vec<Vector2<f64>> {
+        (0..30)
+            .map(|i| {
+                let x = i as f64;
+                Vector2::new(x, 0.2 * x * (0.5 * x).sin())
+            })
+            .collect()
+    }
+
+    /// q is the curve and p is the curve moved by the ground truth transform. returns (p, q, x that maps p onto q)
+    fn synthetic_scans() -> (Vec<Vector2<f64>>, Vec<Vector2<f64>>, Vector3<f64>) {
+        let q = synthetic_curve();
+        let rotation = Rotation2::new(PI / 4.0);
+        let translation = Vector2::new(-2.0, 5.0);
+        let p = q.iter().map(|point| (rotation * point) + translation).collect::<Vec<_>>();
+        let inverse_rotation = rotation.inverse();
+        let inverse_translation = inverse_rotation * -translation;
+        (p, q, Vector3::new(inverse_translation[0], inverse_translation[1], -PI / 4.0))
+    }
+
+    fn synthetic_params(variant: IcpVariant) -> IcpParams {
+        IcpParams {
+            variant,
+            max_iterations: 100,
+            max_correspondence_distance: 10.0,
+            trim_ratio: 1.0,
+            normal_window: 1,
+            normal_max_neighbor_distance: 2.0,
+            ..Default::default()
+        }
+    }
+
+    fn pose_error(x: &Vector3<f64>, expected: &Vector3<f64>) -> f64 {
+        (x.xy() - expected.xy()).norm() + wrap_angle(x[2] - expected[2]).abs()
+    }
+
+    /// counts how many initial guesses on a 5x5x5 grid around the ground truth converge back to it
+    fn convergence_basin(variant: IcpVariant, max_translation_offset: f64, max_rotation_offset: f64) -> usize {
+        let (p, q, expected) = synthetic_scans();
+        let params = synthetic_params(variant);
+        let steps = [-1.0, -0.5, 0.0, 0.5, 1.0];
+        let mut converged = 0;
+        for dx in steps.map(|step| step * max_translation_offset) {
+            for dy in steps.map(|step| step * max_translation_offset) {
+                for dtheta in steps.map(|step| step * max_rotation_offset) {
+                    let x = icp(&p, &q, expected + Vector3::new(dx, dy, dtheta), &params).x;
+                    if pose_error(&x, &expected) < 1e-3 {
+                        converged += 1;
+                    }
+                }
+            }
+        }
+        converged
+    }
 
     #[test]
-    fn test_lstsq() {
+    fn test_solve_ldlt() {
         let h = Matrix3::new(
             365.0,
             0.0,
@@ -116,7 +420,7 @@ mod test {
         );
 
         let g = Vector3::new(12299.9634f64, 21937.3152, 22231887.2);
-        let solution = lstsq(&h, &-g, 1e-6).unwrap().solution;
+        let solution = solve_ldlt(&h, &-g).unwrap();
         let expected_solution = Vector3::new(-33.9394150, -55.0489031, -0.0109298043);
         let residuals = expected_solution - solution;
         println!("Residuals: {}", residuals);
@@ -136,11 +440,16 @@ mod test {
     #[test]
     fn test_jacobian() {
         let p = Vector2::new(10.0, 1111.1);
-        let j = jacobian(&p);
+        let j = jacobian(0.0, &p);
         let expected_j = Matrix2x3::new(1.0, 0.0, -1111.1, 0.0, 1.0, 10.0);
         let residuals = expected_j - j;
         println!("Residuals: {}", residuals);
         assert!(residuals.norm() < 1e-9);
+        let j = jacobian(PI / 2.0, &p);
+        let expected_j = Matrix2x3::new(1.0, 0.0, -10.0, 0.0, 1.0, -1111.1);
+        let residuals = expected_j - j;
+        println!("Residuals: {}", residuals);
+        assert!(residuals.norm() < 1e-9);
     }
 
     #[test]
@@ -210,23 +519,21 @@ mod test {
             Vector2::new(28.0, 5.54740119),
             Vector2::new(29.0, 5.42239132),
         ];
-        let correspondences = vec![
-            0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20, 21, 22, 23,
-            24, 25, 26, 27, 28, 29,
-        ];
+        let correspondences = (0..30).map(|i| (i, i)).collect::<Vec<_>>();
         let (h, g) = prepare_system(&x, &p, &q, &correspondences);
+        // the rotation column of the jacobian is evaluated at theta, so its cross terms are the sums of dR(theta) * p
         let expected_h = Matrix3::new(
             30.0,
             0.0,
-            -462.89890748,
+            -155.99821960,
             0.0,
             30.0,
-            242.28399215,
-            -462.89890748,
-            242.28399215,
+            498.63963631,
+            -155.99821960,
+            498.63963631,
             11533.37791438,
         );
-        let expected_g = Vector3::new(0.00012049, 0.00010064, -0.00132618);
+        let expected_g = Vector3::new(0.00012121, 0.00009869, 0.00060452);
         let residuals = expected_h - h;
         println!("Residuals: {}", residuals);
         assert!(residuals.norm() < 1e-3);
@@ -373,10 +680,175 @@ mod test {
             .map(|point| (rotation * point) + translation)
             .collect::<Vec<_>>();
 
-        let x = icp_least_squares(&p, &q, Vector3::zeros(), 100);
+        let params = IcpParams {
+            variant: IcpVariant::PointToPoint,
+            max_iterations: 100,
+            max_correspondence_distance: f64::INFINITY,
+            trim_ratio: 1.0,
+            translation_epsilon: 1e-9,
+            rotation_epsilon: 1e-9,
+            ..Default::default()
+        };
+        let x = icp(&p, &q, Vector3::zeros(), &params).x;
         let expected_x = Vector3::new(0.1, 0.1, 0.4);
         let residuals = x - expected_x;
         println!("Residuals: {}", residuals);
         assert!(residuals.norm() < 1e-6);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_estimate_normals() {
+        let points = (0..10).map(|i| Vector2::new(i as f64 * 0.01, 1.0)).collect::<Vec<_>>();
+        let normals = estimate_normals(&points, 2, 0.05);
+        for normal in &normals {
+            let normal = normal.unwrap();
+            assert!((normal.dot(&Vector2::new(0.0, 1.0)).abs() - 1.0).abs() < 1e-9);
+        }
+        // isolated points don't get a normal
+        let normals = estimate_normals(&[Vector2::new(0.0, 0.0), Vector2::new(1.0, 0.0), Vector2::new(2.0, 0.0)], 2, 0.5);
+        assert!(normals.iter().all(|normal| normal.is_none()));
+    }
+
+    #[test]
+    fn test_point_to_point_accuracy() {
+        let (p, q, expected) = synthetic_scans();
+        let result = icp(&p, &q, expected + Vector3::new(0.5, -0.5, 0.1), &synthetic_params(IcpVariant::PointToPoint));
+        println!("Residuals: {}", result.x - expected);
+        assert!(pose_error(&result.x, &expected) < 1e-6);
+        assert!(result.converged);
+        assert!(!result.degenerate);
+        assert!(result.residual < 1e-6);
+    }
+
+    #[test]
+    fn test_point_to_line_accuracy() {
+        let (p, q, expected) = synthetic_scans();
+        let result = icp(&p, &q, expected + Vector3::new(0.5, -0.5, 0.1), &synthetic_params(IcpVariant::PointToLine));
+        println!("Residuals: {}", result.x - expected);
+        assert!(pose_error(&result.x, &expected) < 1e-6);
+        assert!(result.converged);
+        assert!(!result.degenerate);
+        assert!(result.residual < 1e-6);
+    }
+
+    #[test]
+    fn test_convergence_basin() {
+        // both variants should recover from any reasonable odometry error
+        assert_eq!(convergence_basin(IcpVariant::PointToPoint, 1.0, 0.3), 125);
+        assert_eq!(convergence_basin(IcpVariant::PointToLine, 1.0, 0.3), 125);
+        // with targets this sparse the normals are only a rough approximation of the curve, so point to line
+        // gets pulled into more local minima than point to point once the initial guess is far off
+        let point_to_point = convergence_basin(IcpVariant::PointToPoint, 4.0, 1.2);
+        let point_to_line = convergence_basin(IcpVariant::PointToLine, 4.0, 1.2);
+        println!("point to point converged {point_to_point}/125, point to line converged {point_to_line}/125");
+        assert!(point_to_point >= 110);
+        assert!(point_to_line >= 60);
+    }
+
+    #[test]
+    fn test_trimmed_outliers() {
+        let (mut p, q, expected) = synthetic_scans();
+        // a few points that aren't in the target scan at all
+        p.push(Vector2::new(40.0, 40.0));
+        p.push(Vector2::new(-30.0, 10.0));
+        p.push(Vector2::new(10.0, -25.0));
+        for variant in [IcpVariant::PointToPoint, IcpVariant::PointToLine] {
+            let params = IcpParams { trim_ratio: 0.85, ..synthetic_params(variant) };
+            let x = icp(&p, &q, expected + Vector3::new(0.3, 0.3, -0.05), &params).x;
+            println!("{:?} residuals: {}", variant, x - expected);
+            assert!(pose_error(&x, &expected) < 1e-6);
+        }
+    }
+
+    #[test]
+    fn test_accuracy_with_different_sampling() {
+        // a real lidar never hits the exact same spots twice, so sample the source halfway between the target points
+        let curve = |x: f64| Vector2::new(x, 0.2 * x * (0.5 * x).sin());
+        let q = (0..120).map(|i| curve(i as f64 * 0.25)).collect::<Vec<_>>();
+        let rotation = Rotation2::new(0.1);
+        let translation = Vector2::new(0.3, -0.2);
+        let p = (0..119).map(|i| (rotation * curve(i as f64 * 0.25 + 0.125)) + translation).collect::<Vec<_>>();
+        let inverse_rotation = rotation.inverse();
+        let inverse_translation = inverse_rotation * -translation;
+        let expected = Vector3::new(inverse_translation[0], inverse_translation[1], -0.1);
+        let params = |variant| IcpParams { normal_max_neighbor_distance: 0.6, ..synthetic_params(variant) };
+        let point_to_point = pose_error(&icp(&p, &q, Vector3::zeros(), &params(IcpVariant::PointToPoint)).x, &expected);
+        let point_to_line = pose_error(&icp(&p, &q, Vector3::zeros(), &params(IcpVariant::PointToLine)).x, &expected);
+        println!("point to point error {point_to_point}, point to line error {point_to_line}");
+        assert!(point_to_line < 0.01);
+        assert!(point_to_line < point_to_point);
+    }
+
+    /// run with `cargo test --release bench_icp -- --ignored --nocapture`
+    #[test]
+    #[ignore]
+    fn bench_icp_real_scan_size() {
+        use crate::{geometry::Transform2d, sim::{room_walls, simulate_scan}};
+        use std::time::Instant;
+
+        // roughly what the lidar gives us in one revolution
+        let walls = room_walls();
+        let q = simulate_scan(&walls, &Transform2d::new(0.0, 0.5, 0.0), 1500, 12.0);
+        let p = simulate_scan(&walls, &Transform2d::new(0.1, 0.55, 0.05), 1500, 12.0);
+        const RUNS: u32 = 20;
+        for variant in [IcpVariant::PointToPoint, IcpVariant::PointToLine] {
+            let params = IcpParams { variant, ..Default::default() };
+            let start = Instant::now();
+            let mut result = icp(&p, &q, Vector3::zeros(), &params);
+            for _ in 1..RUNS {
+                result = icp(&p, &q, Vector3::zeros(), &params);
+            }
+            println!(
+                "{:?}: {} x {} points took {:.2}ms per match, result {:?}",
+                variant,
+                p.len(),
+                q.len(),
+                start.elapsed().as_secs_f64() * 1000.0 / RUNS as f64,
+                result.x.as_slice()
+            );
+        }
+    }
+
+    #[test]
+    fn test_solve_ldlt_rejects_singular() {
+        let h = Matrix3::new(1.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 0.0);
+        assert!(solve_ldlt(&h, &Vector3::new(1.0, 1.0, 1.0)).is_none());
+        let h = Matrix3::new(4.0, 2.0, 0.6, 2.0, 5.0, 1.0, 0.6, 1.0, 3.0);
+        let b = Vector3::new(1.0, -2.0, 0.5);
+        let x = solve_ldlt(&h, &b).unwrap();
+        assert!((h * x - b).norm() < 1e-12);
+    }
+
+    #[test]
+    fn test_degenerate_single_wall() {
+        // a single straight wall can't tell us how far we slid along it
+        let q = (0..200).map(|i| Vector2::new(i as f64 * 0.01, 1.0)).collect::<Vec<_>>();
+        let p = q.iter().map(|point| point + Vector2::new(0.05, -0.03)).collect::<Vec<_>>();
+        let result = icp(&p, &q, Vector3::zeros(), &IcpParams::default());
+        assert!(result.degenerate);
+        // the direction that is constrained is still right
+        assert!((result.x[1] - 0.03).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_degenerate_corridor() {
+        use crate::{geometry::Transform2d, sim::simulate_scan};
+        let walls = vec![
+            (Vector2::new(-50.0, -1.0), Vector2::new(50.0, -1.0)),
+            (Vector2::new(-50.0, 1.0), Vector2::new(50.0, 1.0)),
+        ];
+        let q = simulate_scan(&walls, &Transform2d::ZERO, 1000, 6.0);
+        let p = simulate_scan(&walls, &Transform2d::new(0.2, 0.1, 0.05), 1000, 6.0);
+        let result = icp(&p, &q, Vector3::zeros(), &IcpParams::default());
+        assert!(result.degenerate);
+
+        // a room constrains everything
+        let walls = crate::sim::room_walls();
+        let q = simulate_scan(&walls, &Transform2d::ZERO, 1000, 12.0);
+        let p = simulate_scan(&walls, &Transform2d::new(0.2, 0.1, 0.05), 1000, 12.0);
+        let result = icp(&p, &q, Vector3::zeros(), &IcpParams::default());
+        assert!(!result.degenerate);
+        assert!(result.converged);
+        assert!((result.x - Vector3::new(0.2, 0.1, 0.05)).norm() < 1e-3);
+    }
+}
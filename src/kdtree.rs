@@ -0,0 +1,109 @@
+use nalgebra::Vector2;
+
+/// 2d kd-tree for nearest neighbour lookups, used for icp correspondences.
+/// it is built once per target scan and never modified afterwards.
+pub struct KdTree2d {
+    points: Vec<Vector2<f64>>,
+    /// indices into points, arranged so that every subtree is a contiguous range with its splitting point in the middle
+    indices: Vec<usize>,
+}
+
+impl KdTree2d {
+    pub fn new(points: &[Vector2<f64>]) -> Self {
+        let mut indices = (0..points.len()).collect::<Vec<_>>();
+        build(points, &mut indices, 0);
+        Self { points: points.to_vec(), indices }
+    }
+
+    /// (index, distance) of the closest point that is at most max_distance away
+    pub fn nearest_within(&self, target: &Vector2<f64>, max_distance: f64) -> Option<(usize, f64)> {
+        let mut best = (usize::MAX, max_distance * max_distance);
+        self.search(target, 0, self.indices.len(), 0, &mut best);
+        if best.0 == usize::MAX {
+            None
+        } else {
+            Some((best.0, best.1.sqrt()))
+        }
+    }
+
+    /// best is (index, squared distance)
+    fn search(&self, target: &Vector2<f64>, lo: usize, hi: usize, depth: usize, best: &mut (usize, f64)) {
+        if lo >= hi {
+            return;
+        }
+        let mid = lo + (hi - lo) / 2;
+        let index = self.indices[mid];
+        let point = &self.points[index];
+        let dist_squared = (point - target).norm_squared();
+        if dist_squared < best.1 || (best.0 == usize::MAX && dist_squared <= best.1) {
+            *best = (index, dist_squared);
+        }
+        let axis = depth % 2;
+        let diff = target[axis] - point[axis];
+        let (near, far) = if diff < 0.0 { ((lo, mid), (mid + 1, hi)) } else { ((mid + 1, hi), (lo, mid)) };
+        self.search(target, near.0, near.1, depth + 1, best);
+        // only check the other side if the splitting line is closer than the best point so far
+        if diff * diff <= best.1 {
+            self.search(target, far.0, far.1, depth + 1, best);
+        }
+    }
+}
+
+fn build(points: &[Vector2<f64>], indices: &mut [usize], depth: usize) {
+    if indices.len() <= 1 {
+        return;
+    }
+    let axis = depth % 2;
+    let mid = indices.len() / 2;
+    indices.select_nth_unstable_by(mid, |a, b| points[*a][axis].total_cmp(&points[*b][axis]));
+    let (left, right) = indices.split_at_mut(mid);
+    build(points, left, depth + 1);
+    build(points, &mut right[1..], depth + 1);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn brute_force_nearest(points: &[Vector2<f64>], target: &Vector2<f64>) -> (usize, f64) {
+        let mut best = (0, f64::INFINITY);
+        for (i, point) in points.iter().enumerate() {
+            let dist = (point - target).norm();
+            if dist < best.1 {
+                best = (i, dist);
+            }
+        }
+        best
+    }
+
+    /// deterministic scattered points so the test doesn't need an rng
+    fn scattered_points(n: usize) -> Vec<Vector2<f64>> {
+        (0..n)
+            .map(|i| {
+                let i = i as f64;
+                Vector2::new((i * 12.9898).sin() * 43.758 % 5.0, (i * 78.233).cos() * 12.345 % 5.0)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_nearest_matches_brute_force() {
+        let points = scattered_points(500);
+        let tree = KdTree2d::new(&points);
+        for target in scattered_points(200).iter().map(|point| point * 1.3 + Vector2::new(0.1, -0.2)) {
+            let (_, expected_dist) = brute_force_nearest(&points, &target);
+            let (index, dist) = tree.nearest_within(&target, f64::INFINITY).unwrap();
+            assert!((dist - expected_dist).abs() < 1e-12);
+            assert!(((points[index] - target).norm() - expected_dist).abs() < 1e-12);
+        }
+    }
+
+    #[test]
+    fn test_nearest_within() {
+        let points = vec![Vector2::new(0.0, 0.0), Vector2::new(1.0, 0.0), Vector2::new(0.0, 3.0)];
+        let tree = KdTree2d::new(&points);
+        assert_eq!(tree.nearest_within(&Vector2::new(0.9, 0.1), 0.5).map(|x| x.0), Some(1));
+        assert_eq!(tree.nearest_within(&Vector2::new(0.0, 1.5), 0.5), None);
+        assert_eq!(KdTree2d::new(&[]).nearest_within(&Vector2::new(0.0, 0.0), f64::INFINITY), None);
+    }
+}
@@ -43,7 +43,7 @@ use tokio::io::{AsyncReadExt, AsyncWriteExt};
 
 use crate::geometry::Transform2d;
 
-const ROBOT_TO_LIDAR: Transform2d = Transform2d::new(-0.085, -0.01, PI / 2.0);
+pub const ROBOT_TO_LIDAR: Transform2d = Transform2d::new(-0.085, -0.01, PI / 2.0);
 
 pub async fn start_lidar_thread(io: SocketIo) -> (Receiver<LidarScan>, Arc<RwLock<LidarStatus>>){
     let (tx, rx) = mpsc::channel::<LidarScan>();
@@ -0,0 +1,258 @@
+//! dynamic window local planner. the global path is only as good as the costmap was when it got planned, this dodges whatever is around right now.
+//! it rolls out a bunch of constant speed arcs the robot can reach before the next update, throws out the ones that hit an obstacle
+//! (lethal costmap cells near the robot), and picks the one that best matches what the path follower wanted while staying near the global path.
+
+use nalgebra::Vector2;
+
+use crate::{
+    drivetrain::XAVIERBOT_FOOTPRINT_RADIUS_METERS,
+    geometry::{Transform2d, Twist2d},
+};
+
+#[derive(Debug, Clone)]
+pub struct LocalPlannerParams {
+    pub robot_radius_meters: f64,
+    /// arcs that get closer than this to an obstacle count as collisions
+    pub safety_margin_meters: f64,
+    pub max_speed_meters_per_second: f64,
+    pub max_angular_speed_radians_per_second: f64,
+    pub max_accel_meters_per_second_squared: f64,
+    pub max_angular_accel_radians_per_second_squared: f64,
+    /// how far the speeds can change from the current ones, ideally about how long until the next scan
+    pub window_seconds: f64,
+    /// how far ahead in time the arcs get rolled out
+    pub horizon_seconds: f64,
+    /// arcs are rolled out at least this far, even if they're too slow to get that far within the horizon
+    pub min_rollout_distance_meters: f64,
+    /// how finely the arcs get checked for collisions
+    pub rollout_step_meters: f64,
+    pub linear_samples: usize,
+    pub angular_samples: usize,
+    /// full speed is only allowed when the closest obstacle in front is at least this far away, it scales down to min speed at the safety margin
+    pub slowdown_distance_meters: f64,
+    /// so the robot can still creep away from or past things that are close, the rollouts make sure it doesn't creep into them
+    pub min_speed_meters_per_second: f64,
+    pub path_distance_weight: f64,
+    pub progress_weight: f64,
+    pub follow_weight: f64,
+    pub clearance_weight: f64,
+}
+
+impl Default for LocalPlannerParams {
+    fn default() -> Self {
+        Self {
+            robot_radius_meters: XAVIERBOT_FOOTPRINT_RADIUS_METERS,
+            safety_margin_meters: 0.05,
+            max_speed_meters_per_second: 0.3,
+            max_angular_speed_radians_per_second: 1.0,
+            max_accel_meters_per_second_squared: 0.5,
+            max_angular_accel_radians_per_second_squared: 3.0,
+            window_seconds: 0.2,
+            horizon_seconds: 1.5,
+            min_rollout_distance_meters: 0.5,
+            rollout_step_meters: 0.03,
+            linear_samples: 7,
+            angular_samples: 15,
+            slowdown_distance_meters: 0.5,
+            min_speed_meters_per_second: 0.05,
+            path_distance_weight: 1.0,
+            progress_weight: 1.0,
+            follow_weight: 0.1,
+            clearance_weight: 0.02,
+        }
+    }
+}
+
+pub struct LocalPlanner {
+    pub params: LocalPlannerParams,
+}
+
+/// distance from point to the polyline, and how far along the polyline the closest point is
+fn closest_on_polyline(polyline: &[Vector2<f64>], point: &Vector2<f64>) -> (f64, f64) {
+    if polyline.len() == 1 {
+        return ((point - polyline[0]).norm(), 0.0);
+    }
+    let mut best = (f64::INFINITY, 0.0);
+    let mut distance_along = 0.0;
+    for segment in polyline.windows(2) {
+        let direction = segment[1] - segment[0];
+        let length = direction.norm();
+        let t = if length < 1e-9 { 0.0 } else { ((point - segment[0]).dot(&direction) / (length * length)).clamp(0.0, 1.0) };
+        let distance = (point - (segment[0] + direction * t)).norm();
+        if distance < best.0 {
+            best = (distance, distance_along + t * length);
+        }
+        distance_along += length;
+    }
+    best
+}
+
+/// the first part of a world frame path that comes within max_distance of the robot, in the robot frame.
+/// waypoints can be far apart, so a waypoint is kept if either of the segments it's part of comes close enough.
+pub fn path_in_robot_frame<'a>(world_to_robot: &Transform2d, waypoints: impl IntoIterator<Item = &'a Transform2d>, max_distance: f64) -> Vec<Vector2<f64>> {
+    let robot_to_world = -world_to_robot.clone();
+    let points = waypoints
+        .into_iter()
+        .map(|waypoint| {
+            let robot_to_waypoint = robot_to_world.clone() + waypoint.clone();
+            Vector2::new(robot_to_waypoint.x_meters, robot_to_waypoint.y_meters)
+        })
+        .collect::<Vec<_>>();
+    let near = |i: usize| {
+        let segment_near = |a: usize, b: usize| closest_on_polyline(&points[a..=b], &Vector2::zeros()).0 <= max_distance;
+        (i > 0 && segment_near(i - 1, i)) || (i + 1 < points.len() && segment_near(i, i + 1)) || (points.len() == 1 && points[0].norm() <= max_distance)
+    };
+    (0..points.len()).skip_while(|i| !near(*i)).take_while(|i| near(*i)).map(|i| points[i]).collect()
+}
+
+impl LocalPlanner {
+    pub fn new(params: LocalPlannerParams) -> Self {
+        Self { params }
+    }
+
+    /// only obstacles the rollouts can actually reach matter
+    pub fn reach_meters(&self) -> f64 {
+        let p = &self.params;
+        (p.max_speed_meters_per_second * p.horizon_seconds).max(p.min_rollout_distance_meters) + p.robot_radius_meters + p.safety_margin_meters
+    }
+
+    /// how fast the robot may drive forward with obstacles this close in front of it
+    pub fn speed_cap(&self, obstacles: &[Vector2<f64>]) -> f64 {
+        let p = &self.params;
+        let nearest = obstacles
+            .iter()
+            .filter(|point| point[0] > 0.0)
+            .map(|point| point.norm() - p.robot_radius_meters)
+            // anything inside the footprint is the robot seeing itself
+            .filter(|clearance| *clearance > 0.0)
+            .fold(f64::INFINITY, f64::min);
+        let fraction = ((nearest - p.safety_margin_meters) / (p.slowdown_distance_meters - p.safety_margin_meters)).min(1.0);
+        p.min_speed_meters_per_second.max(p.max_speed_meters_per_second * fraction.max(0.0))
+    }
+
+    /// picks speeds close to desired that don't run into any of the obstacle points (robot frame) and stay near the path (also robot frame).
+    /// turning in place never collides, so if everything else is blocked the robot at least stops.
+    pub fn plan(&self, points: &[Vector2<f64>], path: &[Vector2<f64>], desired: &Twist2d, current: &Twist2d) -> Twist2d {
+        let p = &self.params;
+        let reach = self.reach_meters();
+        let obstacles = points.iter().filter(|point| point.norm() > p.robot_radius_meters && point.norm() < reach).collect::<Vec<_>>();
+        let clearance_at = |x: f64, y: f64| obstacles.iter().map(|point| (point[0] - x).hypot(point[1] - y)).fold(f64::INFINITY, f64::min) - p.robot_radius_meters;
+        let start_clearance = clearance_at(0.0, 0.0);
+        let start_progress = if path.is_empty() { 0.0 } else { closest_on_polyline(path, &Vector2::zeros()).1 };
+
+        let max_speed = self.speed_cap(points);
+        let max_linear_change = p.max_accel_meters_per_second_squared * p.window_seconds;
+        let max_angular_change = p.max_angular_accel_radians_per_second_squared * p.window_seconds;
+        let max_linear = (current.dx + max_linear_change).min(max_speed);
+        let min_linear = (current.dx - max_linear_change).max(0.0).min(max_linear);
+        let max_angular = (current.dtheta + max_angular_change).min(p.max_angular_speed_radians_per_second);
+        let min_angular = (current.dtheta - max_angular_change).max(-p.max_angular_speed_radians_per_second).min(max_angular);
+        let steps = |min: f64, max: f64, n: usize| (0..n).map(move |i| if n > 1 { min + (max - min) * i as f64 / (n - 1) as f64 } else { min });
+
+        let mut candidates = steps(min_linear, max_linear, p.linear_samples)
+            .flat_map(|linear| steps(min_angular, max_angular, p.angular_samples).map(move |angular| Twist2d::new(linear, 0.0, angular)))
+            .collect::<Vec<_>>();
+        // whatever the follower wanted, as close as the window allows, so it gets tracked exactly when nothing is in the way
+        candidates.push(Twist2d::new(desired.dx.clamp(min_linear, max_linear), 0.0, desired.dtheta.clamp(min_angular, max_angular)));
+
+        let mut best = (f64::INFINITY, Twist2d::new(0.0, 0.0, 0.0));
+        for candidate in candidates {
+            let mut pose = Transform2d::ZERO;
+            let mut min_clearance = start_clearance;
+            let mut collides = false;
+            if candidate.dx > 0.0 {
+                // slow arcs still get rolled out for a while, otherwise creeping straight into an obstacle always looks fine
+                let distance = (candidate.dx * p.horizon_seconds).max(p.min_rollout_distance_meters);
+                let steps = (distance / p.rollout_step_meters).ceil();
+                let step = Transform2d::from(candidate.clone() * (distance / steps / candidate.dx));
+                for _ in 0..steps as usize {
+                    pose += step.clone();
+                    let clearance = clearance_at(pose.x_meters, pose.y_meters);
+                    min_clearance = min_clearance.min(clearance);
+                    // driving away from something that's already too close is fine
+                    if clearance < p.safety_margin_meters && clearance < start_clearance {
+                        collides = true;
+                        break;
+                    }
+                }
+            }
+            if collides {
+                continue;
+            }
+            let mut cost = p.follow_weight
+                * ((candidate.dx - desired.dx).abs() / p.max_speed_meters_per_second
+                    + (candidate.dtheta - desired.dtheta).abs() / p.max_angular_speed_radians_per_second)
+                + p.clearance_weight / min_clearance.max(0.01);
+            if !path.is_empty() {
+                let (distance, progress) = closest_on_polyline(path, &Vector2::new(pose.x_meters, pose.y_meters));
+                cost += p.path_distance_weight * distance - p.progress_weight * (progress - start_progress) / (p.max_speed_meters_per_second * p.horizon_seconds);
+            }
+            if cost < best.0 {
+                best = (cost, candidate);
+            }
+        }
+        best.1
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        paths::{Path, PurePursuitConfig},
+        sim::{room_walls, simulate_scan},
+    };
+
+    fn min_clearance(walls: &[crate::sim::Wall], pose: &Transform2d) -> f64 {
+        simulate_scan(walls, pose, 720, 12.0).iter().map(|point| point.norm()).fold(f64::INFINITY, f64::min)
+    }
+
+    #[test]
+    fn test_avoids_obstacle_on_path() {
+        // the path scrapes along the pillar, like it would if the pillar wasn't in the map
+        let walls = room_walls();
+        let path = Path { waypoints: vec![Transform2d::new(-2.5, -0.3, 0.0), Transform2d::new(1.0, -0.3, 0.0)] };
+        let pure_pursuit = PurePursuitConfig::default();
+        let planner = LocalPlanner::new(LocalPlannerParams { window_seconds: 0.05, ..Default::default() });
+        let mut pose = path.waypoints[0].clone();
+        let mut speeds = Twist2d::ZERO;
+        let mut closest: f64 = f64::INFINITY;
+        for _ in 0..800 {
+            let scan = simulate_scan(&walls, &pose, 360, 12.0);
+            let (desired, _) = path.pure_pursuit(&pose, speeds.dx, &pure_pursuit);
+            speeds = planner.plan(&scan, &path_in_robot_frame(&pose, &path.waypoints, 2.0), &desired, &speeds);
+            pose += Transform2d::from(speeds.clone() * 0.05);
+            closest = closest.min(min_clearance(&walls, &pose));
+        }
+        let error = -pose + path.waypoints[1].clone();
+        assert!(error.norm() < 0.1, "{:?}", error);
+        assert!(closest > planner.params.robot_radius_meters, "{}", closest);
+    }
+
+    #[test]
+    fn test_tracks_desired_when_clear() {
+        let planner = LocalPlanner::new(LocalPlannerParams::default());
+        let desired = Twist2d::new(0.3, 0.0, 0.1);
+        // the arc the follower is trying to drive
+        let path = (0..20).map(|i| Transform2d::from(desired.clone() * (0.1 * i as f64))).collect::<Vec<_>>();
+        let path = path_in_robot_frame(&Transform2d::ZERO, &path, 2.0);
+        let speeds = planner.plan(&[], &path, &desired, &desired);
+        assert!((speeds.dx - desired.dx).abs() < 1e-9 && (speeds.dtheta - desired.dtheta).abs() < 1e-9, "{:?}", speeds);
+    }
+
+    #[test]
+    fn test_slows_down_and_stops_for_wall() {
+        let planner = LocalPlanner::new(LocalPlannerParams::default());
+        let wall = |x: f64| (-20..=20).map(|i| Vector2::new(x, i as f64 * 0.02)).collect::<Vec<_>>();
+        assert_eq!(planner.speed_cap(&wall(2.0)), planner.params.max_speed_meters_per_second);
+        let close = planner.speed_cap(&wall(0.4));
+        assert!(close > 0.0 && close < planner.params.max_speed_meters_per_second);
+        // obstacles behind don't slow it down
+        assert_eq!(planner.speed_cap(&wall(-0.3)), planner.params.max_speed_meters_per_second);
+        // a wall right in front, wider than anything it could steer around: it stops
+        let wide_wall = (-100..=100).map(|i| Vector2::new(0.22, i as f64 * 0.02)).collect::<Vec<_>>();
+        let path = vec![Vector2::new(0.0, 0.0), Vector2::new(2.0, 0.0)];
+        let speeds = planner.plan(&wide_wall, &path, &Twist2d::new(0.3, 0.0, 0.0), &Twist2d::new(0.1, 0.0, 0.0));
+        assert_eq!(speeds.dx, 0.0);
+    }
+}
@@ -7,67 +7,354 @@ mod drivetrain;
 mod lidar;
 mod ws;
 mod icp;
+mod amcl;
+mod correlative;
+mod kdtree;
+mod occupancy_grid;
 mod paths;
+mod planner;
+mod costmap;
+mod coverage;
+mod exploration;
+mod trajectory;
+mod controllers;
+mod local_planner;
+mod safety;
+mod goal;
+mod mission;
+mod behaviour;
+mod recovery;
+mod spline;
+mod submap;
+mod zones;
+#[cfg(test)]
+mod sim;
 
+use std::{fs, io, path::{Path as FilePath, PathBuf}, time::{SystemTime, UNIX_EPOCH}};
+
+use amcl::{Amcl, AmclParams};
+use behaviour::{Behaviour, BehaviourInputs, BehaviourMessage, BehaviourParams, DriveRequest};
+use controllers::LtvUnicycleController;
+use costmap::{Costmap, CostmapParams};
+use coverage::{CoverageParams, CoverageTracker};
 use drivetrain::XAVIERBOT_WHEEL_SEPARATION_METERS;
-use geometry::Transform2d;
+use exploration::{ExplorationParams, ExplorationStatus, ExplorationUpdate, Explorer, Frontier};
+use geometry::{Transform2d, Twist2d};
+use goal::NavigationResult;
+use nalgebra::{Matrix3, Vector2, Vector3};
+use occupancy_grid::{OccupancyGrid, OccupancyGridConfig, OccupancyGridMapper};
 use odometry::{DifferentialDriveOdometry, WheelOdometry};
-use paths::Path;
-use pose_graph::{LidarPoseGraph, PoseGraphUpdateResult};
+use pose_estimator::PoseEstimator;
+use pose_graph::{LidarPoseGraph, PoseGraphBackend, PoseGraphUpdateResult};
+use safety::{SafetyMonitor, SafetyParams};
 use tokio::time::{sleep, Instant, Duration};
-use ws::{DriveCommand, WsPoseGraphNode};
+use ws::{WsPoseGraphNode, WsSubmap};
+use zones::Zones;
 const DURATION_PER_FRAME: Duration = Duration::from_millis(10);
+/// where maps get saved to if no directory is passed on the command line
+const DEFAULT_MAP_DIRECTORY: &str = "map";
+const MAP_BROADCAST_PERIOD: Duration = Duration::from_secs(2);
+const GRAPH_FILE_OPTIMIZE_ITERATIONS: usize = 100;
+/// how much the pose estimator trusts the newest pose graph node while mapping (x meters, y meters, theta radians)
+const SCAN_MATCHING_STD_DEVS: (f64, f64, f64) = (0.02, 0.02, 0.01);
+
+/// writes the pose graph (plus a g2o export of it), the zones, and map_server and png exports of the occupancy grid into map_directory
+fn save_map(map_directory: &FilePath, pose_graph: &LidarPoseGraph, grid: &OccupancyGrid, zones: &Zones) -> io::Result<()> {
+    fs::create_dir_all(map_directory)?;
+    pose_graph.save(map_directory.join("pose_graph.json"))?;
+    zones.save(map_directory.join("zones.json"))?;
+    // for looking at the graph in g2o_viewer and friends
+    pose_graph.backend.save_graph_file(map_directory.join("pose_graph.g2o"))?;
+    grid.save_map_server(map_directory.join("map.yaml"))?;
+    grid.save_png(map_directory.join("map.png"))
+}
+
+/// writes a robot frame scan and the pose it was taken at into map_directory/scans
+fn save_scan(map_directory: &FilePath, name: &str, pose: &Transform2d, scan: &[Vector2<f64>]) -> io::Result<()> {
+    let directory = map_directory.join("scans");
+    fs::create_dir_all(&directory)?;
+    let points = scan.iter().map(|point| [point[0], point[1]]).collect::<Vec<_>>();
+    fs::write(directory.join(format!("{}.json", name)), serde_json::to_vec(&serde_json::json!({ "pose": pose, "points": points }))?)
+}
+
+/// optimizes a g2o or TORO file and writes the result next to it, for benchmarking the optimizer on public datasets
+fn optimize_graph_file(path: &FilePath) -> io::Result<()> {
+    let mut graph = PoseGraphBackend::load_graph_file(path)?;
+    let initial_chi2 = graph.chi2();
+    let start = Instant::now();
+    graph.optimize(GRAPH_FILE_OPTIMIZE_ITERATIONS);
+    println!("{} nodes, chi2 {} -> {} in {:?}", graph.num_nodes(), initial_chi2, graph.chi2(), start.elapsed());
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let extension = path.extension().unwrap_or_default().to_string_lossy();
+    graph.save_graph_file(path.with_file_name(format!("{}-optimized.{}", stem, extension)))
+}
 
 #[tokio::main]
 async fn main() {
+    // usage: xavier-robot --optimize <graph file>. doesn't start the robot at all
+    if let [_, flag, graph_file] = &std::env::args().collect::<Vec<_>>()[..] {
+        if flag == "--optimize" {
+            if let Err(e) = optimize_graph_file(FilePath::new(graph_file)) {
+                eprintln!("couldn't optimize {}: {}", graph_file, e);
+            }
+            return;
+        }
+    }
     let program_start = Instant::now();
 
     let (state, io) = ws::start_web_server_thread().await;
     let (scan_rx, lidar_health) = lidar::start_lidar_thread(io.clone()).await;
     let (commanded_speeds, heading, wheel_positions, drivetrain_health) = drivetrain::start_drivetrain_thread(io.clone()).await;
 
-    let mut odom = DifferentialDriveOdometry::new(XAVIERBOT_WHEEL_SEPARATION_METERS, heading.read().unwrap().clone(), wheel_positions.read().unwrap().clone());
-    let mut pose_graph = LidarPoseGraph::new();
+    let odom = DifferentialDriveOdometry::new(XAVIERBOT_WHEEL_SEPARATION_METERS, heading.read().unwrap().clone(), wheel_positions.read().unwrap().clone());
+    let mut pose_estimator = PoseEstimator::new(Transform2d::ZERO, odom, program_start.elapsed());
+    // usage: xavier-robot [map directory]. if the directory already has a map in it we localize in that map instead of building a new one
+    let map_directory = std::env::args().nth(1).map(PathBuf::from).unwrap_or(PathBuf::from(DEFAULT_MAP_DIRECTORY));
+    let mut occupancy_grid = OccupancyGridMapper::new(OccupancyGridConfig::default());
+    let (mut pose_graph, mapping) = match LidarPoseGraph::load(map_directory.join("pose_graph.json")) {
+        Ok(pose_graph) => {
+            println!("loaded map with {} nodes from {}", pose_graph.node_scans.len(), map_directory.display());
+            occupancy_grid.update(&pose_graph.backend.poses(), &pose_graph.node_scans);
+            (pose_graph, false)
+        }
+        Err(e) => {
+            println!("not loading a map from {} ({}), starting a new one", map_directory.display(), e);
+            (LidarPoseGraph::new(), true)
+        }
+    };
+    // the map's origin is where mapping started, which is usually where the robot gets turned on again. if it isn't, amcl notices and relocalizes.
+    let mut amcl = (!mapping).then(|| {
+        let mut amcl = Amcl::new(&occupancy_grid.grid, AmclParams::default(), SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_nanos() as u64);
+        amcl.set_pose(&Transform2d::ZERO, &Matrix3::from_diagonal(&Vector3::new(0.25, 0.25, 0.07)));
+        amcl
+    });
+
+    let mut behaviour = Behaviour::new(BehaviourParams {
+        ltv_unicycle: LtvUnicycleController { dt_seconds: DURATION_PER_FRAME.as_secs_f64(), ..Default::default() },
+        ..Default::default()
+    });
+    // the static layer follows the occupancy grid, which doesn't change in a loaded map
+    let mut costmap = Costmap::from_grid(&occupancy_grid.grid, CostmapParams::default());
+    let mut zones = match Zones::load(map_directory.join("zones.json")) {
+        Ok(zones) => zones,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Zones::default(),
+        Err(e) => {
+            eprintln!("ignoring the zones in {}: {}", map_directory.display(), e);
+            Zones::default()
+        }
+    };
+    costmap.set_keep_out_zones(&zones.keep_out_polygons());
+    // how much of the area being surveyed has been seen, if there is one
+    let mut coverage: Option<CoverageTracker> = None;
+    // picks frontiers to drive to while exploring
+    let mut explorer: Option<Explorer> = None;
+    // robot frame
+    let mut latest_scan: Vec<Vector2<f64>> = Vec::new();
+    let mut safety = SafetyMonitor::new(SafetyParams::default());
+    let mut prev_estop_reason = None;
 
     let mut prev_frame = program_start;
+    // for how fast odometry says the robot is going
+    let mut prev_odom_pose = pose_estimator.odometry().get_pose().clone();
+    let mut prev_now = program_start.elapsed();
+    let mut last_map_broadcast = program_start;
     loop {
-        odom.update(*heading.read().unwrap(), &wheel_positions.read().unwrap());
-        io.broadcast().emit("odom", odom.get_pose()).await.unwrap();
-        dbg!(*heading.read().unwrap(), &wheel_positions.read().unwrap(), odom.get_pose());
-        let mut locked = state.cmd_vel.lock().unwrap();
-        match &*locked {
-            DriveCommand::TeleopVelocity(s) => *commanded_speeds.lock().unwrap() = s.clone(),
-            DriveCommand::PathfindToPosition(pos) => {
-                let mut new_path = Path { waypoints: Vec::new() };
-                new_path.waypoints.push(odom.get_pose().clone());
-                new_path.waypoints.push(Transform2d::new(0.2, 0.2, 1.0));
-                new_path.waypoints.push(pos.clone());
-                io.broadcast().emit("path", &new_path.waypoints).await.unwrap(); // FIXME don't hold the lock here
-                *locked = DriveCommand::FollowPath(new_path);
-            },
-            DriveCommand::FollowPath(path) => {
-                let (pursuit_speeds, goal_pose) = path.pure_pursuit(odom.get_pose());
-                io.broadcast().emit("pursuitPose", &goal_pose).await.unwrap();
-                *commanded_speeds.lock().unwrap() = pursuit_speeds;
+        let now = program_start.elapsed();
+        pose_estimator.update_odometry(*heading.read().unwrap(), &wheel_positions.read().unwrap(), now);
+        let pose = pose_estimator.get_estimated_pose();
+        io.broadcast().emit("odom", &pose).await.unwrap();
+        dbg!(*heading.read().unwrap(), &wheel_positions.read().unwrap(), &pose);
+        if std::mem::take(&mut *state.dashboard_connected.lock().unwrap()) {
+            // transitions only get sent as they happen, so a dashboard that connects later needs the history
+            io.broadcast().emit("behaviourState", &behaviour.state()).await.unwrap();
+            io.broadcast().emit("behaviourLog", &behaviour.log().collect::<Vec<_>>()).await.unwrap();
+        }
+        if std::mem::take(&mut *state.clear_estop_requested.lock().unwrap()) {
+            safety.clear();
+        }
+        safety.update(now);
+        if safety.estop_reason() != prev_estop_reason {
+            prev_estop_reason = safety.estop_reason();
+            io.broadcast().emit("estop", &prev_estop_reason).await.unwrap();
+        }
+        let coverage_request = state.coverage_request.lock().unwrap().take();
+        if let Some(polygon) = coverage_request {
+            let polygon = polygon.into_iter().map(|[x, y]| Vector2::new(x, y)).collect::<Vec<_>>();
+            let params = CoverageParams::default();
+            let paths = coverage::plan_coverage(&costmap, &polygon, &pose, &params);
+            if paths.is_empty() {
+                eprintln!("nothing to cover in {:?}", polygon);
+            } else {
+                io.broadcast().emit("coveragePaths", &paths.iter().map(|path| &path.waypoints).collect::<Vec<_>>()).await.unwrap();
+                behaviour.request(DriveRequest::StartMission(coverage::to_mission(&paths)), now);
+                coverage = Some(CoverageTracker::new(&costmap, &polygon, params));
+            }
+        }
+        let exploration_request = state.exploration_request.lock().unwrap().take();
+        match exploration_request {
+            // a loaded map doesn't get any bigger, so there'd be nothing to explore into
+            Some(true) if !mapping => eprintln!("can only explore while mapping"),
+            Some(true) => explorer = Some(Explorer::new(ExplorationParams::default())),
+            Some(false) if explorer.take().is_some() => {
+                behaviour.request(DriveRequest::Cancel, now);
+                io.broadcast().emit("exploration", &ExplorationStatus::Stopped).await.unwrap();
+            }
+            Some(false) | None => {}
+        }
+        for request in std::mem::take(&mut *state.drive_requests.lock().unwrap()) {
+            // whatever it is, it replaces the coverage mission
+            coverage = None;
+            behaviour.request(request, now);
+        }
+        if let Some(exploring) = &mut explorer {
+            match exploring.update(&occupancy_grid.grid, &costmap, &pose, now) {
+                ExplorationUpdate::Continue => {}
+                ExplorationUpdate::Goal(goal) => {
+                    io.broadcast().emit("frontiers", exploring.frontiers()).await.unwrap();
+                    io.broadcast().emit("exploration", &ExplorationStatus::Exploring { frontiers: exploring.frontiers().len() }).await.unwrap();
+                    coverage = None;
+                    behaviour.request(DriveRequest::PathfindTo(goal), now);
+                }
+                ExplorationUpdate::Done => {
+                    println!("no reachable frontiers left, done exploring");
+                    explorer = None;
+                    io.broadcast().emit("frontiers", &Vec::<Frontier>::new()).await.unwrap();
+                    io.broadcast().emit("exploration", &ExplorationStatus::Done).await.unwrap();
+                }
+            }
+        }
+        let current_speeds = commanded_speeds.lock().unwrap().clone();
+        let odom_pose = pose_estimator.odometry().get_pose().clone();
+        let measured_speeds = Twist2d::from(-prev_odom_pose.clone() + odom_pose.clone()) * (1.0 / now.saturating_sub(prev_now).as_secs_f64().max(1e-3));
+        (prev_odom_pose, prev_now) = (odom_pose, now);
+        let follower = *state.follower.lock().unwrap();
+        let zone_edits = std::mem::take(&mut *state.zone_edits.lock().unwrap());
+        if !zone_edits.is_empty() {
+            for edit in zone_edits {
+                if let Err(e) = zones.apply(edit) {
+                    eprintln!("couldn't edit zones: {}", e);
+                }
+            }
+            costmap.set_keep_out_zones(&zones.keep_out_polygons());
+            // zones are cheap to save, so they don't wait for the whole map to be saved
+            if let Err(e) = fs::create_dir_all(&map_directory).and_then(|_| zones.save(map_directory.join("zones.json"))) {
+                eprintln!("failed to save zones: {}", e);
+            }
+            io.broadcast().emit("zones", &zones.zones).await.unwrap();
+        }
+        costmap.update();
+        let speeds = behaviour.update(&BehaviourInputs {
+            pose: &pose,
+            now,
+            costmap: &costmap,
+            zones: &zones,
+            scan: &latest_scan,
+            current_speeds: &current_speeds,
+            measured_speeds: &measured_speeds,
+            estop: safety.estop_reason(),
+            follower,
+        });
+        *commanded_speeds.lock().unwrap() = safety.limit(zones.limit(&pose, speeds));
+        for message in behaviour.take_messages() {
+            match message {
+                BehaviourMessage::Path(waypoints) => io.broadcast().emit("path", &waypoints).await.unwrap(),
+                BehaviourMessage::Trajectory(trajectory) => io.broadcast().emit("trajectory", &trajectory).await.unwrap(),
+                BehaviourMessage::PursuitPose(pose) => io.broadcast().emit("pursuitPose", &pose).await.unwrap(),
+                BehaviourMessage::PlanningError(e) => io.broadcast().emit("planningError", &e).await.unwrap(),
+                BehaviourMessage::NavigationResult(result) => {
+                    io.broadcast().emit("navigationResult", &result).await.unwrap();
+                    // cancelled ones were replaced by a new request, which already took care of it (and may have just started surveying)
+                    if result != NavigationResult::Cancelled {
+                        coverage = None;
+                    }
+                    if explorer.as_mut().is_some_and(|exploring| !exploring.navigation_result(&result)) {
+                        explorer = None;
+                        io.broadcast().emit("exploration", &ExplorationStatus::Stopped).await.unwrap();
+                    }
+                }
+                BehaviourMessage::MissionProgress(progress) => io.broadcast().emit("missionProgress", &progress).await.unwrap(),
+                BehaviourMessage::Transition(transition) => io.broadcast().emit("behaviourTransition", &transition).await.unwrap(),
+                BehaviourMessage::Recovery(report) => io.broadcast().emit("recovery", &report).await.unwrap(),
+                BehaviourMessage::ClearLocalCostmap => costmap.clear_obstacles(),
+                BehaviourMessage::Refused(reason) => io.broadcast().emit("refused", &reason).await.unwrap(),
+                BehaviourMessage::SaveScan { name } => {
+                    if let Err(e) = save_scan(&map_directory, &name, &pose, &latest_scan) {
+                        eprintln!("failed to save scan: {}", e);
+                    }
+                }
+            }
+        }
+        if let Ok(scan) = scan_rx.try_recv() {
+            latest_scan = scan.to_cartesian_points();
+            costmap.update_obstacles(&pose, &latest_scan);
+            if let Some(coverage) = &mut coverage {
+                coverage.update(&pose, &latest_scan);
+                io.broadcast().emit("coverage", &coverage.progress()).await.unwrap();
+            }
+            let speeds = commanded_speeds.lock().unwrap().clone();
+            if safety.update_scan(&latest_scan, &speeds, now) {
+                *commanded_speeds.lock().unwrap() = Twist2d::ZERO;
+            }
+            io.broadcast().emit("protectiveField", &safety.field(&speeds).iter().map(|vertex| [vertex[0], vertex[1]]).collect::<Vec<_>>()).await.unwrap();
+            if let Some(amcl) = &mut amcl {
+                // localizing in a loaded map, which is kept as is
+                if let Some(estimate) = amcl.update(pose_estimator.odometry().get_pose(), &scan.to_cartesian_points()) {
+                    let (std_x, std_y, std_theta) = estimate.std_devs();
+                    // TODO scans don't have timestamps yet, so this is off by however long the scan took to get here
+                    if pose_estimator.add_vision_measurement(estimate.pose, now, std_x, std_y, std_theta).is_err() {
+                        eprintln!("couldn't add amcl measurement to the pose estimator");
+                    }
+                }
+            } else {
+                // the pose graph only uses how far odometry moved between scans, scan matching against the submaps takes care of the rest
+                let odom_pose = pose_estimator.odometry().get_pose().clone();
+                let res = pose_graph.update(odom_pose.clone(), scan.to_cartesian_points());
+                match res {
+                    PoseGraphUpdateResult::Added => io.broadcast().emit("poseGraphNode", &WsPoseGraphNode{tf:pose_graph.backend.pose(pose_graph.backend.num_nodes() - 1), scan:scan.to_cartesian_points_ws()}).await.unwrap(),
+                    PoseGraphUpdateResult::LoopClosed => {
+                        io.broadcast().emit("poseGraph", &pose_graph.backend.poses().into_iter().zip(&pose_graph.node_scans).map(|(tf, scan)| {
+                            WsPoseGraphNode{ tf, scan: scan.iter().map(|x| [x[0], x[1]]).collect::<Vec<_>>() }
+                        }).collect::<Vec<_>>()).await.unwrap();
+                        // finished submaps don't change, but they move with the graph
+                        let submap_poses = (0..pose_graph.submaps.len()).map(|index| pose_graph.world_to_submap(index)).collect::<Vec<_>>();
+                        io.broadcast().emit("submapPoses", &submap_poses).await.unwrap();
+                    }
+                    _=>{}
+                }
+                if !matches!(res, PoseGraphUpdateResult::NotAdded) {
+                    let world_to_newest = pose_graph.backend.pose(pose_graph.backend.num_nodes() - 1);
+                    let (std_x, std_y, std_theta) = SCAN_MATCHING_STD_DEVS;
+                    if pose_estimator.add_vision_measurement(world_to_newest, now, std_x, std_y, std_theta).is_err() {
+                        eprintln!("couldn't add scan matching measurement to the pose estimator");
+                    }
+                    for index in pose_graph.take_finished_submaps() {
+                        let submap = WsSubmap { index, tf: pose_graph.world_to_submap(index), grid: pose_graph.submaps[index].grid.to_ws() };
+                        io.broadcast().emit("submap", &submap).await.unwrap();
+                    }
+                    for (index, scan) in pose_graph.take_pruned_nodes() {
+                        occupancy_grid.remove_node(index, &scan);
+                    }
+                    // only nodes that moved since the last update get re-raytraced
+                    occupancy_grid.update(&pose_graph.backend.poses(), &pose_graph.node_scans);
+                    costmap.set_static(&occupancy_grid.grid);
+                    io.broadcast().emit("occupancyGrid", &occupancy_grid.grid.to_ws()).await.unwrap();
+                }
+            }
+        }
+        if last_map_broadcast.elapsed() > MAP_BROADCAST_PERIOD {
+            // nothing changes in a loaded map, but dashboards that connected later still need to see it
+            if !mapping {
+                io.broadcast().emit("occupancyGrid", &occupancy_grid.grid.to_ws()).await.unwrap();
+            }
+            io.broadcast().emit("zones", &zones.zones).await.unwrap();
+            last_map_broadcast = Instant::now();
+        }
+        if std::mem::take(&mut *state.save_map_requested.lock().unwrap()) {
+            match save_map(&map_directory, &pose_graph, &occupancy_grid.grid, &zones) {
+                Ok(()) => println!("saved map to {}", map_directory.display()),
+                Err(e) => eprintln!("failed to save map to {}: {}", map_directory.display(), e),
             }
         }
-        // if let Ok(scan) = scan_rx.try_recv() {
-        //     let res = pose_graph.update(odom.get_pose().clone(), scan.to_cartesian_points());
-        //     match res {
-        //         PoseGraphUpdateResult::Added => io.broadcast().emit("poseGraphNode", &WsPoseGraphNode{tf:odom.get_pose().clone(), scan:scan.to_cartesian_points_ws()}).await.unwrap(),
-        //         PoseGraphUpdateResult::LoopClosed => io.broadcast().emit("poseGraph", &{
-        //             let mut nodes = Vec::new();
-        //             let coords = &pose_graph.backend.nodes;
-        //             dbg!(coords.len() ,coords.len()/3);
-        //             for i in 0..(coords.len()/3) {
-        //                 // scuffed
-        //                 nodes.push(WsPoseGraphNode{ tf: Transform2d::new(coords[3*i], coords[3*i+1], coords[3*i+2]), scan: pose_graph.node_scans[i].iter().map(|x| [x[0], x[1]]).collect::<Vec<_>>() });
-        //             }
-        //             nodes
-        //         }).await.unwrap(),
-        //         _=>{}
-        //     }
-        // }
         if let Some(i) = DURATION_PER_FRAME.checked_sub(prev_frame.elapsed()) {
             sleep(i).await;
         } else {
@@ -0,0 +1,237 @@
+//! missions: drive to a list of poses one after another, doing some actions at each one.
+//! this only keeps track of where in the mission the robot is, behaviour.rs does the actual planning and following for each leg.
+
+use serde::{Deserialize, Serialize};
+use tokio::time::Duration;
+
+use crate::geometry::{wrap_angle, Transform2d, Twist2d};
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum MissionAction {
+    Wait { seconds: f64 },
+    RotateTo { heading_radians: f64 },
+    /// saves the latest scan along with the pose it was taken at
+    SaveScan,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MissionWaypoint {
+    pub pose: Transform2d,
+    #[serde(default)]
+    pub actions: Vec<MissionAction>,
+}
+
+/// what the dashboard can do to a running mission
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MissionControl {
+    Pause,
+    Resume,
+    /// gives up on the current waypoint (and whatever actions are left at it) and moves on to the next one
+    Skip,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct MissionParams {
+    pub heading_kp: f64,
+    pub max_angular_speed_radians_per_second: f64,
+    pub heading_tolerance_radians: f64,
+}
+
+impl Default for MissionParams {
+    fn default() -> Self {
+        Self { heading_kp: 2.0, max_angular_speed_radians_per_second: 1.0, heading_tolerance_radians: 0.05 }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub enum MissionStage {
+    /// driving to the current waypoint
+    Driving,
+    /// doing the action-th action of the current waypoint
+    Acting { action: usize, #[serde(skip)] started: Duration },
+    Done,
+}
+
+/// sent to the dashboard whenever it changes
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct MissionProgress {
+    pub waypoint: usize,
+    pub num_waypoints: usize,
+    pub stage: MissionStage,
+    pub paused: bool,
+}
+
+/// what the current action wants from the main loop
+#[derive(Debug, Clone)]
+pub struct ActionOutput {
+    pub speeds: Twist2d,
+    pub save_scan: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct Mission {
+    pub waypoints: Vec<MissionWaypoint>,
+    pub params: MissionParams,
+    current: usize,
+    stage: MissionStage,
+    paused_since: Option<Duration>,
+}
+
+impl Mission {
+    pub fn new(waypoints: Vec<MissionWaypoint>, params: MissionParams) -> Self {
+        let stage = if waypoints.is_empty() { MissionStage::Done } else { MissionStage::Driving };
+        Self { waypoints, params, current: 0, stage, paused_since: None }
+    }
+
+    /// where the robot should be driving to right now, if it should be driving at all
+    pub fn current_goal(&self) -> Option<&Transform2d> {
+        match self.stage {
+            MissionStage::Driving => Some(&self.waypoints[self.current].pose),
+            _ => None,
+        }
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.stage == MissionStage::Done
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused_since.is_some()
+    }
+
+    pub fn progress(&self) -> MissionProgress {
+        MissionProgress { waypoint: self.current, num_waypoints: self.waypoints.len(), stage: self.stage.clone(), paused: self.is_paused() }
+    }
+
+    /// the robot made it to the current waypoint, on to its actions
+    pub fn arrived(&mut self, now: Duration) {
+        if self.stage == MissionStage::Driving {
+            self.start_action(0, now);
+        }
+    }
+
+    pub fn control(&mut self, control: MissionControl, now: Duration) {
+        match control {
+            MissionControl::Pause => {
+                self.paused_since.get_or_insert(now);
+            }
+            MissionControl::Resume => {
+                // waiting shouldn't count the time spent paused
+                if let (Some(paused_since), MissionStage::Acting { started, .. }) = (self.paused_since.take(), &mut self.stage) {
+                    *started += now.saturating_sub(paused_since);
+                }
+            }
+            MissionControl::Skip => {
+                if !self.is_done() {
+                    self.next_waypoint();
+                }
+            }
+        }
+    }
+
+    /// runs the current action. only does anything while acting and not paused.
+    pub fn act(&mut self, pose: &Transform2d, now: Duration) -> ActionOutput {
+        let mut output = ActionOutput { speeds: Twist2d::ZERO, save_scan: false };
+        let MissionStage::Acting { action, started } = self.stage.clone() else {
+            return output;
+        };
+        if self.is_paused() {
+            return output;
+        }
+        let done = match &self.waypoints[self.current].actions[action] {
+            MissionAction::Wait { seconds } => now.saturating_sub(started).as_secs_f64() >= *seconds,
+            MissionAction::RotateTo { heading_radians } => {
+                let error = wrap_angle(heading_radians - pose.theta_radians);
+                output.speeds.dtheta = (self.params.heading_kp * error).clamp(-self.params.max_angular_speed_radians_per_second, self.params.max_angular_speed_radians_per_second);
+                error.abs() < self.params.heading_tolerance_radians
+            }
+            MissionAction::SaveScan => {
+                output.save_scan = true;
+                true
+            }
+        };
+        if done {
+            output.speeds = Twist2d::ZERO;
+            self.start_action(action + 1, now);
+        }
+        output
+    }
+
+    fn start_action(&mut self, action: usize, now: Duration) {
+        if action < self.waypoints[self.current].actions.len() {
+            self.stage = MissionStage::Acting { action, started: now };
+        } else {
+            self.next_waypoint();
+        }
+    }
+
+    fn next_waypoint(&mut self) {
+        self.current += 1;
+        self.stage = if self.current < self.waypoints.len() { MissionStage::Driving } else { MissionStage::Done };
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn s(seconds: f64) -> Duration {
+        Duration::from_secs_f64(seconds)
+    }
+
+    fn mission() -> Mission {
+        Mission::new(
+            vec![
+                MissionWaypoint { pose: Transform2d::new(1.0, 0.0, 0.0), actions: vec![MissionAction::Wait { seconds: 2.0 }, MissionAction::RotateTo { heading_radians: 1.0 }, MissionAction::SaveScan] },
+                MissionWaypoint { pose: Transform2d::new(2.0, 0.0, 0.0), actions: vec![] },
+                MissionWaypoint { pose: Transform2d::new(3.0, 0.0, 0.0), actions: vec![MissionAction::SaveScan] },
+            ],
+            MissionParams::default(),
+        )
+    }
+
+    #[test]
+    fn test_sequences_waypoints_and_actions() {
+        let mut mission = mission();
+        assert_eq!(mission.current_goal().map(|goal| goal.x_meters), Some(1.0));
+        mission.arrived(s(10.0));
+        assert!(mission.current_goal().is_none());
+        // waiting, and pausing doesn't count towards it
+        let pose = Transform2d::new(1.0, 0.0, 0.0);
+        assert_eq!(mission.act(&pose, s(11.0)).speeds.dtheta, 0.0);
+        mission.control(MissionControl::Pause, s(11.0));
+        mission.control(MissionControl::Resume, s(14.0));
+        mission.act(&pose, s(14.5));
+        assert!(matches!(mission.progress().stage, MissionStage::Acting { action: 0, .. }));
+        mission.act(&pose, s(15.0));
+        assert!(matches!(mission.progress().stage, MissionStage::Acting { action: 1, .. }));
+        // rotating
+        let output = mission.act(&pose, s(15.1));
+        assert!(output.speeds.dtheta > 0.0 && output.speeds.dx == 0.0);
+        let output = mission.act(&Transform2d::new(1.0, 0.0, 0.99), s(16.0));
+        assert_eq!(output.speeds.dtheta, 0.0);
+        assert!(mission.act(&pose, s(16.1)).save_scan);
+        // no actions at the second one
+        assert_eq!(mission.current_goal().map(|goal| goal.x_meters), Some(2.0));
+        mission.arrived(s(20.0));
+        assert_eq!(mission.current_goal().map(|goal| goal.x_meters), Some(3.0));
+        mission.arrived(s(30.0));
+        assert!(mission.act(&pose, s(30.0)).save_scan);
+        assert!(mission.is_done());
+        assert_eq!(mission.progress(), MissionProgress { waypoint: 3, num_waypoints: 3, stage: MissionStage::Done, paused: false });
+    }
+
+    #[test]
+    fn test_skip() {
+        let mut mission = mission();
+        mission.control(MissionControl::Skip, s(0.0));
+        assert_eq!(mission.current_goal().map(|goal| goal.x_meters), Some(2.0));
+        mission.arrived(s(1.0));
+        mission.arrived(s(2.0));
+        // skipping the actions of the last one ends the mission
+        mission.control(MissionControl::Skip, s(2.0));
+        assert!(mission.is_done());
+        mission.control(MissionControl::Skip, s(3.0));
+        assert!(mission.is_done());
+    }
+}
@@ -0,0 +1,463 @@
+use std::{fs::{self, File}, io::{self, BufWriter}, path::Path};
+
+use nalgebra::Vector2;
+
+use crate::{geometry::Transform2d, lidar::ROBOT_TO_LIDAR, ws::WsOccupancyGrid};
+
+/// value sent to the dashboard for cells that have never been observed, same as -1 in a ros OccupancyGrid
+pub const UNKNOWN_CELL: u8 = 255;
+
+/// same thresholds as ros map_saver, written into the yaml so map_server reads the cells back the same way
+pub const OCCUPIED_THRESHOLD: f64 = 0.65;
+pub const FREE_THRESHOLD: f64 = 0.196;
+
+#[derive(Debug, Clone)]
+pub struct OccupancyGridConfig {
+    pub resolution_meters: f64,
+    /// world position of the corner of cell (0, 0)
+    pub origin_x_meters: f64,
+    pub origin_y_meters: f64,
+    pub width_cells: usize,
+    pub height_cells: usize,
+    /// added to a cell every time a ray ends in it
+    pub log_odds_hit: f32,
+    /// added to a cell every time a ray passes through it
+    pub log_odds_miss: f32,
+    /// log odds are clamped to this when reading cells so a few observations can always flip a cell
+    pub log_odds_clamp: f32,
+    /// rays are only traced up to this far
+    pub max_range_meters: f64,
+}
+
+impl Default for OccupancyGridConfig {
+    fn default() -> Self {
+        Self {
+            resolution_meters: 0.05,
+            origin_x_meters: -10.0,
+            origin_y_meters: -10.0,
+            width_cells: 400,
+            height_cells: 400,
+            log_odds_hit: 0.85,
+            log_odds_miss: -0.4,
+            log_odds_clamp: 5.0,
+            max_range_meters: 12.0,
+        }
+    }
+}
+
+/// log-odds occupancy grid built from lidar scans
+#[derive(Debug, Clone)]
+pub struct OccupancyGrid {
+    pub config: OccupancyGridConfig,
+    /// (hits, misses) of every cell, counted instead of summing log odds so that scans can be removed again exactly.
+    /// row major, starting at the origin.
+    counts: Vec<(i32, i32)>,
+}
+
+impl OccupancyGrid {
+    pub fn new(config: OccupancyGridConfig) -> Self {
+        let counts = vec![(0, 0); config.width_cells * config.height_cells];
+        Self { config, counts }
+    }
+
+    pub fn width(&self) -> usize {
+        self.config.width_cells
+    }
+
+    pub fn height(&self) -> usize {
+        self.config.height_cells
+    }
+
+    pub fn world_to_cell(&self, point: &Vector2<f64>) -> (i64, i64) {
+        (
+            ((point[0] - self.config.origin_x_meters) / self.config.resolution_meters).floor() as i64,
+            ((point[1] - self.config.origin_y_meters) / self.config.resolution_meters).floor() as i64,
+        )
+    }
+
+    pub fn cell_to_world(&self, x: i64, y: i64) -> Vector2<f64> {
+        Vector2::new(
+            self.config.origin_x_meters + (x as f64 + 0.5) * self.config.resolution_meters,
+            self.config.origin_y_meters + (y as f64 + 0.5) * self.config.resolution_meters,
+        )
+    }
+
+    pub fn index(&self, x: i64, y: i64) -> Option<usize> {
+        if x < 0 || y < 0 || x >= self.config.width_cells as i64 || y >= self.config.height_cells as i64 {
+            None
+        } else {
+            Some(y as usize * self.config.width_cells + x as usize)
+        }
+    }
+
+    /// clamped log odds, 0.0 for unknown cells and cells outside of the map
+    pub fn log_odds(&self, x: i64, y: i64) -> f32 {
+        self.index(x, y).map_or(0.0, |index| {
+            let (hits, misses) = self.counts[index];
+            (hits as f32 * self.config.log_odds_hit + misses as f32 * self.config.log_odds_miss)
+                .clamp(-self.config.log_odds_clamp, self.config.log_odds_clamp)
+        })
+    }
+
+    /// probability that the cell is occupied, 0.5 if it's unknown
+    pub fn probability(&self, x: i64, y: i64) -> f64 {
+        1.0 - 1.0 / (1.0 + (self.log_odds(x, y) as f64).exp())
+    }
+
+    pub fn is_known(&self, x: i64, y: i64) -> bool {
+        self.index(x, y).is_some_and(|index| self.counts[index] != (0, 0))
+    }
+
+    /// raytraces a scan (in the robot frame) into the map from the lidar's position
+    pub fn integrate_scan(&mut self, world_to_robot: &Transform2d, scan: &[Vector2<f64>]) {
+        self.apply_scan(world_to_robot, scan, 1);
+    }
+
+    /// exactly undoes integrate_scan with the same arguments. used to move scans around after loop closure.
+    pub fn remove_scan(&mut self, world_to_robot: &Transform2d, scan: &[Vector2<f64>]) {
+        self.apply_scan(world_to_robot, scan, -1);
+    }
+
+    fn apply_scan(&mut self, world_to_robot: &Transform2d, scan: &[Vector2<f64>], weight: i32) {
+        let world_to_lidar = world_to_robot.clone() + Transform2d::new(ROBOT_TO_LIDAR.x_meters, ROBOT_TO_LIDAR.y_meters, 0.0);
+        let origin = Vector2::new(world_to_lidar.x_meters, world_to_lidar.y_meters);
+        let start = self.world_to_cell(&origin);
+        for point in scan {
+            let world_point = world_to_robot.clone() + Transform2d::new(point[0], point[1], 0.0);
+            let mut end_point = Vector2::new(world_point.x_meters, world_point.y_meters);
+            let range = (end_point - origin).norm();
+            let hit = range <= self.config.max_range_meters;
+            if !hit {
+                end_point = origin + (end_point - origin) * (self.config.max_range_meters / range);
+            }
+            let end = self.world_to_cell(&end_point);
+            for (x, y) in bresenham(start, end) {
+                if (x, y) == end {
+                    break;
+                }
+                self.add(x, y, 0, weight);
+            }
+            if hit {
+                self.add(end.0, end.1, weight, 0);
+            }
+        }
+    }
+
+    fn add(&mut self, x: i64, y: i64, hits: i32, misses: i32) {
+        if let Some(index) = self.index(x, y) {
+            self.counts[index].0 += hits;
+            self.counts[index].1 += misses;
+        }
+    }
+
+    /// centers of every cell that's more likely occupied than OCCUPIED_THRESHOLD. submaps keep theirs up to date themselves,
+    /// this is for checking that they do.
+    #[cfg(test)]
+    pub fn occupied_points(&self) -> Vec<Vector2<f64>> {
+        let mut points = Vec::new();
+        for y in 0..self.height() as i64 {
+            for x in 0..self.width() as i64 {
+                if self.probability(x, y) > OCCUPIED_THRESHOLD {
+                    points.push(self.cell_to_world(x, y));
+                }
+            }
+        }
+        points
+    }
+
+    /// the smallest grid with the same cells that still contains every known cell. an empty grid stays as is.
+    pub fn cropped(&self) -> OccupancyGrid {
+        let known = (0..self.counts.len()).filter(|index| self.counts[*index] != (0, 0));
+        let (mut min_x, mut min_y, mut max_x, mut max_y) = (usize::MAX, usize::MAX, 0, 0);
+        for index in known {
+            let (x, y) = (index % self.width(), index / self.width());
+            (min_x, min_y, max_x, max_y) = (min_x.min(x), min_y.min(y), max_x.max(x), max_y.max(y));
+        }
+        if min_x > max_x {
+            return self.clone();
+        }
+        let config = OccupancyGridConfig {
+            origin_x_meters: self.config.origin_x_meters + min_x as f64 * self.config.resolution_meters,
+            origin_y_meters: self.config.origin_y_meters + min_y as f64 * self.config.resolution_meters,
+            width_cells: max_x - min_x + 1,
+            height_cells: max_y - min_y + 1,
+            ..self.config.clone()
+        };
+        let counts = (min_y..=max_y)
+            .flat_map(|y| self.counts[y * self.width() + min_x..=y * self.width() + max_x].iter().cloned())
+            .collect();
+        OccupancyGrid { config, counts }
+    }
+
+    /// occupancy from 0 to 100 (or UNKNOWN_CELL) for every cell, row major
+    pub fn to_occupancy_values(&self) -> Vec<u8> {
+        (0..self.counts.len())
+            .map(|index| {
+                if self.counts[index] == (0, 0) {
+                    UNKNOWN_CELL
+                } else {
+                    let (x, y) = ((index % self.width()) as i64, (index / self.width()) as i64);
+                    (self.probability(x, y) * 100.0).round() as u8
+                }
+            })
+            .collect()
+    }
+
+    /// run length encoded so that the big empty areas of the map don't cost anything to send
+    pub fn to_ws(&self) -> WsOccupancyGrid {
+        let mut runs: Vec<[u32; 2]> = Vec::new();
+        for value in self.to_occupancy_values() {
+            match runs.last_mut() {
+                Some([last, count]) if *last == value as u32 => *count += 1,
+                _ => runs.push([value as u32, 1]),
+            }
+        }
+        WsOccupancyGrid {
+            origin: [self.config.origin_x_meters, self.config.origin_y_meters],
+            resolution_meters: self.config.resolution_meters,
+            width: self.width(),
+            height: self.height(),
+            runs,
+        }
+    }
+
+    /// greyscale image in map_server's trinary format (0 occupied, 254 free, 205 unknown).
+    /// the first row is the top of the map (highest y) like map_server expects.
+    pub fn to_map_image(&self) -> Vec<u8> {
+        let mut pixels = Vec::with_capacity(self.counts.len());
+        for y in (0..self.height() as i64).rev() {
+            for x in 0..self.width() as i64 {
+                let probability = self.probability(x, y);
+                pixels.push(if !self.is_known(x, y) {
+                    205
+                } else if probability > OCCUPIED_THRESHOLD {
+                    0
+                } else if probability < FREE_THRESHOLD {
+                    254
+                } else {
+                    205
+                });
+            }
+        }
+        pixels
+    }
+
+    /// writes a ros map_server compatible yaml file and the pgm it points to next to it
+    pub fn save_map_server(&self, yaml_path: impl AsRef<Path>) -> io::Result<()> {
+        let pgm_path = yaml_path.as_ref().with_extension("pgm");
+        let mut pgm = format!("P5\n# xavier-robot occupancy grid\n{} {}\n255\n", self.width(), self.height()).into_bytes();
+        pgm.extend(self.to_map_image());
+        fs::write(&pgm_path, pgm)?;
+        let image_name = pgm_path.file_name().unwrap().to_string_lossy();
+        fs::write(yaml_path, format!(
+            "image: {}\nmode: trinary\nresolution: {}\norigin: [{}, {}, 0.0]\nnegate: 0\noccupied_thresh: {}\nfree_thresh: {}\n",
+            image_name, self.config.resolution_meters, self.config.origin_x_meters, self.config.origin_y_meters, OCCUPIED_THRESHOLD, FREE_THRESHOLD
+        ))
+    }
+
+    pub fn save_png(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let mut encoder = png::Encoder::new(BufWriter::new(File::create(path)?), self.width() as u32, self.height() as u32);
+        encoder.set_color(png::ColorType::Grayscale);
+        encoder.set_depth(png::BitDepth::Eight);
+        let mut writer = encoder.write_header()?;
+        writer.write_image_data(&self.to_map_image())?;
+        Ok(writer.finish()?)
+    }
+}
+
+/// every cell on the line from start to end, including both ends
+pub fn bresenham(start: (i64, i64), end: (i64, i64)) -> Vec<(i64, i64)> {
+    let (mut x, mut y) = start;
+    let dx = (end.0 - x).abs();
+    let dy = -(end.1 - y).abs();
+    let sx = if x < end.0 { 1 } else { -1 };
+    let sy = if y < end.1 { 1 } else { -1 };
+    let mut err = dx + dy;
+    let mut cells = Vec::with_capacity((dx - dy) as usize + 1);
+    loop {
+        cells.push((x, y));
+        if (x, y) == end {
+            return cells;
+        }
+        let e2 = 2 * err;
+        if e2 >= dy {
+            err += dy;
+            x += sx;
+        }
+        if e2 <= dx {
+            err += dx;
+            y += sy;
+        }
+    }
+}
+
+/// keeps an occupancy grid in sync with the pose graph. after loop closure only the scans whose nodes actually moved get re-integrated.
+pub struct OccupancyGridMapper {
+    pub grid: OccupancyGrid,
+    /// the pose every node's scan was integrated at
+    integrated_poses: Vec<Transform2d>,
+    /// nodes that moved less than this aren't re-integrated
+    pub translation_tolerance_meters: f64,
+    pub rotation_tolerance_radians: f64,
+}
+
+impl OccupancyGridMapper {
+    pub fn new(config: OccupancyGridConfig) -> Self {
+        Self {
+            grid: OccupancyGrid::new(config),
+            integrated_poses: Vec::new(),
+            translation_tolerance_meters: 0.01,
+            rotation_tolerance_radians: 0.005,
+        }
+    }
+
+    /// brings the map up to date with the current node poses. returns how many scans had to be (re-)integrated.
+    pub fn update(&mut self, node_poses: &[Transform2d], node_scans: &[Vec<Vector2<f64>>]) -> usize {
+        assert_eq!(node_poses.len(), node_scans.len());
+        // nodes can be removed from the graph, so start over if the map knows about more nodes than the graph has
+        if self.integrated_poses.len() > node_poses.len() {
+            self.rebuild(node_poses, node_scans);
+            return node_poses.len();
+        }
+        let mut updated = 0;
+        for (i, (pose, scan)) in node_poses.iter().zip(node_scans).enumerate() {
+            if let Some(integrated_pose) = self.integrated_poses.get(i) {
+                let moved = -integrated_pose.clone() + pose.clone();
+                if moved.norm() < self.translation_tolerance_meters && moved.theta_radians.abs() < self.rotation_tolerance_radians {
+                    continue;
+                }
+                self.grid.remove_scan(integrated_pose, scan);
+                self.grid.integrate_scan(pose, scan);
+                self.integrated_poses[i] = pose.clone();
+            } else {
+                self.grid.integrate_scan(pose, scan);
+                self.integrated_poses.push(pose.clone());
+            }
+            updated += 1;
+        }
+        updated
+    }
+
+    /// takes the scan of a node that got pruned from the pose graph back out of the map. nodes after it shift down by one, same as in the pose graph.
+    pub fn remove_node(&mut self, index: usize, scan: &[Vector2<f64>]) {
+        if index < self.integrated_poses.len() {
+            let pose = self.integrated_poses.remove(index);
+            self.grid.remove_scan(&pose, scan);
+        }
+    }
+
+    pub fn rebuild(&mut self, node_poses: &[Transform2d], node_scans: &[Vec<Vector2<f64>>]) {
+        self.grid = OccupancyGrid::new(self.grid.config.clone());
+        self.integrated_poses.clear();
+        self.update(node_poses, node_scans);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::sim::{room_walls, simulate_scan};
+
+    #[test]
+    fn test_bresenham() {
+        assert_eq!(bresenham((0, 0), (3, 0)), vec![(0, 0), (1, 0), (2, 0), (3, 0)]);
+        assert_eq!(bresenham((0, 0), (-2, -2)), vec![(0, 0), (-1, -1), (-2, -2)]);
+        assert_eq!(bresenham((1, 1), (1, 1)), vec![(1, 1)]);
+        let line = bresenham((0, 0), (2, 5));
+        assert_eq!(line.first(), Some(&(0, 0)));
+        assert_eq!(line.last(), Some(&(2, 5)));
+        assert_eq!(line.len(), 6);
+    }
+
+    #[test]
+    fn test_integrate_scan() {
+        let mut grid = OccupancyGrid::new(OccupancyGridConfig::default());
+        let pose = Transform2d::new(0.5, 0.3, 0.4);
+        grid.integrate_scan(&pose, &simulate_scan(&room_walls(), &pose, 720, 12.0));
+        // the walls are occupied
+        let (x, y) = grid.world_to_cell(&Vector2::new(2.99, 0.0));
+        assert!(grid.probability(x, y).max(grid.probability(x + 1, y)) > 0.6);
+        // the space between the robot and the walls is free
+        let (x, y) = grid.world_to_cell(&Vector2::new(1.5, -1.0));
+        assert!(grid.probability(x, y) < 0.4);
+        // and outside of the room is unknown
+        let (x, y) = grid.world_to_cell(&Vector2::new(4.0, 0.0));
+        assert!(!grid.is_known(x, y));
+        assert_eq!(grid.probability(x, y), 0.5);
+    }
+
+    #[test]
+    fn test_remove_scan_is_exact() {
+        let mut grid = OccupancyGrid::new(OccupancyGridConfig::default());
+        let walls = room_walls();
+        let first = Transform2d::new(0.5, 0.3, 0.4);
+        let second = Transform2d::new(-1.5, 1.0, -0.3);
+        grid.integrate_scan(&first, &simulate_scan(&walls, &first, 360, 12.0));
+        let expected = grid.to_occupancy_values();
+        let scan = simulate_scan(&walls, &second, 360, 12.0);
+        grid.integrate_scan(&second, &scan);
+        assert_ne!(grid.to_occupancy_values(), expected);
+        grid.remove_scan(&second, &scan);
+        assert_eq!(grid.to_occupancy_values(), expected);
+    }
+
+    #[test]
+    fn test_mapper_only_reintegrates_moved_nodes() {
+        let walls = room_walls();
+        let mut poses = vec![Transform2d::new(0.0, 0.0, 0.0), Transform2d::new(0.5, 0.2, 0.3), Transform2d::new(1.0, 0.5, 0.6)];
+        let scans = poses.iter().map(|pose| simulate_scan(&walls, pose, 360, 12.0)).collect::<Vec<_>>();
+        let mut mapper = OccupancyGridMapper::new(OccupancyGridConfig::default());
+        assert_eq!(mapper.update(&poses, &scans), 3);
+        assert_eq!(mapper.update(&poses, &scans), 0);
+        // pretend a loop closure moved the last node
+        poses[2] = Transform2d::new(1.1, 0.5, 0.6);
+        assert_eq!(mapper.update(&poses, &scans), 1);
+
+        let mut expected = OccupancyGrid::new(OccupancyGridConfig::default());
+        for (pose, scan) in poses.iter().zip(&scans) {
+            expected.integrate_scan(pose, scan);
+        }
+        assert_eq!(mapper.grid.to_occupancy_values(), expected.to_occupancy_values());
+
+        // pruning the middle node takes its scan back out
+        mapper.remove_node(1, &scans[1]);
+        poses.remove(1);
+        assert_eq!(mapper.update(&poses, &[scans[0].clone(), scans[2].clone()]), 0);
+        let mut expected = OccupancyGrid::new(OccupancyGridConfig::default());
+        expected.integrate_scan(&poses[0], &scans[0]);
+        expected.integrate_scan(&poses[1], &scans[2]);
+        assert_eq!(mapper.grid.to_occupancy_values(), expected.to_occupancy_values());
+    }
+
+    #[test]
+    fn test_run_length_encoding() {
+        let mut grid = OccupancyGrid::new(OccupancyGridConfig { width_cells: 10, height_cells: 10, ..Default::default() });
+        grid.add(3, 0, 3, 1);
+        let ws = grid.to_ws();
+        assert_eq!(ws.runs, vec![[UNKNOWN_CELL as u32, 3], [90, 1], [UNKNOWN_CELL as u32, 96]]);
+        assert_eq!(ws.runs.iter().map(|[_, count]| count).sum::<u32>(), 100);
+    }
+
+    #[test]
+    fn test_save_map_server() {
+        let mut grid = OccupancyGrid::new(OccupancyGridConfig { width_cells: 3, height_cells: 2, ..Default::default() });
+        // bottom left is occupied and the cell next to it is free
+        grid.add(0, 0, 3, 0);
+        grid.add(1, 0, 0, 5);
+        assert_eq!(grid.to_map_image(), vec![205, 205, 205, 0, 254, 205]);
+
+        let dir = std::env::temp_dir().join(format!("xavier-map-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        grid.save_map_server(dir.join("map.yaml")).unwrap();
+        let yaml = fs::read_to_string(dir.join("map.yaml")).unwrap();
+        assert!(yaml.contains("image: map.pgm\n"));
+        assert!(yaml.contains("resolution: 0.05\n"));
+        assert!(yaml.contains("origin: [-10, -10, 0.0]\n"));
+        let pgm = fs::read(dir.join("map.pgm")).unwrap();
+        assert!(pgm.starts_with(b"P5\n"));
+        assert!(pgm.ends_with(&[205, 205, 205, 0, 254, 205]));
+        grid.save_png(dir.join("map.png")).unwrap();
+        assert!(fs::read(dir.join("map.png")).unwrap().starts_with(b"\x89PNG"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,30 +1,127 @@
 use std::f64::consts::PI;
 
-use crate::geometry::{Transform2d, Twist2d};
+use crate::geometry::{wrap_angle, Transform2d, Twist2d};
 
 pub struct Path {
     pub waypoints: Vec<Transform2d>,
 }
 
+/// gains and limits for Path::pure_pursuit
+#[derive(Debug, Clone)]
+pub struct PurePursuitConfig {
+    /// lookahead distance is current speed times this, clamped between the min and max lookahead
+    pub lookahead_time_seconds: f64,
+    pub min_lookahead_meters: f64,
+    pub max_lookahead_meters: f64,
+    pub max_speed_meters_per_second: f64,
+    pub max_angular_speed_radians_per_second: f64,
+    /// caps speed in turns to sqrt(max_lateral_acceleration / curvature)
+    pub max_lateral_acceleration_meters_per_second_squared: f64,
+    /// caps speed near the end of the path so the robot can stop in time
+    pub max_deceleration_meters_per_second_squared: f64,
+    /// the robot keeps creeping forward at least this fast until it's at the goal
+    pub min_speed_meters_per_second: f64,
+    /// if the lookahead point is further off to the side than this the robot turns in place towards it first
+    pub rotate_in_place_angle_radians: f64,
+    /// angular speed per radian of heading error when turning in place
+    pub heading_kp: f64,
+    /// once the robot is this close to the last waypoint it only turns to the goal heading
+    pub arrival_distance_meters: f64,
+}
+
+impl Default for PurePursuitConfig {
+    fn default() -> Self {
+        Self {
+            lookahead_time_seconds: 1.5,
+            min_lookahead_meters: 0.15,
+            max_lookahead_meters: 0.6,
+            max_speed_meters_per_second: 0.3,
+            max_angular_speed_radians_per_second: 1.0,
+            max_lateral_acceleration_meters_per_second_squared: 0.3,
+            max_deceleration_meters_per_second_squared: 0.3,
+            min_speed_meters_per_second: 0.03,
+            rotate_in_place_angle_radians: PI / 3.0,
+            heading_kp: 2.0,
+            arrival_distance_meters: 0.05,
+        }
+    }
+}
+
 impl Path {
-    pub fn pure_pursuit(&self, current_position: &Transform2d) -> (Twist2d, Transform2d) {
-        let curr_t = self.get_path_progress(current_position);
-        let max_t = self.waypoints.len() as f64 - 1.0;
-        let target_t = (curr_t + 0.1).min(max_t); // TODO look ahead a fixed distance instead of using path progress
-        let target_pose = self.get_path_at_time(target_t);
-        let err = -current_position.clone() + target_pose.clone();
-        dbg!(&err);
-        let cmd_vel = if max_t - curr_t < 0.02 { // TODO consider adding a debouncer here
+    /// adaptive pure pursuit: drives along the arc through the point where the path leaves a circle around the robot whose radius grows with speed.
+    /// current_speed is the robot's forward speed, returns the command and the lookahead point.
+    pub fn pure_pursuit(&self, current_position: &Transform2d, current_speed: f64, config: &PurePursuitConfig) -> (Twist2d, Transform2d) {
+        let goal = self.waypoints.last().unwrap();
+        let to_goal = -current_position.clone() + goal.clone();
+        if to_goal.norm() < config.arrival_distance_meters {
             // we are already at the target position, we can now rotate to the desired angle.
-            Twist2d::new(0.2 * err.x_meters, 0.2 * err.y_meters, (10.0 * ((err.theta_radians + PI).rem_euclid(2.0*PI) - PI)).max(-1.0).min(1.0))
-        } else {
-            // we have to drive to the target position
-            let angle_err = (err.y_meters).atan2(err.x_meters);
-            dbg!(angle_err);
-            let forward_vel = (0.4 - 10.0 * angle_err * angle_err).max(0.0).min(0.2);
-            Twist2d::new(forward_vel, 0.0, (10.0 * angle_err).min(1.0).max(-1.0))
+            // this never stops correcting by itself, the goal checker decides when it's done
+            let omega = (config.heading_kp * wrap_angle(to_goal.theta_radians))
+                .clamp(-config.max_angular_speed_radians_per_second, config.max_angular_speed_radians_per_second);
+            return (Twist2d::new(0.0, 0.0, omega), goal.clone());
+        }
+
+        let lookahead = (current_speed.abs() * config.lookahead_time_seconds).clamp(config.min_lookahead_meters, config.max_lookahead_meters);
+        // a path that's just the goal has no segments to make progress along
+        let progress = if self.waypoints.len() < 2 { 0.0 } else { self.get_path_progress(current_position) };
+        let target_pose = self.lookahead_point(current_position, progress, lookahead);
+        let err = -current_position.clone() + target_pose.clone();
+        let angle_err = err.y_meters.atan2(err.x_meters);
+        if angle_err.abs() > config.rotate_in_place_angle_radians {
+            let omega = (config.heading_kp * angle_err).clamp(-config.max_angular_speed_radians_per_second, config.max_angular_speed_radians_per_second);
+            return (Twist2d::new(0.0, 0.0, omega), target_pose);
+        }
+
+        // curvature of the arc that goes through the lookahead point and is tangent to the robot's heading
+        let curvature = 2.0 * err.y_meters / (err.x_meters * err.x_meters + err.y_meters * err.y_meters);
+        let mut speed = config.max_speed_meters_per_second
+            .min((config.max_lateral_acceleration_meters_per_second_squared / curvature.abs()).sqrt())
+            .min((2.0 * config.max_deceleration_meters_per_second_squared * self.remaining_distance(progress)).sqrt())
+            .max(config.min_speed_meters_per_second);
+        if (speed * curvature).abs() > config.max_angular_speed_radians_per_second {
+            speed = config.max_angular_speed_radians_per_second / curvature.abs();
+        }
+        (Twist2d::new(speed, 0.0, speed * curvature), target_pose)
+    }
+
+    /// the first point after progress where the path leaves the circle of radius lookahead around the robot, or the end of the path
+    fn lookahead_point(&self, current_position: &Transform2d, progress: f64, lookahead: f64) -> Transform2d {
+        if self.waypoints.len() < 2 {
+            return self.waypoints.last().unwrap().clone();
+        }
+        let first_segment = (progress.floor() as usize).min(self.waypoints.len() - 2);
+        for i in first_segment..self.waypoints.len() - 1 {
+            let (a, b) = (&self.waypoints[i], &self.waypoints[i + 1]);
+            let (dx, dy) = (b.x_meters - a.x_meters, b.y_meters - a.y_meters);
+            let (fx, fy) = (a.x_meters - current_position.x_meters, a.y_meters - current_position.y_meters);
+            // |a + t * (b - a) - robot| = lookahead, the larger root is where the path leaves the circle
+            let qa = dx * dx + dy * dy;
+            let qb = 2.0 * (fx * dx + fy * dy);
+            let qc = fx * fx + fy * fy - lookahead * lookahead;
+            let discriminant = qb * qb - 4.0 * qa * qc;
+            if qa == 0.0 || discriminant < 0.0 {
+                continue;
+            }
+            let t = (-qb + discriminant.sqrt()) / (2.0 * qa);
+            let min_t = if i == first_segment { progress - i as f64 } else { 0.0 };
+            if (min_t..=1.0).contains(&t) {
+                return self.get_path_at_time(i as f64 + t);
+            }
+        }
+        self.waypoints.last().unwrap().clone()
+    }
+
+    /// distance along the path from progress to the end
+    pub fn remaining_distance(&self, progress: f64) -> f64 {
+        if self.waypoints.len() < 2 {
+            return 0.0;
+        }
+        let segment_length = |i: usize| {
+            let (a, b) = (&self.waypoints[i], &self.waypoints[i + 1]);
+            (b.x_meters - a.x_meters).hypot(b.y_meters - a.y_meters)
         };
-        (cmd_vel, target_pose)
+        let current = (progress.floor() as usize).min(self.waypoints.len() - 2);
+        (1.0 - (progress - current as f64)) * segment_length(current) + (current + 1..self.waypoints.len() - 1).map(segment_length).sum::<f64>()
     }
 
     pub fn get_path_at_time(&self, t: f64) -> Transform2d {
@@ -41,8 +138,9 @@ impl Path {
         let waypoint_2 = &self.waypoints[t.floor() as usize + 1];
         let x = waypoint_1.x_meters + (waypoint_2.x_meters - waypoint_1.x_meters) * t.fract();
         let y = waypoint_1.y_meters + (waypoint_2.y_meters - waypoint_1.y_meters) * t.fract();
+        // the short way around, going from 3.1 to -3.1 shouldn't spin almost a full turn
         let theta = waypoint_1.theta_radians
-            + (waypoint_2.theta_radians - waypoint_1.theta_radians) * t.fract();
+            + wrap_angle(waypoint_2.theta_radians - waypoint_1.theta_radians) * t.fract();
         Transform2d::new(x, y, theta)
     }
     /// 0.0 is the starting waypoint position, 1.0 is the second waypoint, 2.0 is the third, etc. 1.5 is halfway between the second and third waypoints.
@@ -112,4 +210,70 @@ fn test_projection() {
         path.get_path_progress(&Transform2d::new(123123.123, 1.0, 2.0)),
         2.0
     );
-}
\ No newline at end of file
+}
+#[test]
+fn test_get_path_at_time_wraps_theta() {
+    let path = Path { waypoints: vec![Transform2d::new(0.0, 0.0, 3.0), Transform2d::new(1.0, 0.0, -3.0)] };
+    let halfway = path.get_path_at_time(0.5);
+    assert!((halfway.x_meters - 0.5).abs() < 1e-9);
+    assert!((wrap_angle(halfway.theta_radians).abs() - PI).abs() < 1e-9);
+}
+
+#[cfg(test)]
+fn simulate_pure_pursuit(path: &Path, start: Transform2d, config: &PurePursuitConfig, steps: usize) -> (Transform2d, f64) {
+    // perfect unicycle at 50hz, returns where it ended up and the furthest it ever got from the path
+    let mut pose = start;
+    let mut speed = 0.0;
+    let mut max_cross_track_error: f64 = 0.0;
+    for _ in 0..steps {
+        let (cmd, _) = path.pure_pursuit(&pose, speed, config);
+        speed = cmd.dx;
+        pose += Transform2d::from(cmd * 0.02);
+        let progress = path.get_path_progress(&pose);
+        let closest = path.get_path_at_time(progress);
+        max_cross_track_error = max_cross_track_error.max((closest.x_meters - pose.x_meters).hypot(closest.y_meters - pose.y_meters));
+    }
+    (pose, max_cross_track_error)
+}
+
+#[test]
+fn test_pure_pursuit_follows_corner() {
+    let path = Path { waypoints: vec![Transform2d::new(0.0, 0.0, 0.0), Transform2d::new(1.5, 0.0, 0.0), Transform2d::new(1.5, 1.5, PI / 2.0)] };
+    let config = PurePursuitConfig::default();
+    let (end, max_error) = simulate_pure_pursuit(&path, Transform2d::new(0.0, 0.1, 0.0), &config, 1000);
+    let err = -end + path.waypoints[2].clone();
+    assert!(err.norm() < config.arrival_distance_meters, "{:?}", err);
+    assert!(err.theta_radians.abs() < 0.05, "{:?}", err);
+    // corner cutting is bounded by the lookahead
+    assert!(max_error < 0.2, "{}", max_error);
+}
+
+#[test]
+fn test_pure_pursuit_speed_limits() {
+    let config = PurePursuitConfig::default();
+    let straight = Path { waypoints: vec![Transform2d::new(0.0, 0.0, 0.0), Transform2d::new(5.0, 0.0, 0.0)] };
+    let (cmd, target) = straight.pure_pursuit(&Transform2d::ZERO, 0.0, &config);
+    assert!((target.x_meters - config.min_lookahead_meters).abs() < 1e-9);
+    assert_eq!(cmd.dx, config.max_speed_meters_per_second);
+    // faster means looking further ahead
+    let (_, target) = straight.pure_pursuit(&Transform2d::ZERO, 0.3, &config);
+    assert!((target.x_meters - 0.45).abs() < 1e-9);
+    // slows down near the end
+    let (cmd, _) = straight.pure_pursuit(&Transform2d::new(4.98, 0.0, 0.0), 0.3, &config);
+    assert!(cmd.dx < config.max_speed_meters_per_second / 2.0);
+    // and in tight turns
+    let (cmd, _) = straight.pure_pursuit(&Transform2d::new(1.0, 0.1, 0.0), 0.0, &config);
+    assert!(cmd.dx < config.max_speed_meters_per_second);
+    assert!(cmd.dtheta < 0.0);
+    // the path is behind us, turn around first
+    let (cmd, _) = straight.pure_pursuit(&Transform2d::new(1.0, 0.0, PI), 0.0, &config);
+    assert_eq!(cmd.dx, 0.0);
+    assert!(cmd.dtheta.abs() > 0.0);
+
+    // just the goal, which is all there is to aim at
+    let goal = Path { waypoints: vec![Transform2d::new(1.0, 0.0, 0.0)] };
+    assert_eq!(goal.remaining_distance(0.0), 0.0);
+    let (cmd, target) = goal.pure_pursuit(&Transform2d::ZERO, 0.0, &config);
+    assert_eq!(target, goal.waypoints[0]);
+    assert!(cmd.dx > 0.0);
+}
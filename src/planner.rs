@@ -0,0 +1,382 @@
+//! global path planning: theta* (any-angle a*) over the costmap, staying out of inflated cells and away from walls when it's cheap to.
+
+use std::{cmp::Ordering, collections::BinaryHeap};
+
+use nalgebra::Vector2;
+use serde::Serialize;
+
+use crate::{
+    costmap::{Costmap, INSCRIBED_COST, LETHAL_COST, NO_INFORMATION},
+    geometry::Transform2d,
+    occupancy_grid::bresenham,
+    paths::Path,
+    spline::Spline,
+};
+
+/// waypoint spacing of smoothed paths, so the curves stay curves
+const SMOOTH_PATH_SPACING_METERS: f64 = 0.1;
+
+#[derive(Debug, Clone)]
+pub struct PlannerParams {
+    /// how much longer a path can get to stay out of the decaying cost around obstacles. 0 only cares about length.
+    pub cost_weight: f64,
+    /// long straight segments get split up so that path progress means roughly the same everywhere
+    pub max_waypoint_spacing_meters: f64,
+    /// round off the corners of the path with a spline, unless that would hit something
+    pub smooth: bool,
+    /// when smoothing, a curve straight from the start heading into the goal heading replaces the planned path
+    /// if it doesn't hit anything and is at most this many times as long
+    pub max_heading_curve_detour: f64,
+}
+
+impl Default for PlannerParams {
+    fn default() -> Self {
+        Self {
+            cost_weight: 0.5,
+            max_waypoint_spacing_meters: 0.25,
+            smooth: true,
+            max_heading_curve_detour: 1.3,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub enum PlannerError {
+    /// the goal is inside an obstacle or too close to one for the robot to fit
+    GoalInObstacle,
+    /// there's no collision free way to the goal (or it's outside of the map)
+    GoalUnreachable,
+}
+
+/// costmap reduced to what the planner cares about: cells the robot's center can't be in, and how much the rest cost
+pub struct InflatedGrid {
+    origin: Vector2<f64>,
+    resolution_meters: f64,
+    width: usize,
+    height: usize,
+    /// actually occupied cells
+    lethal: Vec<bool>,
+    /// cells closer than the inflation radius to a lethal cell (including the lethal cells)
+    inflated: Vec<bool>,
+    /// extra cost per meter of driving through a cell, 0 far from obstacles
+    penalty: Vec<f64>,
+    inflation_radius_meters: f64,
+}
+
+impl InflatedGrid {
+    pub fn new(costmap: &Costmap, params: &PlannerParams) -> Self {
+        let (width, height) = (costmap.width(), costmap.height());
+        let costs = (0..height as i64).flat_map(|y| (0..width as i64).map(move |x| (x, y))).map(|(x, y)| costmap.cost(x, y)).collect::<Vec<_>>();
+        Self {
+            origin: Vector2::new(costmap.config.origin_x_meters, costmap.config.origin_y_meters),
+            resolution_meters: costmap.config.resolution_meters,
+            width,
+            height,
+            lethal: costs.iter().map(|cost| *cost == LETHAL_COST).collect(),
+            inflated: costs.iter().map(|cost| *cost == LETHAL_COST || *cost == INSCRIBED_COST).collect(),
+            penalty: costs
+                .iter()
+                .map(|cost| if *cost >= INSCRIBED_COST || *cost == NO_INFORMATION { 0.0 } else { params.cost_weight * *cost as f64 / (INSCRIBED_COST - 1) as f64 })
+                .collect(),
+            inflation_radius_meters: costmap.params.inscribed_radius_meters(),
+        }
+    }
+
+    fn cell_of(&self, point: &Vector2<f64>) -> Option<(i64, i64)> {
+        let cell = ((point - self.origin) / self.resolution_meters).map(|coordinate| coordinate.floor() as i64);
+        self.index(cell[0], cell[1]).map(|_| (cell[0], cell[1]))
+    }
+
+    fn cell_center(&self, x: i64, y: i64) -> Vector2<f64> {
+        self.origin + Vector2::new(x as f64 + 0.5, y as f64 + 0.5) * self.resolution_meters
+    }
+
+    fn index(&self, x: i64, y: i64) -> Option<usize> {
+        if x < 0 || y < 0 || x >= self.width as i64 || y >= self.height as i64 {
+            None
+        } else {
+            Some(y as usize * self.width + x as usize)
+        }
+    }
+
+    /// the robot often ends up closer to a wall than the inflation radius (people drive it there), so it's allowed to drive through
+    /// inflated cells around where it starts as long as it doesn't hit anything
+    fn traversable(&self, x: i64, y: i64, start: (i64, i64)) -> bool {
+        let escape_radius_cells = self.inflation_radius_meters / self.resolution_meters;
+        self.index(x, y).is_some_and(|index| {
+            !self.inflated[index] || (!self.lethal[index] && (((x - start.0).pow(2) + (y - start.1).pow(2)) as f64).sqrt() <= escape_radius_cells)
+        })
+    }
+}
+
+#[derive(PartialEq)]
+struct OpenCell {
+    f: f64,
+    index: usize,
+}
+
+impl Eq for OpenCell {}
+
+impl Ord for OpenCell {
+    fn cmp(&self, other: &Self) -> Ordering {
+        // reversed so the binary heap pops the lowest f first
+        other.f.total_cmp(&self.f)
+    }
+}
+
+impl PartialOrd for OpenCell {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// plans a collision free path from start to goal. the path starts at start and ends at goal, including its heading.
+pub fn plan_path(grid: &InflatedGrid, start: &Transform2d, goal: &Transform2d, params: &PlannerParams) -> Result<Path, PlannerError> {
+    let goal_point = Vector2::new(goal.x_meters, goal.y_meters);
+    let start_point = Vector2::new(start.x_meters, start.y_meters);
+    let (goal_x, goal_y) = grid.cell_of(&goal_point).ok_or(PlannerError::GoalUnreachable)?;
+    if grid.inflated[grid.index(goal_x, goal_y).unwrap()] {
+        return Err(PlannerError::GoalInObstacle);
+    }
+    let (start_x, start_y) = grid.cell_of(&start_point).ok_or(PlannerError::GoalUnreachable)?;
+    let traversable = |x: i64, y: i64| grid.traversable(x, y, (start_x, start_y));
+    let distance = |a: (i64, i64), b: (i64, i64)| (((a.0 - b.0).pow(2) + (a.1 - b.1).pow(2)) as f64).sqrt();
+    // length of a straight line weighted by the average penalty of the cells it goes through, none if it hits something
+    let line_cost = |a: (i64, i64), b: (i64, i64)| {
+        let cells = bresenham(a, b);
+        let mut penalty = 0.0;
+        for (x, y) in &cells {
+            if !traversable(*x, *y) {
+                return None;
+            }
+            penalty += grid.penalty[grid.index(*x, *y).unwrap()];
+        }
+        Some(distance(a, b) * (1.0 + penalty / cells.len() as f64))
+    };
+    let cell = |index: usize| ((index % grid.width) as i64, (index / grid.width) as i64);
+
+    let start_index = grid.index(start_x, start_y).unwrap();
+    let goal_index = grid.index(goal_x, goal_y).unwrap();
+    let mut g = vec![f64::INFINITY; grid.lethal.len()];
+    let mut parent = vec![usize::MAX; grid.lethal.len()];
+    let mut closed = vec![false; grid.lethal.len()];
+    let mut open = BinaryHeap::new();
+    g[start_index] = 0.0;
+    parent[start_index] = start_index;
+    open.push(OpenCell { f: distance((start_x, start_y), (goal_x, goal_y)), index: start_index });
+    while let Some(OpenCell { index, .. }) = open.pop() {
+        if closed[index] {
+            continue;
+        }
+        closed[index] = true;
+        if index == goal_index {
+            break;
+        }
+        let (x, y) = cell(index);
+        for (dx, dy) in [(1, 0), (-1, 0), (0, 1), (0, -1), (1, 1), (1, -1), (-1, 1), (-1, -1)] {
+            let (nx, ny) = (x + dx, y + dy);
+            // diagonal moves can't cut corners
+            if !traversable(nx, ny) || (dx != 0 && dy != 0 && !(traversable(x + dx, y) && traversable(x, y + dy))) {
+                continue;
+            }
+            let neighbour = grid.index(nx, ny).unwrap();
+            if closed[neighbour] {
+                continue;
+            }
+            // theta*: connect straight to our parent if nothing is in the way, otherwise it's a regular a* step
+            let grandparent = parent[index];
+            let (new_parent, new_g) = match line_cost(cell(grandparent), (nx, ny)) {
+                Some(cost) => (grandparent, g[grandparent] + cost),
+                None => (index, g[index] + distance((x, y), (nx, ny)) * (1.0 + grid.penalty[neighbour])),
+            };
+            if new_g < g[neighbour] {
+                g[neighbour] = new_g;
+                parent[neighbour] = new_parent;
+                open.push(OpenCell { f: new_g + distance((nx, ny), (goal_x, goal_y)), index: neighbour });
+            }
+        }
+    }
+    if !closed[goal_index] {
+        return Err(PlannerError::GoalUnreachable);
+    }
+
+    let mut corners = vec![goal_index];
+    while *corners.last().unwrap() != start_index {
+        corners.push(parent[*corners.last().unwrap()]);
+    }
+    corners.reverse();
+    // the first and last corners are the cells of the start and goal, which get replaced by the exact start and goal points.
+    // if they're in the same cell there's just the one corner and nothing in between.
+    let mut points = vec![start_point];
+    points.extend(corners.get(1..corners.len() - 1).unwrap_or_default().iter().map(|index| {
+        let (x, y) = cell(*index);
+        grid.cell_center(x, y)
+    }));
+    points.push(goal_point);
+    if params.smooth {
+        if let Some(path) = heading_curve(grid, &points, start, goal, params).or_else(|| smooth_path(grid, &points, start, goal, params)) {
+            return Ok(path);
+        }
+    }
+    Ok(points_to_path(&points, start, goal, params.max_waypoint_spacing_meters))
+}
+
+/// whether any waypoint is somewhere the robot can't drive, starting from start_cell
+fn collides(grid: &InflatedGrid, path: &Path, start_cell: (i64, i64)) -> bool {
+    path.waypoints.iter().any(|waypoint| {
+        grid.cell_of(&Vector2::new(waypoint.x_meters, waypoint.y_meters)).is_none_or(|(x, y)| !grid.traversable(x, y, start_cell))
+    })
+}
+
+/// a quintic hermite from start to goal, so the robot drives off the way it's facing and arrives facing the goal heading
+/// instead of turning in place at both ends. only if it doesn't hit anything and isn't much longer than the planned path.
+fn heading_curve(grid: &InflatedGrid, points: &[Vector2<f64>], start: &Transform2d, goal: &Transform2d, params: &PlannerParams) -> Option<Path> {
+    let planned_length = points.windows(2).map(|pair| (pair[1] - pair[0]).norm()).sum::<f64>();
+    let spline = Spline::quintic_hermite(&[start.clone(), goal.clone()]);
+    if spline.length() > params.max_heading_curve_detour * planned_length {
+        return None;
+    }
+    let mut path = spline.to_path(params.max_waypoint_spacing_meters.min(SMOOTH_PATH_SPACING_METERS));
+    if collides(grid, &path, grid.cell_of(&points[0])?) {
+        return None;
+    }
+    *path.waypoints.first_mut().unwrap() = start.clone();
+    *path.waypoints.last_mut().unwrap() = goal.clone();
+    Some(path)
+}
+
+/// a clamped cubic spline through the corners of the path, if there are any corners and the spline doesn't hit anything
+fn smooth_path(grid: &InflatedGrid, points: &[Vector2<f64>], start: &Transform2d, goal: &Transform2d, params: &PlannerParams) -> Option<Path> {
+    if points.len() < 3 {
+        return None;
+    }
+    let corners = Path { waypoints: points.iter().map(|point| Transform2d::new(point[0], point[1], 0.0)).collect() };
+    let spline = Spline::through_path(&corners);
+    let mut path = spline.to_path(params.max_waypoint_spacing_meters.min(SMOOTH_PATH_SPACING_METERS));
+    if collides(grid, &path, grid.cell_of(&points[0])?) {
+        return None;
+    }
+    // the robot turns to the goal heading once it's there
+    *path.waypoints.first_mut().unwrap() = start.clone();
+    *path.waypoints.last_mut().unwrap() = goal.clone();
+    Some(path)
+}
+
+/// straight lines between the points, split up so no two waypoints are further apart than max_spacing_meters.
+/// waypoints face along the path, except for the first and last one which keep the start and goal headings.
+fn points_to_path(points: &[Vector2<f64>], start: &Transform2d, goal: &Transform2d, max_spacing_meters: f64) -> Path {
+    let mut waypoints = vec![start.clone()];
+    for segment in points.windows(2) {
+        let delta = segment[1] - segment[0];
+        let heading = delta[1].atan2(delta[0]);
+        let steps = (delta.norm() / max_spacing_meters).ceil().max(1.0) as usize;
+        for step in 1..=steps {
+            let point = segment[0] + delta * (step as f64 / steps as f64);
+            waypoints.push(Transform2d::new(point[0], point[1], heading));
+        }
+    }
+    *waypoints.last_mut().unwrap() = goal.clone();
+    Path { waypoints }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        costmap::CostmapParams,
+        drivetrain::XAVIERBOT_FOOTPRINT_RADIUS_METERS,
+        occupancy_grid::{OccupancyGrid, OccupancyGridConfig},
+        sim::{empty_costmap, room_walls, simulate_scan},
+    };
+
+    /// the room from the simulator, mapped from a few spots so every wall is seen
+    fn room_grid() -> InflatedGrid {
+        let walls = room_walls();
+        let mut grid = OccupancyGrid::new(OccupancyGridConfig { origin_x_meters: -5.0, origin_y_meters: -5.0, width_cells: 200, height_cells: 200, ..Default::default() });
+        for pose in [Transform2d::new(-2.0, -1.0, 0.0), Transform2d::new(0.0, 1.0, 0.0), Transform2d::new(2.0, -1.0, 0.0), Transform2d::new(0.5, -1.2, 0.0)] {
+            grid.integrate_scan(&pose, &simulate_scan(&walls, &pose, 720, 12.0));
+        }
+        InflatedGrid::new(&Costmap::from_grid(&grid, CostmapParams::default()), &PlannerParams::default())
+    }
+
+    fn distance_to_pillar(point: &Vector2<f64>) -> f64 {
+        let dx = (-0.9 - point[0]).max(point[0] + 0.6).max(0.0);
+        let dy = (-0.6 - point[1]).max(point[1] + 0.3).max(0.0);
+        dx.hypot(dy)
+    }
+
+    #[test]
+    fn test_plan_around_pillar() {
+        let grid = room_grid();
+        let params = PlannerParams::default();
+        let start = Transform2d::new(-2.0, -0.45, 0.3);
+        let goal = Transform2d::new(0.5, -0.45, 1.0);
+        let path = plan_path(&grid, &start, &goal, &params).unwrap();
+        assert_eq!(path.waypoints.first(), Some(&start));
+        assert_eq!(path.waypoints.last(), Some(&goal));
+        let mut length = 0.0;
+        for segment in path.waypoints.windows(2) {
+            let a = Vector2::new(segment[0].x_meters, segment[0].y_meters);
+            let b = Vector2::new(segment[1].x_meters, segment[1].y_meters);
+            assert!((b - a).norm() <= params.max_waypoint_spacing_meters + 1e-9);
+            length += (b - a).norm();
+            for i in 0..=10 {
+                let point = a + (b - a) * (i as f64 / 10.0);
+                assert!(distance_to_pillar(&point) > XAVIERBOT_FOOTPRINT_RADIUS_METERS, "{:?}", point);
+            }
+        }
+        // a detour, but not a silly one
+        assert!(length > 2.5 && length < 3.5, "{}", length);
+        // and smooth, apart from turning to the start and goal headings
+        let inner = &path.waypoints[1..path.waypoints.len() - 1];
+        for pair in inner.windows(2) {
+            assert!(crate::geometry::wrap_angle(pair[1].theta_radians - pair[0].theta_radians).abs() < 0.5, "{:?}", pair);
+        }
+    }
+
+    #[test]
+    fn test_arrives_facing_the_goal_heading() {
+        let grid = room_grid();
+        let params = PlannerParams::default();
+        // nothing in the way, so it curves into the goal heading instead of turning in place there
+        let (start, goal) = (Transform2d::new(-2.0, 1.0, 0.0), Transform2d::new(0.0, 0.5, -0.5));
+        let path = plan_path(&grid, &start, &goal, &params).unwrap();
+        let before_goal = &path.waypoints[path.waypoints.len() - 2];
+        assert!(crate::geometry::wrap_angle(before_goal.theta_radians - goal.theta_radians).abs() < 0.2, "{:?}", before_goal);
+        assert!(crate::geometry::wrap_angle(path.waypoints[1].theta_radians - start.theta_radians).abs() < 0.2, "{:?}", path.waypoints[1]);
+        // facing away from the goal the curve would be a big loop, so it's the planned path
+        let behind = plan_path(&grid, &Transform2d::new(-2.0, 1.0, std::f64::consts::PI), &goal, &params).unwrap();
+        assert!(behind.waypoints[1].theta_radians.abs() < 0.5, "{:?}", behind.waypoints[1]);
+    }
+
+    #[test]
+    fn test_plan_errors() {
+        let grid = room_grid();
+        let params = PlannerParams::default();
+        let start = Transform2d::new(-2.0, -1.0, 0.0);
+        assert_eq!(plan_path(&grid, &start, &Transform2d::new(-0.75, -0.45, 0.0), &params).err(), Some(PlannerError::GoalInObstacle));
+        // free, but too close to the wall for the robot to fit
+        assert_eq!(plan_path(&grid, &start, &Transform2d::new(2.9, 0.0, 0.0), &params).err(), Some(PlannerError::GoalInObstacle));
+        // outside of the room
+        assert_eq!(plan_path(&grid, &start, &Transform2d::new(4.0, 0.0, 0.0), &params).err(), Some(PlannerError::GoalUnreachable));
+        assert_eq!(plan_path(&grid, &start, &Transform2d::new(40.0, 0.0, 0.0), &params).err(), Some(PlannerError::GoalUnreachable));
+    }
+
+    #[test]
+    fn test_start_next_to_wall() {
+        let grid = room_grid();
+        let start = Transform2d::new(-2.9, 0.0, 0.0);
+        let (x, y) = grid.cell_of(&Vector2::new(start.x_meters, start.y_meters)).unwrap();
+        assert!(grid.inflated[grid.index(x, y).unwrap()]);
+        assert!(plan_path(&grid, &start, &Transform2d::new(0.0, 1.0, 0.0), &PlannerParams::default()).is_ok());
+    }
+
+    #[test]
+    fn test_start_and_goal_in_the_same_cell() {
+        let params = PlannerParams::default();
+        let grid = InflatedGrid::new(&empty_costmap(), &params);
+        let (start, goal) = (Transform2d::new(0.01, 0.01, 0.0), Transform2d::new(0.02, 0.02, 1.0));
+        let path = plan_path(&grid, &start, &goal, &params).unwrap();
+        assert_eq!(path.waypoints, vec![start, goal]);
+    }
+}
@@ -1,13 +1,17 @@
 use std::{collections::BTreeMap, marker::PhantomData, time::Duration};
 
-use crate::{geometry::{Transform2d, Twist2d}, odometry::WheelOdometry, utils::{Interpolate, TimeInterpolatableBuffer}};
+use crate::{geometry::{wrap_angle, Transform2d, Twist2d}, odometry::WheelOdometry, utils::TimeInterpolatableBuffer};
 
 const BUFFER_SIZE: Duration = Duration::from_secs(2);
+/// how much we trust odometry (x meters, y meters, theta radians), same defaults as wpilib
+const DEFAULT_STATE_STD_DEVS: [f64; 3] = [0.02, 0.02, 0.01];
 
 pub struct PoseEstimator<T: WheelOdometry<U>, U> {
     odometry: T,
     odometry_buffer: TimeInterpolatableBuffer<Transform2d>,
     world_to_odom: BTreeMap<Duration, Transform2d>,
+    /// variances of the odometry in x, y, theta
+    state_variances: [f64; 3],
     _marker: PhantomData<U>,
 }
 
@@ -17,6 +21,7 @@ impl<T: WheelOdometry<U>, U> PoseEstimator<T, U> {
             odometry,
             odometry_buffer: TimeInterpolatableBuffer::new(BUFFER_SIZE),
             world_to_odom: BTreeMap::new(),
+            state_variances: DEFAULT_STATE_STD_DEVS.map(|std| std * std),
             _marker: PhantomData::<U>,
         };
         res.odometry_buffer.add_sample(time_since_program_start, res.odometry.get_pose().clone());
@@ -24,6 +29,14 @@ impl<T: WheelOdometry<U>, U> PoseEstimator<T, U> {
         res
     }
 
+    pub fn odometry(&self) -> &T {
+        &self.odometry
+    }
+
+    pub fn get_estimated_pose(&self) -> Transform2d {
+        self.world_to_odom.last_key_value().unwrap().1.clone() + self.odometry.get_pose().clone()
+    }
+
     pub fn update_odometry(&mut self, gyro_angle_radians: f64, wheel_positions: &U, time_since_program_start: Duration) {
         self.odometry.update(gyro_angle_radians, wheel_positions);
         self.odometry_buffer
@@ -50,8 +63,16 @@ impl<T: WheelOdometry<U>, U> PoseEstimator<T, U> {
         std_theta: f64,
     ) -> Result<(),()> {
         let world_to_estimated_pose = self.sample_at(timestamp)?;
-        let t = 0.1; // TODO actually do this statistically :)
-        let world_to_robot = Transform2d::interpolate(&world_to_estimated_pose, &world_to_vision_pose, t);
+        // steady state kalman gain for every axis like wpilib does, q is how much we trust odometry and r the measurement
+        let gains = [std_x, std_y, std_theta].iter().zip(self.state_variances).map(|(std, q)| {
+            let r = std * std;
+            if q == 0.0 { 0.0 } else { q / (q + (q * r).sqrt()) }
+        }).collect::<Vec<_>>();
+        let mut estimated_to_vision = -world_to_estimated_pose.clone() + world_to_vision_pose;
+        estimated_to_vision.theta_radians = wrap_angle(estimated_to_vision.theta_radians);
+        let twist = Twist2d::from(estimated_to_vision);
+        let scaled_twist = Twist2d::new(twist.dx * gains[0], twist.dy * gains[1], twist.dtheta * gains[2]);
+        let world_to_robot = world_to_estimated_pose + Transform2d::from(scaled_twist);
         let odom_to_robot = self.odometry_buffer.get_value(timestamp).ok_or(())?;
         let new_world_to_odom = world_to_robot + (-odom_to_robot);
         self.world_to_odom.insert(timestamp, new_world_to_odom);
@@ -64,3 +85,41 @@ impl<T: WheelOdometry<U>, U> PoseEstimator<T, U> {
         Ok(previous_world_to_odom.1.clone() + odom_at_time)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::odometry::{DifferentialDriveOdometry, DifferentialDriveWheelPositions};
+
+    fn estimator() -> PoseEstimator<DifferentialDriveOdometry, DifferentialDriveWheelPositions> {
+        let odometry = DifferentialDriveOdometry::new(0.3, 0.0, DifferentialDriveWheelPositions::ZERO);
+        PoseEstimator::new(Transform2d::ZERO, odometry, Duration::ZERO)
+    }
+
+    #[test]
+    fn test_vision_measurement_weighting() {
+        let mut trusted = estimator();
+        let mut distrusted = estimator();
+        let time = Duration::from_millis(20);
+        trusted.update_odometry(0.0, &DifferentialDriveWheelPositions::ZERO, time);
+        distrusted.update_odometry(0.0, &DifferentialDriveWheelPositions::ZERO, time);
+        let measurement = Transform2d::new(1.0, 0.0, 0.0);
+        trusted.add_vision_measurement(measurement.clone(), time, 0.001, 0.001, 0.001).unwrap();
+        distrusted.add_vision_measurement(measurement, time, 10.0, 10.0, 10.0).unwrap();
+        // a nearly perfect measurement is almost fully believed, a terrible one barely moves the pose
+        assert!(trusted.get_estimated_pose().x_meters > 0.8);
+        assert!(distrusted.get_estimated_pose().x_meters < 0.05);
+        assert!(distrusted.get_estimated_pose().x_meters > 0.0);
+    }
+
+    #[test]
+    fn test_vision_measurement_wraps_angle() {
+        let mut estimator = estimator();
+        let time = Duration::from_millis(20);
+        estimator.update_odometry(0.0, &DifferentialDriveWheelPositions::ZERO, time);
+        // 2pi - 0.1 is really just 0.1 radians clockwise, not almost a full turn
+        estimator.add_vision_measurement(Transform2d::new(0.0, 0.0, 2.0 * std::f64::consts::PI - 0.1), time, 0.01, 0.01, 0.01).unwrap();
+        let theta = estimator.get_estimated_pose().theta_radians;
+        assert!(theta < 0.0 && theta > -0.1);
+    }
+}
@@ -1,11 +1,14 @@
 use std::f64::consts::PI;
+use std::{collections::{BTreeMap, HashSet}, fs, io, path::Path};
 
 use nalgebra::{DMatrix, DVector, Matrix3, Vector3, Vector2};
 use nalgebra_sparse::{factorization::CscCholesky, CscMatrix};
+use serde::{Deserialize, Serialize};
 
 
-use crate::geometry::Transform2d;
-use crate::icp::icp_least_squares;
+use crate::geometry::{wrap_angle, Transform2d};
+use crate::kdtree::KdTree2d;
+use crate::submap::{Submap, SubmapParams};
 
 #[derive(Debug)]
 pub struct PoseGraphBackend {
@@ -33,7 +36,8 @@ impl PoseGraphBackend {
             self.edges.push(PoseGraphEdge {
                 i: self.nodes.len() / 3 - 1,
                 j: self.nodes.len() / 3,
-                i_to_j: prev_node_to_new.clone()
+                i_to_j: prev_node_to_new.clone(),
+                information: Matrix3::identity()
             });
             let world_to_prev = Transform2d::new(self.nodes[self.nodes.len() - 3], self.nodes[self.nodes.len() - 2], self.nodes[self.nodes.len() - 1]);
             let world_to_new = world_to_prev + prev_node_to_new;
@@ -43,8 +47,54 @@ impl PoseGraphBackend {
             self.nodes = self.nodes.push(world_to_new.theta_radians);
         }
     }
-    pub fn add_loop_closure(&mut self, i: usize, j: usize, i_to_j: Transform2d) {
-        self.edges.push(PoseGraphEdge { i, j, i_to_j });
+    pub fn num_nodes(&self) -> usize {
+        self.nodes.len() / 3
+    }
+    pub fn pose(&self, i: usize) -> Transform2d {
+        Transform2d::new(self.nodes[3 * i], self.nodes[3 * i + 1], self.nodes[3 * i + 2])
+    }
+    /// removes node k and replaces its edges with edges between every pair of its neighbours, composing the transforms and
+    /// propagating the covariances so the rest of the graph keeps (approximately) the same constraints. nodes after k shift down by one.
+    pub fn marginalize_node(&mut self, k: usize) {
+        assert!(k < self.num_nodes());
+        // (neighbour, k_to_neighbour, covariance) for every edge touching k
+        let mut neighbours = Vec::new();
+        for edge in self.edges.iter().filter(|edge| edge.i == k || edge.j == k) {
+            let covariance = edge.information.try_inverse().unwrap_or(Matrix3::identity());
+            if edge.i == k {
+                neighbours.push((edge.j, edge.i_to_j.clone(), covariance));
+            } else {
+                let (j_to_i, covariance) = invert_with_covariance(&edge.i_to_j, &covariance);
+                neighbours.push((edge.i, j_to_i, covariance));
+            }
+        }
+        self.edges.retain(|edge| edge.i != k && edge.j != k);
+        for a in 0..neighbours.len() {
+            for b in (a + 1)..neighbours.len() {
+                let (node_a, k_to_a, covariance_a) = &neighbours[a];
+                let (node_b, k_to_b, covariance_b) = &neighbours[b];
+                if node_a == node_b {
+                    continue;
+                }
+                let (a_to_k, covariance_a) = invert_with_covariance(k_to_a, covariance_a);
+                let (a_to_b, covariance) = compose_with_covariance(&a_to_k, &covariance_a, k_to_b, covariance_b);
+                let Some(information) = covariance.try_inverse() else { continue };
+                self.edges.push(PoseGraphEdge { i: *node_a, j: *node_b, i_to_j: a_to_b, information });
+            }
+        }
+        for edge in &mut self.edges {
+            if edge.i > k { edge.i -= 1; }
+            if edge.j > k { edge.j -= 1; }
+        }
+        self.nodes = self.nodes.clone().remove_rows(3 * k, 3);
+        self.dirty = true;
+    }
+    pub fn poses(&self) -> Vec<Transform2d> {
+        self.nodes.as_slice().chunks(3).map(|node| Transform2d::new(node[0], node[1], node[2])).collect()
+    }
+    /// information is the inverse of the covariance of i_to_j
+    pub fn add_loop_closure(&mut self, i: usize, j: usize, i_to_j: Transform2d, information: Matrix3<f64>) {
+        self.edges.push(PoseGraphEdge { i, j, i_to_j, information });
         self.dirty = true;
     }
     pub fn optimize(&mut self, max_iterations: usize) {
@@ -52,55 +102,24 @@ impl PoseGraphBackend {
             let mut b: DVector<f64> = DVector::zeros(self.nodes.nrows());
             let mut h: DMatrix<f64> = DMatrix::zeros(self.nodes.nrows(), self.nodes.nrows()); // TODO bench constructing using a sparse representation... would be better memory but could take more compute
             for edge in &self.edges {
-                let x_i = self.nodes.get(edge.i * 3).unwrap();
-                let y_i = self.nodes.get(edge.i * 3 + 1).unwrap();
-                let theta_i = self.nodes.get(edge.i * 3 + 2).unwrap();
-                let x_j = self.nodes.get(edge.j * 3).unwrap();
-                let y_j = self.nodes.get(edge.j * 3 + 1).unwrap();
-                let theta_j = self.nodes.get(edge.j * 3 + 2).unwrap();
-                
-                let x_ij = edge.i_to_j.x_meters;
-                let y_ij = edge.i_to_j.y_meters;
-                let theta_ij = edge.i_to_j.theta_radians;
-                
-                // compute the error function TODO add test coverage
-                let unrotated_x = theta_i.cos() * (x_j-x_i) + theta_i.sin() * (y_j-y_i) - x_ij;
-                let unrotated_y = -theta_i.sin() * (x_j-x_i) + theta_i.cos() * (y_j-y_i) - y_ij;
-                let e_ij = Vector3::new(
-                    theta_ij.cos() * unrotated_x + theta_ij.sin() * unrotated_y,
-                    -theta_ij.sin() * unrotated_x + theta_ij.cos() * unrotated_y,
-                    theta_j - theta_i - theta_ij
-                );
-
-                // compute the jacobians of the error function TODO add test coverage
-                let unrotated_x = theta_i.cos() * (y_j - y_i) - theta_i.sin() * (x_j - x_i);
-                let unrotated_y = -(theta_i).cos() * (x_j - x_i) - (theta_i).sin() * (y_j - y_i);
-                let jacobian_e_wrt_i = Matrix3::from_row_slice(&[
-                    -(theta_i + theta_ij).cos(), -(theta_i + theta_ij).sin(), theta_ij.cos() * unrotated_x + theta_ij.sin() * unrotated_y,
-                    (theta_i + theta_ij).sin(), -(theta_i + theta_ij).cos(), -theta_ij.sin() * unrotated_x + theta_ij.cos() * unrotated_y,
-                    0.0, 0.0, -1.0
-                ]);
-                let jacobian_e_wrt_j = Matrix3::from_row_slice(&[
-                    (theta_i + theta_ij).cos(), (theta_i + theta_ij).sin(), 0.0,
-                    -(theta_i + theta_ij).sin(), (theta_i + theta_ij).cos(), 0.0,
-                    0.0, 0.0, 1.0
-                ]);
+                let (e_ij, jacobian_e_wrt_i, jacobian_e_wrt_j) = linearize_edge(&self.nodes, edge);
 
                 // compute the contributions of this constraint to the linear system
+                let omega = &edge.information;
                 let mut h_ii = h.view_mut((edge.i*3, edge.i*3), (3, 3));
-                h_ii += jacobian_e_wrt_i.transpose() * jacobian_e_wrt_i;
+                h_ii += jacobian_e_wrt_i.transpose() * omega * jacobian_e_wrt_i;
                 let mut h_ij = h.view_mut((edge.i*3, edge.j*3), (3, 3));
-                h_ij += jacobian_e_wrt_i.transpose() * jacobian_e_wrt_j;
+                h_ij += jacobian_e_wrt_i.transpose() * omega * jacobian_e_wrt_j;
                 let mut h_ji = h.view_mut((edge.j*3, edge.i*3), (3, 3));
-                h_ji += jacobian_e_wrt_j.transpose() * jacobian_e_wrt_i;
+                h_ji += jacobian_e_wrt_j.transpose() * omega * jacobian_e_wrt_i;
                 let mut h_jj = h.view_mut((edge.j*3, edge.j*3), (3, 3));
-                h_jj += jacobian_e_wrt_j.transpose() * jacobian_e_wrt_j;
+                h_jj += jacobian_e_wrt_j.transpose() * omega * jacobian_e_wrt_j;
                 
                 // compute the coefficient vector
                 let mut b_i = b.rows_mut(edge.i * 3, 3);
-                b_i += jacobian_e_wrt_i.transpose() * e_ij;
+                b_i += jacobian_e_wrt_i.transpose() * omega * e_ij;
                 let mut b_j = b.rows_mut(edge.j * 3, 3);
-                b_j += jacobian_e_wrt_j.transpose() * e_ij;
+                b_j += jacobian_e_wrt_j.transpose() * omega * e_ij;
             }
             let mut h_11 = h.view_mut((0, 0), (3, 3));
             h_11 += Matrix3::identity();
@@ -115,13 +134,285 @@ impl PoseGraphBackend {
         }
         self.dirty = false;
     }
+    /// sum of the squared mahalanobis errors of every edge, what optimize minimizes
+    pub fn chi2(&self) -> f64 {
+        self.edges.iter().map(|edge| {
+            let (e_ij, _, _) = linearize_edge(&self.nodes, edge);
+            (e_ij.transpose() * edge.information * e_ij)[0]
+        }).sum()
+    }
+}
+
+/// error of an edge given the current node poses, and its jacobians with respect to node i and node j
+fn linearize_edge(nodes: &DVector<f64>, edge: &PoseGraphEdge) -> (Vector3<f64>, Matrix3<f64>, Matrix3<f64>) {
+    let x_i = nodes[edge.i * 3];
+    let y_i = nodes[edge.i * 3 + 1];
+    let theta_i = nodes[edge.i * 3 + 2];
+    let x_j = nodes[edge.j * 3];
+    let y_j = nodes[edge.j * 3 + 1];
+    let theta_j = nodes[edge.j * 3 + 2];
+
+    let x_ij = edge.i_to_j.x_meters;
+    let y_ij = edge.i_to_j.y_meters;
+    let theta_ij = edge.i_to_j.theta_radians;
+
+    // compute the error function
+    let unrotated_x = theta_i.cos() * (x_j-x_i) + theta_i.sin() * (y_j-y_i) - x_ij;
+    let unrotated_y = -theta_i.sin() * (x_j-x_i) + theta_i.cos() * (y_j-y_i) - y_ij;
+    let e_ij = Vector3::new(
+        theta_ij.cos() * unrotated_x + theta_ij.sin() * unrotated_y,
+        -theta_ij.sin() * unrotated_x + theta_ij.cos() * unrotated_y,
+        // nodes and edges from other tools can be off by whole turns
+        wrap_angle(theta_j - theta_i - theta_ij)
+    );
+
+    // compute the jacobians of the error function
+    let unrotated_x = theta_i.cos() * (y_j - y_i) - theta_i.sin() * (x_j - x_i);
+    let unrotated_y = -(theta_i).cos() * (x_j - x_i) - (theta_i).sin() * (y_j - y_i);
+    let jacobian_e_wrt_i = Matrix3::from_row_slice(&[
+        -(theta_i + theta_ij).cos(), -(theta_i + theta_ij).sin(), theta_ij.cos() * unrotated_x + theta_ij.sin() * unrotated_y,
+        (theta_i + theta_ij).sin(), -(theta_i + theta_ij).cos(), -theta_ij.sin() * unrotated_x + theta_ij.cos() * unrotated_y,
+        0.0, 0.0, -1.0
+    ]);
+    let jacobian_e_wrt_j = Matrix3::from_row_slice(&[
+        (theta_i + theta_ij).cos(), (theta_i + theta_ij).sin(), 0.0,
+        -(theta_i + theta_ij).sin(), (theta_i + theta_ij).cos(), 0.0,
+        0.0, 0.0, 1.0
+    ]);
+    (e_ij, jacobian_e_wrt_i, jacobian_e_wrt_j)
 }
 
 #[derive(Debug)]
 struct PoseGraphEdge {
     i: usize,
     j: usize,
-    i_to_j: Transform2d
+    i_to_j: Transform2d,
+    information: Matrix3<f64>
+}
+
+/// text formats other slam tools use for 2d pose graphs
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum GraphFileFormat {
+    /// VERTEX_SE2 id x y theta and EDGE_SE2 i j x y theta followed by the upper triangle of the information matrix row by row
+    G2o,
+    /// VERTEX2 and EDGE2, same thing but the information matrix is written as xx xy yy tt xt yt
+    Toro,
+}
+
+impl GraphFileFormat {
+    /// .g2o, or .graph like the toro datasets use
+    pub fn from_path(path: &Path) -> Option<Self> {
+        match path.extension()?.to_str()? {
+            "g2o" => Some(Self::G2o),
+            "graph" | "toro" => Some(Self::Toro),
+            _ => None,
+        }
+    }
+}
+
+impl PoseGraphBackend {
+    pub fn export(&self, format: GraphFileFormat) -> String {
+        let mut res = String::new();
+        for (i, pose) in self.poses().iter().enumerate() {
+            let tag = match format { GraphFileFormat::G2o => "VERTEX_SE2", GraphFileFormat::Toro => "VERTEX2" };
+            res += &format!("{} {} {} {} {}\n", tag, i, pose.x_meters, pose.y_meters, pose.theta_radians);
+        }
+        for edge in &self.edges {
+            let m = &edge.information;
+            let (tag, information) = match format {
+                GraphFileFormat::G2o => ("EDGE_SE2", [m[(0, 0)], m[(0, 1)], m[(0, 2)], m[(1, 1)], m[(1, 2)], m[(2, 2)]]),
+                GraphFileFormat::Toro => ("EDGE2", [m[(0, 0)], m[(0, 1)], m[(1, 1)], m[(2, 2)], m[(0, 2)], m[(1, 2)]]),
+            };
+            res += &format!("{} {} {} {} {} {}", tag, edge.i, edge.j, edge.i_to_j.x_meters, edge.i_to_j.y_meters, edge.i_to_j.theta_radians);
+            for value in information {
+                res += &format!(" {}", value);
+            }
+            res += "\n";
+        }
+        res
+    }
+
+    /// vertex ids don't have to be contiguous, nodes end up sorted by id and the smallest one anchors the graph
+    pub fn import(contents: &str, format: GraphFileFormat) -> io::Result<Self> {
+        let invalid = |line_number: usize, message: &str| io::Error::new(io::ErrorKind::InvalidData, format!("line {}: {}", line_number + 1, message));
+        let (vertex_tag, edge_tag) = match format { GraphFileFormat::G2o => ("VERTEX_SE2", "EDGE_SE2"), GraphFileFormat::Toro => ("VERTEX2", "EDGE2") };
+        let mut vertices = BTreeMap::new();
+        // (line number, i id, j id, i_to_j, information)
+        let mut edges = Vec::new();
+        for (line_number, line) in contents.lines().enumerate() {
+            let mut tokens = line.split_whitespace();
+            let Some(tag) = tokens.next() else { continue };
+            if tag.starts_with('#') || tag == "FIX" {
+                // node 0 is always fixed anyway
+                continue;
+            }
+            let numbers = tokens.map(|token| token.parse::<f64>()).collect::<Result<Vec<_>, _>>().map_err(|_| invalid(line_number, "not a number"))?;
+            if tag == vertex_tag {
+                let [id, x, y, theta] = numbers[..] else { return Err(invalid(line_number, "expected id x y theta")) };
+                vertices.insert(id as i64, Transform2d::new(x, y, theta));
+            } else if tag == edge_tag {
+                let [i, j, x, y, theta, a, b, c, d, e, f] = numbers[..] else { return Err(invalid(line_number, "expected i j x y theta and 6 information values")) };
+                let information = match format {
+                    GraphFileFormat::G2o => Matrix3::new(a, b, c, b, d, e, c, e, f),
+                    GraphFileFormat::Toro => Matrix3::new(a, b, e, b, c, f, e, f, d),
+                };
+                edges.push((line_number, i as i64, j as i64, Transform2d::new(x, y, theta), information));
+            } else {
+                return Err(invalid(line_number, &format!("unsupported element {}", tag)));
+            }
+        }
+        let indices = vertices.keys().enumerate().map(|(index, id)| (*id, index)).collect::<BTreeMap<_, _>>();
+        let mut res = Self::new();
+        res.nodes = DVector::from_iterator(vertices.len() * 3, vertices.values().flat_map(|pose| [pose.x_meters, pose.y_meters, pose.theta_radians]));
+        for (line_number, i, j, i_to_j, information) in edges {
+            let (Some(i), Some(j)) = (indices.get(&i), indices.get(&j)) else { return Err(invalid(line_number, "edge references a missing vertex")) };
+            res.edges.push(PoseGraphEdge { i: *i, j: *j, i_to_j, information });
+        }
+        res.dirty = true;
+        Ok(res)
+    }
+
+    pub fn save_graph_file(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let format = GraphFileFormat::from_path(path.as_ref()).ok_or(io::Error::new(io::ErrorKind::InvalidInput, "graph files have to end in .g2o or .graph"))?;
+        fs::write(path, self.export(format))
+    }
+
+    pub fn load_graph_file(path: impl AsRef<Path>) -> io::Result<Self> {
+        let format = GraphFileFormat::from_path(path.as_ref()).ok_or(io::Error::new(io::ErrorKind::InvalidInput, "graph files have to end in .g2o or .graph"))?;
+        Self::import(&fs::read_to_string(path)?, format)
+    }
+}
+
+#[test]
+fn test_graph_file_round_trip() {
+    let mut pose_graph = PoseGraphBackend::new();
+    pose_graph.add_node_with_odometry(Transform2d::ZERO);
+    pose_graph.add_node_with_odometry(Transform2d::new(1.0, 0.2, 0.5));
+    pose_graph.add_node_with_odometry(Transform2d::new(0.7, -0.1, -2.0));
+    pose_graph.add_loop_closure(0, 2, Transform2d::new(1.5, 0.8, -1.5), Matrix3::new(10.0, 1.0, 2.0, 1.0, 20.0, 3.0, 2.0, 3.0, 30.0));
+    for format in [GraphFileFormat::G2o, GraphFileFormat::Toro] {
+        let imported = PoseGraphBackend::import(&pose_graph.export(format), format).unwrap();
+        assert_eq!(imported.nodes, pose_graph.nodes);
+        assert_eq!(imported.edges.len(), 3);
+        assert_eq!(imported.edges[2].i_to_j, pose_graph.edges[2].i_to_j);
+        assert_eq!(imported.edges[2].information, pose_graph.edges[2].information);
+    }
+    assert!(pose_graph.export(GraphFileFormat::Toro).contains("EDGE2 0 2 1.5 0.8 -1.5 10 1 20 30 2 3\n"));
+    assert_eq!(GraphFileFormat::from_path(Path::new("intel.g2o")), Some(GraphFileFormat::G2o));
+    assert_eq!(GraphFileFormat::from_path(Path::new("manhattan.graph")), Some(GraphFileFormat::Toro));
+
+    // ids don't need to start at 0 or be contiguous
+    let imported = PoseGraphBackend::import("VERTEX2 5 1 0 0\nVERTEX2 9 2 0 0\nEDGE2 5 9 1 0 0 1 0 1 1 0 0\n", GraphFileFormat::Toro).unwrap();
+    assert_eq!(imported.edges[0].i, 0);
+    assert_eq!(imported.edges[0].j, 1);
+    assert!(PoseGraphBackend::import("EDGE2 5 9 1 0 0 1 0 1 1 0 0\n", GraphFileFormat::Toro).is_err());
+    assert!(PoseGraphBackend::import("VERTEX_XY 1 2 3\n", GraphFileFormat::G2o).is_err());
+}
+
+#[test]
+fn test_optimize_bundled_dataset() {
+    // synthetic grid world with noisy odometry and loop closures, see the comment at the top of the file
+    let mut pose_graph = PoseGraphBackend::import(include_str!("../test_data/grid_world.g2o"), GraphFileFormat::G2o).unwrap();
+    assert_eq!(pose_graph.num_nodes(), 124);
+    assert_eq!(pose_graph.edges.len(), 226);
+    let initial_chi2 = pose_graph.chi2();
+    let initial_poses = pose_graph.poses();
+    pose_graph.optimize(20);
+    let final_chi2 = pose_graph.chi2();
+    assert!(initial_chi2 > 100.0 * final_chi2);
+    // with correctly weighted noise chi2 should be close to the number of degrees of freedom, 3 * 226 - 3 * 123 = 309
+    assert!((final_chi2 - 309.0).abs() < 3.0 * (2.0 * 309.0_f64).sqrt(), "{}", final_chi2);
+    // it already converged so optimizing more shouldn't change anything
+    pose_graph.optimize(20);
+    assert!((pose_graph.chi2() - final_chi2).abs() < 1e-9);
+
+    // the robot drove along a 0.5m grid turning in right angles, so that's where the poses really are.
+    // rms distance to it in position and heading
+    let off_grid = |poses: &[Transform2d]| {
+        let off = |value: f64, step: f64| value - (value / step).round() * step;
+        let squared = poses.iter().map(|pose| (off(pose.x_meters, 0.5).powi(2) + off(pose.y_meters, 0.5).powi(2), off(pose.theta_radians, std::f64::consts::FRAC_PI_2).powi(2)));
+        let (position, heading) = squared.fold((0.0, 0.0), |(a, b), (c, d)| (a + c, b + d));
+        ((position / poses.len() as f64).sqrt(), (heading / poses.len() as f64).sqrt())
+    };
+    let (initial_position, initial_heading) = off_grid(&initial_poses);
+    let (position, heading) = off_grid(&pose_graph.poses());
+    assert!(position < initial_position / 2.0 && heading < initial_heading / 2.0, "{} {} {} {}", initial_position, initial_heading, position, heading);
+    // within a couple of the per edge noise std devs, even at the far end of the graph
+    assert!(position < 2.0 * 0.05 && heading < 2.0 * 0.02, "{} {}", position, heading);
+}
+
+/// jacobian of a + b with respect to a, and with respect to b
+fn compose_jacobians(a: &Transform2d, b: &Transform2d) -> (Matrix3<f64>, Matrix3<f64>) {
+    let (sin, cos) = a.theta_radians.sin_cos();
+    let wrt_a = Matrix3::new(
+        1.0, 0.0, -sin * b.x_meters - cos * b.y_meters,
+        0.0, 1.0, cos * b.x_meters - sin * b.y_meters,
+        0.0, 0.0, 1.0
+    );
+    let wrt_b = Matrix3::new(
+        cos, -sin, 0.0,
+        sin, cos, 0.0,
+        0.0, 0.0, 1.0
+    );
+    (wrt_a, wrt_b)
+}
+
+/// a + b and its first order covariance, assuming a and b are independent
+fn compose_with_covariance(a: &Transform2d, covariance_a: &Matrix3<f64>, b: &Transform2d, covariance_b: &Matrix3<f64>) -> (Transform2d, Matrix3<f64>) {
+    let (wrt_a, wrt_b) = compose_jacobians(a, b);
+    (a.clone() + b.clone(), wrt_a * covariance_a * wrt_a.transpose() + wrt_b * covariance_b * wrt_b.transpose())
+}
+
+fn invert_with_covariance(tf: &Transform2d, covariance: &Matrix3<f64>) -> (Transform2d, Matrix3<f64>) {
+    let (sin, cos) = tf.theta_radians.sin_cos();
+    let (x, y) = (tf.x_meters, tf.y_meters);
+    let jacobian = Matrix3::new(
+        -cos, -sin, sin * x - cos * y,
+        sin, -cos, cos * x + sin * y,
+        0.0, 0.0, -1.0
+    );
+    (-tf.clone(), jacobian * covariance * jacobian.transpose())
+}
+
+#[test]
+fn test_marginalize_node() {
+    let mut pose_graph = PoseGraphBackend::new();
+    pose_graph.add_node_with_odometry(Transform2d::ZERO);
+    pose_graph.add_node_with_odometry(Transform2d::new(1.0, 0.0, PI / 2.0));
+    pose_graph.add_node_with_odometry(Transform2d::new(1.0, 0.5, 0.3));
+    pose_graph.add_node_with_odometry(Transform2d::new(0.5, 0.0, 0.0));
+    let expected_poses = vec![pose_graph.pose(0), pose_graph.pose(2), pose_graph.pose(3)];
+    pose_graph.marginalize_node(1);
+    assert_eq!(pose_graph.poses(), expected_poses);
+    assert_eq!(pose_graph.edges.len(), 2);
+    let composed = pose_graph.edges.iter().find(|edge| edge.i == 0 && edge.j == 1).unwrap();
+    assert_eq!(composed.i_to_j, Transform2d::new(1.0, 0.0, PI / 2.0) + Transform2d::new(1.0, 0.5, 0.3));
+    // two unit covariance edges chained together are less certain than either of them
+    let covariance = composed.information.try_inverse().unwrap();
+    assert!(covariance[(0, 0)] > 1.0 && covariance[(1, 1)] > 1.0 && (covariance[(2, 2)] - 2.0).abs() < 1e-9);
+    // and the pruned graph is still consistent
+    let before = pose_graph.nodes.clone();
+    pose_graph.optimize(5);
+    assert!(pose_graph.nodes.relative_eq(&before, 1e-9, 1e-9));
+}
+
+#[test]
+fn test_compose_jacobians() {
+    // compare against finite differences
+    let a = Transform2d::new(0.7, -0.2, 0.4);
+    let b = Transform2d::new(0.3, 0.5, -0.2);
+    let (wrt_a, wrt_b) = compose_jacobians(&a, &b);
+    let base = a.clone() + b.clone();
+    let difference = |tf: Transform2d| Vector3::new(tf.x_meters - base.x_meters, tf.y_meters - base.y_meters, tf.theta_radians - base.theta_radians);
+    let h = 1e-7;
+    for i in 0..3 {
+        let mut delta = Vector3::zeros();
+        delta[i] = h;
+        let perturbed_a = Transform2d::new(a.x_meters + delta[0], a.y_meters + delta[1], a.theta_radians + delta[2]);
+        let perturbed_b = Transform2d::new(b.x_meters + delta[0], b.y_meters + delta[1], b.theta_radians + delta[2]);
+        assert!((difference(perturbed_a + b.clone()) / h - wrt_a.column(i)).norm() < 1e-5);
+        assert!((difference(a.clone() + perturbed_b) / h - wrt_b.column(i)).norm() < 1e-5);
+    }
 }
 
 #[test]
@@ -139,11 +430,47 @@ fn test_optimize_pose_graph() {
     assert!(pose_graph.nodes.relative_eq(&ground_truth, 1e-9, 1e-9));
 }
 
+/// decides which scans become nodes, and which nodes get pruned again because other nodes already saw everything they did
+#[derive(Debug, Clone)]
+pub struct KeyframeParams {
+    /// a new node is only considered once odometry moved this far since the last one
+    pub min_translation_meters: f64,
+    pub min_rotation_radians: f64,
+    /// scans are downsampled to at most one point per voxel of this size before they're stored
+    pub voxel_size_meters: f64,
+    /// only nodes this close to each other can make each other redundant
+    pub redundancy_radius_meters: f64,
+    /// a point counts as already seen if another nearby node's scan has a point this close to it
+    pub correspondence_distance_meters: f64,
+    /// nodes that see less than this fraction of points nobody else saw get marginalized
+    pub min_information_gain: f64,
+}
+
+impl Default for KeyframeParams {
+    fn default() -> Self {
+        Self {
+            min_translation_meters: 0.15,
+            min_rotation_radians: 0.5,
+            voxel_size_meters: 0.02,
+            redundancy_radius_meters: 1.0,
+            correspondence_distance_meters: 0.05,
+            min_information_gain: 0.1,
+        }
+    }
+}
+
 pub struct LidarPoseGraph {
     pub backend: PoseGraphBackend,
     pub node_scans: Vec<Vec<Vector2<f64>>>,
+    pub keyframe_params: KeyframeParams,
+    pub submaps: Vec<Submap>,
+    pub submap_params: SubmapParams,
     world_to_prev_odom: Transform2d,
-    scans_since_loop_closure: u16
+    nodes_since_loop_closure_search: usize,
+    /// (index at the time it was removed, scan) of every node pruned since the last take_pruned_nodes
+    pruned_nodes: Vec<(usize, Vec<Vector2<f64>>)>,
+    /// indices of submaps finished since the last take_finished_submaps
+    finished_submaps: Vec<usize>
 }
 
 pub enum PoseGraphUpdateResult {
@@ -154,33 +481,336 @@ pub enum PoseGraphUpdateResult {
 
 impl LidarPoseGraph {
     pub fn new() -> Self {
-        Self { backend: PoseGraphBackend::new(), node_scans: Vec::new(), world_to_prev_odom: Transform2d::ZERO, scans_since_loop_closure: 0 }
+        Self {
+            backend: PoseGraphBackend::new(),
+            node_scans: Vec::new(),
+            keyframe_params: KeyframeParams::default(),
+            submaps: Vec::new(),
+            submap_params: SubmapParams::default(),
+            world_to_prev_odom: Transform2d::ZERO,
+            nodes_since_loop_closure_search: 0,
+            pruned_nodes: Vec::new(),
+            finished_submaps: Vec::new()
+        }
     }
     pub fn update(&mut self, world_to_new_odom: Transform2d, new_scan: Vec<Vector2<f64>>) -> PoseGraphUpdateResult {
         assert_eq!(self.node_scans.len(), self.backend.nodes.len() / 3);
 
         let prev_odom_to_new_odom = -self.world_to_prev_odom.clone() + world_to_new_odom.clone();
-        if prev_odom_to_new_odom.norm() > 0.15 || prev_odom_to_new_odom.theta_radians.abs() > 0.5 {
-            self.add_scan(world_to_new_odom, new_scan);
-            PoseGraphUpdateResult::LoopClosed // FIXME
+        if prev_odom_to_new_odom.norm() > self.keyframe_params.min_translation_meters || prev_odom_to_new_odom.theta_radians.abs() > self.keyframe_params.min_rotation_radians {
+            let loop_closed = self.add_scan(world_to_new_odom, new_scan);
+            // the newest node has to stay so the next odometry edge has something to attach to, but the one before it can go
+            // (node 0 anchors the graph so it never gets pruned)
+            if let Some(candidate) = self.backend.num_nodes().checked_sub(2).filter(|candidate| *candidate > 0) {
+                if self.information_gain(candidate) < self.keyframe_params.min_information_gain {
+                    self.prune_node(candidate);
+                }
+            }
+            if loop_closed {
+                PoseGraphUpdateResult::LoopClosed
+            } else {
+                PoseGraphUpdateResult::Added
+            }
         } else {
             PoseGraphUpdateResult::NotAdded
         }
     }
-    fn add_scan(&mut self, world_to_new_odom: Transform2d, new_scan: Vec<Vector2<f64>>) {
+    /// adds a node for the scan and returns whether it closed a loop
+    fn add_scan(&mut self, world_to_new_odom: Transform2d, new_scan: Vec<Vector2<f64>>) -> bool {
         let prev_odom_to_new_odom = -self.world_to_prev_odom.clone() + world_to_new_odom.clone();
-        // if let Some(last_scan) = self.node_scans.last() {
-        //     let icp_result = icp_least_squares(&new_scan, &last_scan, Vector3::new(prev_odom_to_new_odom.x_meters, prev_odom_to_new_odom.y_meters, prev_odom_to_new_odom.theta_radians), 50);
-        //     let icp_result = Transform2d::new(icp_result[0], icp_result[1], (icp_result[2] + PI).rem_euclid(2.0*PI) - PI);
-        //     dbg!(&icp_result, prev_odom_to_new_odom);
-        //     self.backend.add_node_with_odometry(icp_result);
-        // } else {
-            // we can trust odometry for the first node because there's no scan to match to
-            self.backend.add_node_with_odometry(prev_odom_to_new_odom);
-        // }
+        let scan = voxel_downsample(&new_scan, self.keyframe_params.voxel_size_meters);
+        match (self.backend.num_nodes().checked_sub(1), self.submaps.last()) {
+            (Some(prev), Some(submap)) => {
+                let world_to_prev = self.backend.pose(prev);
+                let world_to_predicted = world_to_prev.clone() + prev_odom_to_new_odom;
+                let world_to_submap = self.world_to_submap(self.submaps.len() - 1);
+                let submap_to_predicted = -world_to_submap.clone() + world_to_predicted.clone();
+                // scan to submap matching fixes up the odometry. when there isn't enough to match against (like in a corridor) odometry is all we have.
+                let matcher = submap.build_matcher(self.submap_params.local_matcher.clone());
+                let world_to_new = match submap.match_scan(&scan, &submap_to_predicted, &matcher, &self.submap_params.icp) {
+                    Some(submap_match) => world_to_submap + submap_match.submap_to_robot,
+                    None => world_to_predicted,
+                };
+                self.backend.add_node_with_odometry(-world_to_prev + world_to_new);
+            }
+            // the first node (or the first one after loading a graph) has nothing to match against
+            _ => self.backend.add_node_with_odometry(prev_odom_to_new_odom),
+        }
         self.world_to_prev_odom = world_to_new_odom;
-        self.node_scans.push(new_scan);
-        self.scans_since_loop_closure += 1;
+        self.node_scans.push(scan);
         assert_eq!(self.node_scans.len(), self.backend.nodes.len() / 3);
+        let node = self.backend.num_nodes() - 1;
+        self.insert_into_submap(node);
+        self.nodes_since_loop_closure_search += 1;
+        if self.nodes_since_loop_closure_search >= self.submap_params.loop_closure_every_n_nodes {
+            self.nodes_since_loop_closure_search = 0;
+            self.search_loop_closures(node)
+        } else {
+            false
+        }
+    }
+    pub fn world_to_submap(&self, index: usize) -> Transform2d {
+        let submap = &self.submaps[index];
+        self.backend.pose(submap.anchor) + submap.anchor_to_submap.clone()
+    }
+    fn insert_into_submap(&mut self, node: usize) {
+        if self.submaps.last().is_none_or(|submap| submap.finished) {
+            self.submaps.push(Submap::new(node, self.submap_params.grid.clone()));
+        }
+        let index = self.submaps.len() - 1;
+        let submap_to_node = -self.world_to_submap(index) + self.backend.pose(node);
+        let submap = &mut self.submaps[index];
+        submap.insert(&submap_to_node, &self.node_scans[node]);
+        if submap.num_scans >= self.submap_params.scans_per_submap {
+            submap.finish(self.submap_params.loop_closure_matcher.clone());
+            self.finished_submaps.push(index);
+            // the next submap starts right here so the next scan already has something to match against
+            let mut next = Submap::new(node, self.submap_params.grid.clone());
+            next.insert(&Transform2d::ZERO, &self.node_scans[node]);
+            self.submaps.push(next);
+        }
+    }
+    /// matches a node's scan against every finished submap near it (except the one right before the current submap, which it's already
+    /// connected to through the odometry edges) and adds a loop closure for every match. optimizes the graph if anything was found.
+    fn search_loop_closures(&mut self, node: usize) -> bool {
+        let world_to_node = self.backend.pose(node);
+        // a scan from a repetitive place can line up with several submaps at once, and only one of them is right.
+        // the best scoring one is the most likely to be, and one closure is enough to pull the graph back together.
+        let mut best: Option<(usize, Transform2d, Matrix3<f64>, f64)> = None;
+        for index in 0..self.submaps.len().saturating_sub(2) {
+            let submap = &self.submaps[index];
+            let world_to_submap = self.world_to_submap(index);
+            let submap_to_node = -world_to_submap + world_to_node.clone();
+            let Some(matcher) = submap.matcher().filter(|_| submap_to_node.norm() <= self.submap_params.loop_closure_search_radius_meters) else {
+                continue;
+            };
+            let Some(submap_match) = submap.match_scan(&self.node_scans[node], &submap_to_node, matcher, &self.submap_params.icp) else {
+                continue;
+            };
+            if best.as_ref().is_none_or(|(_, _, _, score)| submap_match.score > *score) {
+                // the edge goes from the anchor node, so the submap's offset from it has to be part of the transform
+                best = Some((submap.anchor, submap.anchor_to_submap.clone() + submap_match.submap_to_robot, submap_match.information, submap_match.score));
+            }
+        }
+        let Some((anchor, anchor_to_node, information, _)) = best else {
+            return false;
+        };
+        self.backend.add_loop_closure(anchor, node, anchor_to_node, information);
+        self.backend.optimize(10);
+        true
+    }
+    /// submaps finished since the last call, to be sent to the dashboard
+    pub fn take_finished_submaps(&mut self) -> Vec<usize> {
+        std::mem::take(&mut self.finished_submaps)
     }
-}
\ No newline at end of file
+    /// fraction of node k's scan that no other node within redundancy_radius_meters saw. the nodes right before and after k
+    /// always overlap with it a lot, so they don't count, otherwise everything but the newest node would get pruned while exploring.
+    pub fn information_gain(&self, k: usize) -> f64 {
+        let world_to_k = self.backend.pose(k);
+        let mut seen = Vec::new();
+        for j in (0..self.backend.num_nodes()).filter(|j| j.abs_diff(k) > 1) {
+            let world_to_j = self.backend.pose(j);
+            if (-world_to_k.clone() + world_to_j.clone()).norm() < self.keyframe_params.redundancy_radius_meters {
+                seen.extend(to_world(&world_to_j, &self.node_scans[j]));
+            }
+        }
+        let scan = to_world(&world_to_k, &self.node_scans[k]);
+        if seen.is_empty() || scan.is_empty() {
+            return 1.0;
+        }
+        let tree = KdTree2d::new(&seen);
+        let unseen = scan.iter().filter(|point| tree.nearest_within(point, self.keyframe_params.correspondence_distance_meters).is_none()).count();
+        unseen as f64 / scan.len() as f64
+    }
+    fn prune_node(&mut self, k: usize) {
+        // submaps hang off of nodes, so any submap attached to k moves over to the node before it (which exists because node 0 is never pruned)
+        let world_to_k = self.backend.pose(k);
+        let world_to_before_k = self.backend.pose(k - 1);
+        for submap in &mut self.submaps {
+            if submap.anchor == k {
+                submap.anchor_to_submap = -world_to_before_k.clone() + world_to_k.clone() + submap.anchor_to_submap.clone();
+                submap.anchor = k - 1;
+            } else if submap.anchor > k {
+                submap.anchor -= 1;
+            }
+        }
+        self.backend.marginalize_node(k);
+        let scan = self.node_scans.remove(k);
+        self.pruned_nodes.push((k, scan));
+    }
+    /// nodes removed since the last call, in the order they were removed. anything indexed by node (like the occupancy grid) needs to drop them too.
+    pub fn take_pruned_nodes(&mut self) -> Vec<(usize, Vec<Vector2<f64>>)> {
+        std::mem::take(&mut self.pruned_nodes)
+    }
+}
+
+fn to_world(world_to_robot: &Transform2d, scan: &[Vector2<f64>]) -> Vec<Vector2<f64>> {
+    let (sin, cos) = world_to_robot.theta_radians.sin_cos();
+    scan.iter().map(|point| Vector2::new(
+        world_to_robot.x_meters + cos * point[0] - sin * point[1],
+        world_to_robot.y_meters + sin * point[0] + cos * point[1]
+    )).collect()
+}
+
+/// keeps the first point in every voxel, so the scan stays in angular order
+fn voxel_downsample(scan: &[Vector2<f64>], voxel_size_meters: f64) -> Vec<Vector2<f64>> {
+    let mut occupied = HashSet::new();
+    scan.iter().filter(|point| occupied.insert(((point[0] / voxel_size_meters).floor() as i64, (point[1] / voxel_size_meters).floor() as i64))).cloned().collect()
+}
+
+#[test]
+fn test_voxel_downsample() {
+    let scan = vec![Vector2::new(0.001, 0.001), Vector2::new(0.005, 0.002), Vector2::new(0.5, 0.5), Vector2::new(0.001, 0.003)];
+    assert_eq!(voxel_downsample(&scan, 0.02), vec![Vector2::new(0.001, 0.001), Vector2::new(0.5, 0.5)]);
+}
+
+#[test]
+fn test_graph_size_bounded_when_revisiting() {
+    use crate::sim::{room_walls, simulate_scan};
+    let walls = room_walls();
+    let mut pose_graph = LidarPoseGraph::new();
+    let mut sizes = Vec::new();
+    for _lap in 0..4 {
+        // drive a 1.2m radius circle around the middle of the room
+        for i in 0..=60 {
+            let angle = i as f64 / 60.0 * 2.0 * PI;
+            let pose = Transform2d::new(0.5 + 1.2 * angle.sin(), 0.1 - 1.2 * angle.cos(), angle);
+            pose_graph.update(pose.clone(), simulate_scan(&walls, &pose, 720, 12.0));
+        }
+        sizes.push(pose_graph.backend.num_nodes());
+    }
+    assert_eq!(pose_graph.node_scans.len(), pose_graph.backend.num_nodes());
+    // every lap after the first only adds the node we're currently at
+    assert!(sizes[3] <= sizes[0] + 1, "{:?}", sizes);
+    // and the first lap kept more than just the endpoints
+    assert!(sizes[0] > 4, "{:?}", sizes);
+    assert!(!pose_graph.take_pruned_nodes().is_empty());
+}
+
+#[test]
+fn test_submaps_correct_odometry_drift() {
+    use crate::sim::{room_walls, simulate_scan};
+    let walls = room_walls();
+    let mut pose_graph = LidarPoseGraph::new();
+    let truth = |i: usize| {
+        let angle = i as f64 / 60.0 * 2.0 * PI;
+        Transform2d::new(0.5 + 1.2 * angle.sin(), 0.1 - 1.2 * angle.cos(), angle)
+    };
+    let mut odom = truth(0);
+    let mut loop_closures = 0;
+    let mut newest_truth = truth(0);
+    for i in 1..=180 {
+        // odometry that overestimates distance and slowly turns left
+        let delta = -truth(i - 1) + truth(i);
+        odom += Transform2d::new(delta.x_meters * 1.05, delta.y_meters * 1.05, delta.theta_radians + 0.01);
+        let scan = simulate_scan(&walls, &truth(i), 720, 12.0);
+        match pose_graph.update(odom.clone(), scan) {
+            PoseGraphUpdateResult::NotAdded => {}
+            PoseGraphUpdateResult::Added => newest_truth = truth(i),
+            PoseGraphUpdateResult::LoopClosed => {
+                newest_truth = truth(i);
+                loop_closures += 1;
+            }
+        }
+    }
+    let odom_error = -truth(180) + odom;
+    // the first node is where odometry thought it was, which was already a bit off
+    let error = -newest_truth + pose_graph.backend.pose(pose_graph.backend.num_nodes() - 1);
+    assert!(odom_error.norm() > 0.3 && wrap_angle(odom_error.theta_radians).abs() > 1.0);
+    assert!(error.norm() < 0.05 && wrap_angle(error.theta_radians).abs() < 0.02, "{:?}", error);
+    assert!(pose_graph.submaps.iter().filter(|submap| submap.finished).count() >= 2);
+    assert_eq!(pose_graph.take_finished_submaps().len(), pose_graph.submaps.len() - 1);
+    assert!(loop_closures > 0);
+}
+
+/// bump this whenever SavedPoseGraph changes so old files get rejected instead of misread
+pub const POSE_GRAPH_FILE_VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize)]
+struct SavedPoseGraph {
+    version: u32,
+    nodes: Vec<Transform2d>,
+    edges: Vec<SavedPoseGraphEdge>,
+    /// robot frame scan of every node
+    scans: Vec<Vec<[f64; 2]>>
+}
+
+#[derive(Serialize, Deserialize)]
+struct SavedPoseGraphEdge {
+    i: usize,
+    j: usize,
+    i_to_j: Transform2d,
+    /// row major
+    information: [[f64; 3]; 3]
+}
+
+impl LidarPoseGraph {
+    pub fn save(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let saved = SavedPoseGraph {
+            version: POSE_GRAPH_FILE_VERSION,
+            nodes: self.backend.poses(),
+            edges: self.backend.edges.iter().map(|edge| SavedPoseGraphEdge {
+                i: edge.i,
+                j: edge.j,
+                i_to_j: edge.i_to_j.clone(),
+                information: [0, 1, 2].map(|row| [0, 1, 2].map(|col| edge.information[(row, col)]))
+            }).collect(),
+            scans: self.node_scans.iter().map(|scan| scan.iter().map(|point| [point[0], point[1]]).collect()).collect()
+        };
+        fs::write(path, serde_json::to_vec(&saved)?)
+    }
+
+    /// the graph continues from the last saved node, so odometry should be reset to its pose before mapping more
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        let value: serde_json::Value = serde_json::from_slice(&fs::read(path)?)?;
+        let version = value.get("version").and_then(|version| version.as_u64());
+        if version != Some(POSE_GRAPH_FILE_VERSION as u64) {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("unsupported pose graph file version {:?}, expected {}", version, POSE_GRAPH_FILE_VERSION)));
+        }
+        let saved: SavedPoseGraph = serde_json::from_value(value)?;
+        if saved.nodes.len() != saved.scans.len() || saved.edges.iter().any(|edge| edge.i.max(edge.j) >= saved.nodes.len()) {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "pose graph file is inconsistent"));
+        }
+        let mut backend = PoseGraphBackend::new();
+        backend.nodes = DVector::from_iterator(saved.nodes.len() * 3, saved.nodes.iter().flat_map(|node| [node.x_meters, node.y_meters, node.theta_radians]));
+        backend.edges = saved.edges.into_iter().map(|edge| PoseGraphEdge {
+            i: edge.i,
+            j: edge.j,
+            i_to_j: edge.i_to_j,
+            information: Matrix3::from_fn(|row, col| edge.information[row][col])
+        }).collect();
+        Ok(Self {
+            backend,
+            node_scans: saved.scans.into_iter().map(|scan| scan.into_iter().map(|[x, y]| Vector2::new(x, y)).collect()).collect(),
+            keyframe_params: KeyframeParams::default(),
+            // submaps aren't saved, mapping more just starts a new one
+            submaps: Vec::new(),
+            submap_params: SubmapParams::default(),
+            world_to_prev_odom: saved.nodes.last().cloned().unwrap_or(Transform2d::ZERO),
+            nodes_since_loop_closure_search: 0,
+            pruned_nodes: Vec::new(),
+            finished_submaps: Vec::new()
+        })
+    }
+}
+
+#[test]
+fn test_save_and_load_pose_graph() {
+    let mut pose_graph = LidarPoseGraph::new();
+    for i in 1..6 {
+        let i = i as f64;
+        pose_graph.update(Transform2d::new(0.2 * i, 0.1 * i, 0.3 * i), vec![Vector2::new(i, 1.0), Vector2::new(-1.0, i)]);
+    }
+    pose_graph.backend.add_loop_closure(0, 4, Transform2d::new(0.8, 0.4, 1.2), Matrix3::from_diagonal(&Vector3::new(10.0, 20.0, 30.0)));
+    let path = std::env::temp_dir().join(format!("xavier-pose-graph-{}.json", std::process::id()));
+    pose_graph.save(&path).unwrap();
+    let loaded = LidarPoseGraph::load(&path).unwrap();
+    assert_eq!(loaded.backend.nodes, pose_graph.backend.nodes);
+    assert_eq!(loaded.node_scans, pose_graph.node_scans);
+    assert_eq!(loaded.backend.edges.len(), 5);
+    assert_eq!(loaded.backend.edges[4].information, Matrix3::from_diagonal(&Vector3::new(10.0, 20.0, 30.0)));
+
+    fs::write(&path, r#"{"version": 0, "nodes": []}"#).unwrap();
+    assert_eq!(LidarPoseGraph::load(&path).err().map(|e| e.kind()), Some(io::ErrorKind::InvalidData));
+    fs::remove_file(&path).unwrap();
+}
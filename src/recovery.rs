@@ -0,0 +1,295 @@
+//! noticing that following a path got stuck, and the things to try before giving up on the goal.
+//! the behaviour state machine runs these in order while it's Recovering, one per stall, replanning after each one.
+
+use std::f64::consts::PI;
+
+use nalgebra::Vector2;
+use serde::Serialize;
+use tokio::time::Duration;
+
+use crate::{
+    drivetrain::XAVIERBOT_FOOTPRINT_RADIUS_METERS,
+    geometry::{wrap_angle, Transform2d, Twist2d},
+    paths::Path,
+};
+
+#[derive(Debug, Clone)]
+pub struct StallParams {
+    /// commanded speeds below these don't count, the robot might just be stopping
+    pub min_speed_meters_per_second: f64,
+    pub min_angular_speed_radians_per_second: f64,
+    /// odometry showing less than this fraction of the commanded speed counts as not moving
+    pub min_speed_ratio: f64,
+    pub not_moving_time: Duration,
+    /// getting less than this much further along the path doesn't count as progress
+    pub min_progress_meters: f64,
+    pub progress_timeout: Duration,
+    /// the end of the path is the goal checker's problem, the robot is supposed to stop there
+    pub end_distance_meters: f64,
+    /// how long the local planner can refuse to drive forward when the follower wants to before it counts as blocked
+    pub blocked_time: Duration,
+}
+
+impl Default for StallParams {
+    fn default() -> Self {
+        Self {
+            min_speed_meters_per_second: 0.05,
+            min_angular_speed_radians_per_second: 0.3,
+            min_speed_ratio: 0.25,
+            not_moving_time: Duration::from_millis(1500),
+            min_progress_meters: 0.05,
+            progress_timeout: Duration::from_secs(8),
+            end_distance_meters: 0.15,
+            blocked_time: Duration::from_secs(3),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub enum StallReason {
+    /// the wheels are told to turn but odometry says the robot isn't going anywhere (wedged, slipping, or the motors are off)
+    NotMoving,
+    /// moving, but not along the path
+    NoProgress,
+    /// the local planner won't drive towards the path because something is in the way
+    Blocked,
+}
+
+/// everything the stall detector compares
+pub struct StallSpeeds<'a> {
+    /// what the follower asked for
+    pub desired: &'a Twist2d,
+    /// what the local planner made of it
+    pub planned: &'a Twist2d,
+    /// what actually got sent to the drivetrain last frame
+    pub commanded: &'a Twist2d,
+    /// how fast odometry says the robot went
+    pub measured: &'a Twist2d,
+}
+
+pub struct StallDetector {
+    pub params: StallParams,
+    not_moving_since: Option<Duration>,
+    blocked_since: Option<Duration>,
+    best_progress_meters: f64,
+    last_progress: Duration,
+}
+
+/// whether measured is way slower than commanded, for one axis
+fn too_slow(commanded: f64, measured: f64, min: f64, ratio: f64) -> bool {
+    commanded.abs() >= min && (measured * commanded.signum()) < ratio * commanded.abs()
+}
+
+impl StallDetector {
+    pub fn new(params: StallParams, now: Duration) -> Self {
+        Self { params, not_moving_since: None, blocked_since: None, best_progress_meters: f64::NEG_INFINITY, last_progress: now }
+    }
+
+    pub fn update(&mut self, path: &Path, pose: &Transform2d, speeds: &StallSpeeds, now: Duration) -> Option<StallReason> {
+        let StallSpeeds { desired, planned, commanded, measured } = speeds;
+        let p = &self.params;
+        let not_moving = too_slow(commanded.dx, measured.dx, p.min_speed_meters_per_second, p.min_speed_ratio)
+            || too_slow(commanded.dtheta, measured.dtheta, p.min_angular_speed_radians_per_second, p.min_speed_ratio);
+        if not_moving {
+            if now.saturating_sub(*self.not_moving_since.get_or_insert(now)) >= p.not_moving_time {
+                return Some(StallReason::NotMoving);
+            }
+        } else {
+            self.not_moving_since = None;
+        }
+
+        if desired.dx.abs() >= p.min_speed_meters_per_second && planned.dx.abs() < p.min_speed_meters_per_second {
+            if now.saturating_sub(*self.blocked_since.get_or_insert(now)) >= p.blocked_time {
+                return Some(StallReason::Blocked);
+            }
+        } else {
+            self.blocked_since = None;
+        }
+
+        if path.waypoints.len() >= 2 {
+            let progress = path.get_path_progress(pose);
+            let remaining = path.remaining_distance(progress);
+            // counted from the end so it doesn't need the path's length
+            let along = -remaining;
+            if remaining < p.end_distance_meters || along > self.best_progress_meters + p.min_progress_meters {
+                self.best_progress_meters = self.best_progress_meters.max(along);
+                self.last_progress = now;
+            } else if now.saturating_sub(self.last_progress) >= p.progress_timeout {
+                return Some(StallReason::NoProgress);
+            }
+        }
+        None
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub enum RecoveryAction {
+    /// just plan a new path from wherever the robot is
+    Replan,
+    /// forget the obstacles the local planner has seen so far
+    ClearLocalCostmap,
+    BackUp { distance_meters: f64, speed_meters_per_second: f64 },
+    RotateInPlace { angle_radians: f64, angular_speed_radians_per_second: f64 },
+}
+
+#[derive(Debug, Clone)]
+pub struct RecoveryParams {
+    /// tried in order, one per stall. once they've all been tried the goal gets aborted.
+    pub actions: Vec<RecoveryAction>,
+    /// a single action taking longer than this failed
+    pub timeout: Duration,
+    /// backing up stops when there's something this close behind the robot
+    pub backup_clearance_meters: f64,
+}
+
+impl Default for RecoveryParams {
+    fn default() -> Self {
+        Self {
+            actions: vec![
+                RecoveryAction::Replan,
+                RecoveryAction::ClearLocalCostmap,
+                RecoveryAction::BackUp { distance_meters: 0.15, speed_meters_per_second: 0.1 },
+                RecoveryAction::RotateInPlace { angle_radians: PI / 2.0, angular_speed_radians_per_second: 0.5 },
+            ],
+            timeout: Duration::from_secs(10),
+            backup_clearance_meters: 0.1,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub enum RecoveryFailure {
+    /// backing up into something
+    Blocked,
+    TimedOut,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub enum RecoveryOutcome {
+    Started,
+    Succeeded,
+    Failed(RecoveryFailure),
+}
+
+/// sent to the dashboard when an attempt starts and when it's over
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct RecoveryReport {
+    /// counting from 1, per goal (or per mission waypoint)
+    pub attempt: usize,
+    pub action: RecoveryAction,
+    pub stall: StallReason,
+    pub outcome: RecoveryOutcome,
+}
+
+pub struct RunningRecovery {
+    pub attempt: usize,
+    pub action: RecoveryAction,
+    pub stall: StallReason,
+    started: Duration,
+    start_pose: Transform2d,
+    prev_theta: f64,
+    rotated_radians: f64,
+}
+
+impl RunningRecovery {
+    pub fn new(attempt: usize, action: RecoveryAction, stall: StallReason, pose: &Transform2d, now: Duration) -> Self {
+        Self { attempt, action, stall, started: now, start_pose: pose.clone(), prev_theta: pose.theta_radians, rotated_radians: 0.0 }
+    }
+
+    pub fn report(&self, outcome: RecoveryOutcome) -> RecoveryReport {
+        RecoveryReport { attempt: self.attempt, action: self.action.clone(), stall: self.stall, outcome }
+    }
+
+    /// speeds to drive at, and the outcome once it's over. scan is in the robot frame.
+    pub fn update(&mut self, pose: &Transform2d, scan: &[Vector2<f64>], now: Duration, params: &RecoveryParams) -> (Twist2d, Option<RecoveryOutcome>) {
+        self.rotated_radians += wrap_angle(pose.theta_radians - self.prev_theta);
+        self.prev_theta = pose.theta_radians;
+        match self.action {
+            RecoveryAction::Replan | RecoveryAction::ClearLocalCostmap => (Twist2d::ZERO, Some(RecoveryOutcome::Succeeded)),
+            RecoveryAction::BackUp { distance_meters, .. } if (-self.start_pose.clone() + pose.clone()).norm() >= distance_meters => {
+                (Twist2d::ZERO, Some(RecoveryOutcome::Succeeded))
+            }
+            RecoveryAction::RotateInPlace { angle_radians, .. } if self.rotated_radians.abs() >= angle_radians.abs() => {
+                (Twist2d::ZERO, Some(RecoveryOutcome::Succeeded))
+            }
+            _ if now.saturating_sub(self.started) > params.timeout => (Twist2d::ZERO, Some(RecoveryOutcome::Failed(RecoveryFailure::TimedOut))),
+            RecoveryAction::BackUp { speed_meters_per_second, .. } => {
+                let r = XAVIERBOT_FOOTPRINT_RADIUS_METERS;
+                // points right at the center are the robot seeing itself, same as the safety monitor
+                let behind = scan.iter().any(|point| point[0] < 0.0 && point[0] > -r - params.backup_clearance_meters && point[1].abs() < r && point.norm() > 0.1);
+                if behind {
+                    (Twist2d::ZERO, Some(RecoveryOutcome::Failed(RecoveryFailure::Blocked)))
+                } else {
+                    (Twist2d::new(-speed_meters_per_second.abs(), 0.0, 0.0), None)
+                }
+            }
+            RecoveryAction::RotateInPlace { angle_radians, angular_speed_radians_per_second } => {
+                (Twist2d::new(0.0, 0.0, angular_speed_radians_per_second.abs().copysign(angle_radians)), None)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::sim::ms;
+
+    fn path() -> Path {
+        Path { waypoints: vec![Transform2d::new(0.0, 0.0, 0.0), Transform2d::new(2.0, 0.0, 0.0)] }
+    }
+
+    #[test]
+    fn test_detects_stalls() {
+        let forward = Twist2d::new(0.2, 0.0, 0.0);
+        // wheels spinning, robot not moving
+        let mut detector = StallDetector::new(StallParams::default(), ms(0));
+        let pose = Transform2d::new(0.5, 0.0, 0.0);
+        assert_eq!(detector.update(&path(), &pose, &StallSpeeds { desired: &forward, planned: &forward, commanded: &forward, measured: &Twist2d::ZERO }, ms(0)), None);
+        assert_eq!(detector.update(&path(), &pose, &StallSpeeds { desired: &forward, planned: &forward, commanded: &forward, measured: &Twist2d::new(0.19, 0.0, 0.0) }, ms(1000)), None);
+        assert_eq!(detector.update(&path(), &pose, &StallSpeeds { desired: &forward, planned: &forward, commanded: &forward, measured: &Twist2d::ZERO }, ms(2000)), None);
+        assert_eq!(detector.update(&path(), &pose, &StallSpeeds { desired: &forward, planned: &forward, commanded: &forward, measured: &Twist2d::ZERO }, ms(3500)), Some(StallReason::NotMoving));
+
+        // the local planner won't go forward
+        let mut detector = StallDetector::new(StallParams::default(), ms(0));
+        let dodging = Twist2d::new(0.0, 0.0, 0.5);
+        assert_eq!(detector.update(&path(), &pose, &StallSpeeds { desired: &forward, planned: &dodging, commanded: &dodging, measured: &dodging }, ms(0)), None);
+        assert_eq!(detector.update(&path(), &pose, &StallSpeeds { desired: &forward, planned: &dodging, commanded: &dodging, measured: &dodging }, ms(3000)), Some(StallReason::Blocked));
+
+        // driving around in circles next to the path
+        let mut detector = StallDetector::new(StallParams::default(), ms(0));
+        for i in 0..=10 {
+            let pose = Transform2d::new(0.5 + 0.1 * (i as f64).sin(), 0.3, 0.0);
+            let status = detector.update(&path(), &pose, &StallSpeeds { desired: &forward, planned: &forward, commanded: &forward, measured: &forward }, ms(i * 1000));
+            assert_eq!(status, if i < 9 { None } else { Some(StallReason::NoProgress) }, "{}", i);
+        }
+        // sitting at the end is fine
+        let mut detector = StallDetector::new(StallParams::default(), ms(0));
+        let end = Transform2d::new(1.95, 0.0, 0.0);
+        assert_eq!(detector.update(&path(), &end, &StallSpeeds { desired: &Twist2d::ZERO, planned: &Twist2d::ZERO, commanded: &Twist2d::ZERO, measured: &Twist2d::ZERO }, ms(20000)), None);
+    }
+
+    #[test]
+    fn test_recoveries() {
+        let params = RecoveryParams::default();
+        let backup = RecoveryAction::BackUp { distance_meters: 0.15, speed_meters_per_second: 0.1 };
+        let mut recovery = RunningRecovery::new(1, backup.clone(), StallReason::Blocked, &Transform2d::ZERO, ms(0));
+        let (speeds, outcome) = recovery.update(&Transform2d::ZERO, &[], ms(10), &params);
+        assert!(speeds.dx < 0.0 && outcome.is_none());
+        assert_eq!(recovery.update(&Transform2d::new(-0.16, 0.0, 0.0), &[], ms(1600), &params).1, Some(RecoveryOutcome::Succeeded));
+        // a wall behind
+        let mut recovery = RunningRecovery::new(1, backup, StallReason::Blocked, &Transform2d::ZERO, ms(0));
+        let wall = [Vector2::new(-0.2, 0.05)];
+        assert_eq!(recovery.update(&Transform2d::ZERO, &wall, ms(10), &params).1, Some(RecoveryOutcome::Failed(RecoveryFailure::Blocked)));
+
+        // rotating across the wraparound
+        let rotate = RecoveryAction::RotateInPlace { angle_radians: -PI / 2.0, angular_speed_radians_per_second: 0.5 };
+        let mut recovery = RunningRecovery::new(2, rotate, StallReason::NotMoving, &Transform2d::new(0.0, 0.0, -3.0), ms(0));
+        assert_eq!(recovery.update(&Transform2d::new(0.0, 0.0, -3.0), &[], ms(10), &params).0.dtheta, -0.5);
+        assert_eq!(recovery.update(&Transform2d::new(0.0, 0.0, 3.0), &[], ms(1000), &params).1, None);
+        assert_eq!(recovery.update(&Transform2d::new(0.0, 0.0, 1.6), &[], ms(3000), &params).1, Some(RecoveryOutcome::Succeeded));
+        // stuck
+        let mut recovery = RunningRecovery::new(2, RecoveryAction::RotateInPlace { angle_radians: PI, angular_speed_radians_per_second: 0.5 }, StallReason::NotMoving, &Transform2d::ZERO, ms(0));
+        assert_eq!(recovery.update(&Transform2d::ZERO, &[], ms(10001), &params).1, Some(RecoveryOutcome::Failed(RecoveryFailure::TimedOut)));
+    }
+}
@@ -0,0 +1,172 @@
+//! safety monitor that doesn't trust the planner or the local planner. if enough raw lidar points end up inside the protective field
+//! around the robot, or the lidar stops sending scans, it latches an e-stop and zeros whatever speeds were commanded until someone clears it.
+
+use nalgebra::Vector2;
+use serde::Serialize;
+use tokio::time::Duration;
+
+use crate::{
+    drivetrain::XAVIERBOT_FOOTPRINT_RADIUS_METERS,
+    geometry::{point_in_polygon, Twist2d},
+};
+
+#[derive(Debug, Clone)]
+pub struct SafetyParams {
+    /// protective field when standing still, polygon in the robot frame
+    pub field: Vec<Vector2<f64>>,
+    /// the field stretches in the direction of travel by however far the robot gets in this long, so it can stop in time
+    pub stopping_time_seconds: f64,
+    /// e-stop if the newest scan is older than this
+    pub max_scan_age: Duration,
+    /// a couple of stray points shouldn't stop the robot
+    pub min_points_in_field: usize,
+    /// points this close to the center are the robot seeing itself
+    pub self_filter_radius_meters: f64,
+}
+
+impl Default for SafetyParams {
+    fn default() -> Self {
+        // the footprint plus a bit, with more room in front
+        let r = XAVIERBOT_FOOTPRINT_RADIUS_METERS;
+        Self {
+            field: vec![Vector2::new(-r - 0.03, -r - 0.03), Vector2::new(r + 0.08, -r - 0.03), Vector2::new(r + 0.08, r + 0.03), Vector2::new(-r - 0.03, r + 0.03)],
+            stopping_time_seconds: 0.5,
+            max_scan_age: Duration::from_millis(500),
+            min_points_in_field: 3,
+            self_filter_radius_meters: 0.1,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub enum EStopReason {
+    ProtectiveField,
+    StaleLidar,
+}
+
+pub struct SafetyMonitor {
+    pub params: SafetyParams,
+    latched: Option<EStopReason>,
+    /// None until the lidar sends its first scan
+    last_scan: Option<Duration>,
+}
+
+impl SafetyMonitor {
+    pub fn new(params: SafetyParams) -> Self {
+        Self { params, latched: None, last_scan: None }
+    }
+
+    /// the protective field at these speeds. vertices on the side the robot is driving towards get pushed out.
+    // TODO turning in place sweeps the corners around too, this only accounts for driving forward and backward
+    pub fn field(&self, speeds: &Twist2d) -> Vec<Vector2<f64>> {
+        let stretch = speeds.dx * self.params.stopping_time_seconds;
+        self.params
+            .field
+            .iter()
+            .map(|vertex| if vertex[0] * stretch > 0.0 { vertex + Vector2::new(stretch, 0.0) } else { *vertex })
+            .collect()
+    }
+
+    /// checks a robot frame scan against the field for the speeds the robot is driving at. returns true if it tripped.
+    pub fn update_scan(&mut self, scan: &[Vector2<f64>], speeds: &Twist2d, now: Duration) -> bool {
+        self.last_scan = Some(now);
+        let field = self.field(speeds);
+        let violations = scan
+            .iter()
+            .filter(|point| point.norm() > self.params.self_filter_radius_meters && point_in_polygon(&field, point))
+            .count();
+        if violations >= self.params.min_points_in_field && self.latched.is_none() {
+            self.latched = Some(EStopReason::ProtectiveField);
+        }
+        self.latched.is_some()
+    }
+
+    /// checks that scans are still coming in. returns true if it tripped.
+    pub fn update(&mut self, now: Duration) -> bool {
+        if let Some(last_scan) = self.last_scan {
+            if now.saturating_sub(last_scan) > self.params.max_scan_age && self.latched.is_none() {
+                self.latched = Some(EStopReason::StaleLidar);
+            }
+        }
+        self.latched.is_some()
+    }
+
+    pub fn estop_reason(&self) -> Option<EStopReason> {
+        self.latched
+    }
+
+    /// zero if the robot isn't allowed to move, otherwise speeds unchanged.
+    /// before the first scan the robot can't see anything, so it doesn't move then either, but that doesn't latch.
+    pub fn limit(&self, speeds: Twist2d) -> Twist2d {
+        if self.latched.is_some() || self.last_scan.is_none() {
+            Twist2d::ZERO
+        } else {
+            speeds
+        }
+    }
+
+    /// unlatches the e-stop. if whatever tripped it is still there, the next update trips it again.
+    pub fn clear(&mut self) {
+        self.latched = None;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn monitor() -> SafetyMonitor {
+        SafetyMonitor::new(SafetyParams::default())
+    }
+
+    fn cluster(x: f64, y: f64) -> Vec<Vector2<f64>> {
+        (0..5).map(|i| Vector2::new(x, y + 0.01 * i as f64)).collect()
+    }
+
+    #[test]
+    fn test_field_scales_with_speed() {
+        let obstacle = cluster(0.35, 0.0);
+        assert!(!monitor().update_scan(&obstacle, &Twist2d::ZERO, Duration::ZERO));
+        let mut fast = monitor();
+        assert!(fast.update_scan(&obstacle, &Twist2d::new(0.3, 0.0, 0.0), Duration::ZERO));
+        assert_eq!(fast.estop_reason(), Some(EStopReason::ProtectiveField));
+        // backing up stretches the back instead
+        let backwards = monitor().field(&Twist2d::new(-0.3, 0.0, 0.0));
+        assert!(backwards.iter().all(|vertex| vertex[0] < 0.3));
+        assert!(backwards.iter().any(|vertex| vertex[0] < -0.3));
+    }
+
+    #[test]
+    fn test_latches_until_cleared() {
+        let mut monitor = monitor();
+        let speeds = Twist2d::new(0.2, 0.0, 0.0);
+        assert_eq!(monitor.limit(speeds.clone()).dx, 0.0, "shouldn't move before the lidar works");
+        assert!(!monitor.update_scan(&cluster(1.0, 0.0), &speeds, Duration::ZERO));
+        assert_eq!(monitor.limit(speeds.clone()).dx, 0.2);
+        // two stray points are noise, the robot seeing itself doesn't count either
+        let mut noise = cluster(0.2, 0.0)[..2].to_vec();
+        noise.extend(cluster(0.05, 0.0));
+        assert!(!monitor.update_scan(&noise, &speeds, Duration::from_millis(100)));
+        assert!(monitor.update_scan(&cluster(0.2, 0.0), &speeds, Duration::from_millis(200)));
+        // the obstacle left but it stays stopped
+        assert!(monitor.update_scan(&cluster(1.0, 0.0), &speeds, Duration::from_millis(300)));
+        assert_eq!(monitor.limit(speeds.clone()).dx, 0.0);
+        monitor.clear();
+        assert!(!monitor.update_scan(&cluster(1.0, 0.0), &speeds, Duration::from_millis(400)));
+        assert_eq!(monitor.limit(speeds).dx, 0.2);
+    }
+
+    #[test]
+    fn test_trips_on_stale_lidar() {
+        let mut monitor = monitor();
+        // no scan yet doesn't latch, it just doesn't move
+        assert!(!monitor.update(Duration::from_secs(5)));
+        monitor.update_scan(&[], &Twist2d::ZERO, Duration::from_secs(5));
+        assert!(!monitor.update(Duration::from_millis(5400)));
+        assert!(monitor.update(Duration::from_millis(5600)));
+        assert_eq!(monitor.estop_reason(), Some(EStopReason::StaleLidar));
+        // scans coming back doesn't unlatch it
+        monitor.update_scan(&[], &Twist2d::ZERO, Duration::from_millis(5700));
+        assert!(monitor.update(Duration::from_millis(5700)));
+    }
+}
@@ -0,0 +1,101 @@
+//! a tiny 2d lidar simulator so scan matching, mapping and localization can be tested without the robot,
+//! plus the other bits of world the tests keep needing.
+
+use nalgebra::Vector2;
+use tokio::time::Duration;
+
+use crate::{
+    costmap::{Costmap, CostmapParams},
+    geometry::Transform2d,
+    lidar::ROBOT_TO_LIDAR,
+    occupancy_grid::OccupancyGridConfig,
+};
+
+pub type Wall = (Vector2<f64>, Vector2<f64>);
+
+/// a 6m x 4m room with a box in one corner and a pillar in the middle, with the origin in the middle of the room
+pub fn room_walls() -> Vec<Wall> {
+    let corners = |points: &[(f64, f64)]| {
+        (0..points.len())
+            .map(|i| {
+                let (x1, y1) = points[i];
+                let (x2, y2) = points[(i + 1) % points.len()];
+                (Vector2::new(x1, y1), Vector2::new(x2, y2))
+            })
+            .collect::<Vec<_>>()
+    };
+    let mut walls = corners(&[(-3.0, -2.0), (3.0, -2.0), (3.0, 2.0), (-3.0, 2.0)]);
+    walls.extend(corners(&[(1.8, 0.9), (2.6, 0.9), (2.6, 1.6), (1.8, 1.6)]));
+    walls.extend(corners(&[(-0.9, -0.6), (-0.6, -0.6), (-0.6, -0.3), (-0.9, -0.3)]));
+    walls
+}
+
+/// casts num_rays evenly spaced rays from the lidar and returns the hits in the robot frame, ordered by angle.
+/// rays that don't hit anything within max_range are dropped, just like the real lidar drops them.
+pub fn simulate_scan(walls: &[Wall], world_to_robot: &Transform2d, num_rays: usize, max_range: f64) -> Vec<Vector2<f64>> {
+    let lidar_offset = Transform2d::new(ROBOT_TO_LIDAR.x_meters, ROBOT_TO_LIDAR.y_meters, 0.0);
+    let world_to_lidar = world_to_robot.clone() + lidar_offset.clone();
+    let origin = Vector2::new(world_to_lidar.x_meters, world_to_lidar.y_meters);
+    let mut points = Vec::with_capacity(num_rays);
+    for i in 0..num_rays {
+        let angle = i as f64 / num_rays as f64 * 2.0 * std::f64::consts::PI;
+        let direction = Vector2::new((world_to_lidar.theta_radians + angle).cos(), (world_to_lidar.theta_radians + angle).sin());
+        let range = walls
+            .iter()
+            .filter_map(|(a, b)| ray_segment_intersection(&origin, &direction, a, b))
+            .fold(f64::INFINITY, f64::min);
+        if range <= max_range {
+            let robot_to_hit = lidar_offset.clone() + Transform2d::new(range * angle.cos(), range * angle.sin(), 0.0);
+            points.push(Vector2::new(robot_to_hit.x_meters, robot_to_hit.y_meters));
+        }
+    }
+    points
+}
+
+/// timestamps in tests are easier to read in milliseconds
+pub fn ms(millis: u64) -> Duration {
+    Duration::from_millis(millis)
+}
+
+/// a 6m x 6m costmap centered on the origin that hasn't seen anything yet
+pub fn empty_costmap() -> Costmap {
+    let mut costmap = Costmap::new(OccupancyGridConfig { origin_x_meters: -3.0, origin_y_meters: -3.0, width_cells: 120, height_cells: 120, ..Default::default() }, CostmapParams::default());
+    costmap.update();
+    costmap
+}
+
+/// counterclockwise corners of an axis aligned square
+pub fn square(min: f64, max: f64) -> Vec<Vector2<f64>> {
+    vec![Vector2::new(min, min), Vector2::new(max, min), Vector2::new(max, max), Vector2::new(min, max)]
+}
+
+/// distance along the ray to the segment, if they intersect
+fn ray_segment_intersection(origin: &Vector2<f64>, direction: &Vector2<f64>, a: &Vector2<f64>, b: &Vector2<f64>) -> Option<f64> {
+    let segment = b - a;
+    let denominator = direction.perp(&segment);
+    if denominator.abs() < 1e-12 {
+        return None;
+    }
+    let to_a = a - origin;
+    let t = to_a.perp(&segment) / denominator;
+    let u = to_a.perp(direction) / denominator;
+    if t > 0.0 && (0.0..=1.0).contains(&u) {
+        Some(t)
+    } else {
+        None
+    }
+}
+
+#[test]
+fn test_simulate_scan() {
+    let walls = vec![(Vector2::new(2.0, -10.0), Vector2::new(2.0, 10.0))];
+    let scan = simulate_scan(&walls, &Transform2d::ZERO, 360, 10.0);
+    // every hit is on the wall, and only the rays pointing forward hit it
+    assert!(!scan.is_empty() && scan.len() < 180);
+    for point in &scan {
+        assert!((point[0] - 2.0).abs() < 1e-9);
+    }
+    // moving the robot moves the wall the other way in the robot frame
+    let scan = simulate_scan(&walls, &Transform2d::new(0.5, 0.0, 0.0), 360, 10.0);
+    assert!(scan.iter().all(|point| (point[0] - 1.5).abs() < 1e-9));
+}
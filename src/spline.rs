@@ -0,0 +1,252 @@
+//! smooth paths through waypoints: clamped cubic and quintic hermite splines (like wpilib's), reparameterized by arc length
+//! so they can be sampled every few centimeters for the trajectory parameterizer.
+
+use nalgebra::Vector2;
+
+use crate::{geometry::Transform2d, paths::Path, trajectory::PoseWithCurvature};
+
+/// how much longer the tangents are than the distance between the waypoints, same as wpilib
+const TANGENT_SCALE: f64 = 1.2;
+/// samples per segment in the arc length table
+const ARC_LENGTH_SAMPLES: usize = 200;
+
+/// one piece of a spline, x(u) and y(u) for u from 0 to 1. coefficients are lowest order first.
+#[derive(Debug, Clone)]
+struct SplineSegment {
+    x: [f64; 6],
+    y: [f64; 6],
+}
+
+impl SplineSegment {
+    fn cubic(p0: Vector2<f64>, t0: Vector2<f64>, p1: Vector2<f64>, t1: Vector2<f64>) -> Self {
+        let coefficients = |p0: f64, t0: f64, p1: f64, t1: f64| [p0, t0, -3.0 * p0 - 2.0 * t0 + 3.0 * p1 - t1, 2.0 * p0 + t0 - 2.0 * p1 + t1, 0.0, 0.0];
+        Self { x: coefficients(p0[0], t0[0], p1[0], t1[0]), y: coefficients(p0[1], t0[1], p1[1], t1[1]) }
+    }
+
+    /// the second derivative is zero at both ends, so the curvature is too
+    fn quintic(p0: Vector2<f64>, t0: Vector2<f64>, p1: Vector2<f64>, t1: Vector2<f64>) -> Self {
+        let coefficients = |p0: f64, t0: f64, p1: f64, t1: f64| {
+            [p0, t0, 0.0, -10.0 * p0 - 6.0 * t0 + 10.0 * p1 - 4.0 * t1, 15.0 * p0 + 8.0 * t0 - 15.0 * p1 + 7.0 * t1, -6.0 * p0 - 3.0 * t0 + 6.0 * p1 - 3.0 * t1]
+        };
+        Self { x: coefficients(p0[0], t0[0], p1[0], t1[0]), y: coefficients(p0[1], t0[1], p1[1], t1[1]) }
+    }
+
+    /// the derivative-th derivative at u
+    fn evaluate(&self, u: f64, derivative: usize) -> Vector2<f64> {
+        let polynomial = |coefficients: &[f64; 6]| {
+            (derivative..6)
+                .map(|power| {
+                    let factor = (power - derivative + 1..=power).product::<usize>() as f64;
+                    coefficients[power] * factor * u.powi((power - derivative) as i32)
+                })
+                .sum::<f64>()
+        };
+        Vector2::new(polynomial(&self.x), polynomial(&self.y))
+    }
+}
+
+/// a chain of spline segments that's continuous in position and heading
+#[derive(Debug, Clone)]
+pub struct Spline {
+    segments: Vec<SplineSegment>,
+    /// (distance along the spline, segment, u) at regular steps of u, for arc length reparameterization
+    arc_lengths: Vec<(f64, usize, f64)>,
+}
+
+fn heading_vector(waypoint: &Transform2d, scale: f64) -> Vector2<f64> {
+    Vector2::new(waypoint.theta_radians.cos(), waypoint.theta_radians.sin()) * scale
+}
+
+fn translation(waypoint: &Transform2d) -> Vector2<f64> {
+    Vector2::new(waypoint.x_meters, waypoint.y_meters)
+}
+
+impl Spline {
+    fn new(segments: Vec<SplineSegment>) -> Self {
+        let mut arc_lengths = vec![(0.0, 0, 0.0)];
+        let mut length = 0.0;
+        for (index, segment) in segments.iter().enumerate() {
+            let mut previous = segment.evaluate(0.0, 0);
+            for step in 1..=ARC_LENGTH_SAMPLES {
+                let u = step as f64 / ARC_LENGTH_SAMPLES as f64;
+                let point = segment.evaluate(u, 0);
+                length += (point - previous).norm();
+                previous = point;
+                arc_lengths.push((length, index, u));
+            }
+        }
+        Self { segments, arc_lengths }
+    }
+
+    /// a quintic between every pair of waypoints, leaving each waypoint in the direction it's facing.
+    /// curvature is zero at the waypoints, so it's continuous too.
+    pub fn quintic_hermite(waypoints: &[Transform2d]) -> Self {
+        assert!(waypoints.len() >= 2);
+        Self::new(
+            waypoints
+                .windows(2)
+                .map(|pair| {
+                    let (p0, p1) = (translation(&pair[0]), translation(&pair[1]));
+                    let scale = TANGENT_SCALE * (p1 - p0).norm();
+                    SplineSegment::quintic(p0, heading_vector(&pair[0], scale), p1, heading_vector(&pair[1], scale))
+                })
+                .collect(),
+        )
+    }
+
+    /// cubic spline through the points whose headings are only fixed at the start and end (that's the clamped part).
+    /// the headings in between are picked so that curvature is continuous everywhere.
+    pub fn clamped_cubic(start: &Transform2d, interior: &[Vector2<f64>], end: &Transform2d) -> Self {
+        let mut points = vec![translation(start)];
+        points.extend_from_slice(interior);
+        points.push(translation(end));
+        let n = points.len() - 1;
+        let mut tangents = vec![Vector2::zeros(); n + 1];
+        tangents[0] = heading_vector(start, TANGENT_SCALE * (points[1] - points[0]).norm());
+        tangents[n] = heading_vector(end, TANGENT_SCALE * (points[n] - points[n - 1]).norm());
+        if n >= 2 {
+            // matching second derivatives at every interior point gives t[i - 1] + 4 t[i] + t[i + 1] = 3 (p[i + 1] - p[i - 1]),
+            // which is tridiagonal, so it's solved with the thomas algorithm
+            let unknowns = n - 1;
+            let mut diagonal = vec![4.0; unknowns];
+            let mut rhs = (1..n).map(|i| 3.0 * (points[i + 1] - points[i - 1])).collect::<Vec<_>>();
+            rhs[0] -= tangents[0];
+            rhs[unknowns - 1] -= tangents[n];
+            for i in 1..unknowns {
+                let factor = 1.0 / diagonal[i - 1];
+                diagonal[i] -= factor;
+                let previous = rhs[i - 1];
+                rhs[i] -= previous * factor;
+            }
+            tangents[unknowns] = rhs[unknowns - 1] / diagonal[unknowns - 1];
+            for i in (0..unknowns - 1).rev() {
+                tangents[i + 1] = (rhs[i] - tangents[i + 2]) / diagonal[i];
+            }
+        }
+        Self::new((0..n).map(|i| SplineSegment::cubic(points[i], tangents[i], points[i + 1], tangents[i + 1])).collect())
+    }
+
+    pub fn length(&self) -> f64 {
+        self.arc_lengths.last().unwrap().0
+    }
+
+    /// (segment, u) of the point distance_meters along the spline
+    fn parameter_at(&self, distance_meters: f64) -> (usize, f64) {
+        let distance = distance_meters.clamp(0.0, self.length());
+        let high = self.arc_lengths.partition_point(|(length, _, _)| *length < distance).clamp(1, self.arc_lengths.len() - 1);
+        let (low_length, _, low_u) = self.arc_lengths[high - 1];
+        let (high_length, segment, high_u) = self.arc_lengths[high];
+        // the table wraps from u = 1 of one segment to u = 1/ARC_LENGTH_SAMPLES of the next
+        let low_u = if high_u < low_u { 0.0 } else { low_u };
+        let fraction = if high_length > low_length { (distance - low_length) / (high_length - low_length) } else { 0.0 };
+        (segment, low_u + (high_u - low_u) * fraction)
+    }
+
+    /// pose and curvature distance_meters along the spline, facing along it
+    pub fn sample_at_distance(&self, distance_meters: f64) -> PoseWithCurvature {
+        let (segment, u) = self.parameter_at(distance_meters);
+        let segment = &self.segments[segment];
+        let position = segment.evaluate(u, 0);
+        let velocity = segment.evaluate(u, 1);
+        let acceleration = segment.evaluate(u, 2);
+        let speed = velocity.norm();
+        let curvature = if speed > 1e-9 { velocity.perp(&acceleration) / speed.powi(3) } else { 0.0 };
+        PoseWithCurvature {
+            pose: Transform2d::new(position[0], position[1], velocity[1].atan2(velocity[0])),
+            curvature_radians_per_meter: curvature,
+        }
+    }
+
+    /// evenly spaced samples from the start to the end (both included)
+    pub fn sample(&self, spacing_meters: f64) -> Vec<PoseWithCurvature> {
+        let steps = (self.length() / spacing_meters).ceil().max(1.0) as usize;
+        (0..=steps).map(|step| self.sample_at_distance(self.length() * step as f64 / steps as f64)).collect()
+    }
+
+    pub fn to_path(&self, spacing_meters: f64) -> Path {
+        Path { waypoints: self.sample(spacing_meters).into_iter().map(|sample| sample.pose).collect() }
+    }
+
+    /// clamped cubic through every waypoint of the path, heading along its first and last segments.
+    /// the headings of the waypoints themselves are ignored, the start and goal headings are for turning in place.
+    pub fn through_path(path: &Path) -> Self {
+        let points = path.waypoints.iter().map(translation).collect::<Vec<_>>();
+        assert!(points.len() >= 2);
+        let heading = |from: &Vector2<f64>, to: &Vector2<f64>| (to[1] - from[1]).atan2(to[0] - from[0]);
+        let last = points.len() - 1;
+        Self::clamped_cubic(
+            &Transform2d::new(points[0][0], points[0][1], heading(&points[0], &points[1])),
+            &points[1..last],
+            &Transform2d::new(points[last][0], points[last][1], heading(&points[last - 1], &points[last])),
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::f64::consts::PI;
+
+    use super::*;
+    use crate::geometry::wrap_angle;
+    use assert_approx_eq::assert_approx_eq;
+
+    #[test]
+    fn test_hermite_passes_through_waypoints() {
+        let waypoints = [Transform2d::new(0.0, 0.0, 0.0), Transform2d::new(1.0, 1.0, PI / 2.0), Transform2d::new(0.0, 2.0, PI)];
+        let spline = Spline::quintic_hermite(&waypoints);
+        for (index, segment) in spline.segments.iter().enumerate() {
+            for (u, waypoint) in [(0.0, &waypoints[index]), (1.0, &waypoints[index + 1])] {
+                assert!((segment.evaluate(u, 0) - translation(waypoint)).norm() < 1e-9);
+                let velocity = segment.evaluate(u, 1);
+                assert_approx_eq!(wrap_angle(velocity[1].atan2(velocity[0]) - waypoint.theta_radians), 0.0, 1e-9);
+            }
+        }
+        let end = spline.sample_at_distance(spline.length());
+        assert!((translation(&end.pose) - Vector2::new(0.0, 2.0)).norm() < 1e-9);
+        // it doesn't curve at the waypoints
+        assert_approx_eq!(spline.sample_at_distance(0.0).curvature_radians_per_meter, 0.0, 1e-9);
+    }
+
+    #[test]
+    fn test_clamped_cubic_is_smooth() {
+        let interior = [Vector2::new(1.0, 0.5), Vector2::new(2.0, -0.3), Vector2::new(3.0, 0.4)];
+        let spline = Spline::clamped_cubic(&Transform2d::new(0.0, 0.0, 0.0), &interior, &Transform2d::new(4.0, 0.0, 0.0));
+        assert_eq!(spline.segments.len(), 4);
+        for pair in spline.segments.windows(2) {
+            assert!((pair[0].evaluate(1.0, 0) - pair[1].evaluate(0.0, 0)).norm() < 1e-9);
+            assert!((pair[0].evaluate(1.0, 1) - pair[1].evaluate(0.0, 1)).norm() < 1e-9);
+            assert!((pair[0].evaluate(1.0, 2) - pair[1].evaluate(0.0, 2)).norm() < 1e-9);
+        }
+        let end = spline.segments[3].evaluate(1.0, 1);
+        assert_approx_eq!(end[1].atan2(end[0]), 0.0, 1e-9);
+
+        // fitting a spline through the samples of another one gives (almost) the same curve back
+        let refit = Spline::through_path(&spline.to_path(0.1));
+        assert_approx_eq!(refit.length(), spline.length(), 1e-3);
+        assert_approx_eq!(refit.sample_at_distance(2.0).curvature_radians_per_meter, spline.sample_at_distance(2.0).curvature_radians_per_meter, 0.05);
+    }
+
+    #[test]
+    fn test_arc_length_and_curvature() {
+        let line = Spline::quintic_hermite(&[Transform2d::new(0.0, 0.0, 0.5), Transform2d::new(2.0 * 0.5f64.cos(), 2.0 * 0.5f64.sin(), 0.5)]);
+        assert_approx_eq!(line.length(), 2.0, 1e-9);
+        assert_approx_eq!(line.sample_at_distance(0.5).pose.x_meters, 0.5 * 0.5f64.cos(), 1e-4);
+        assert_approx_eq!(line.sample_at_distance(1.0).curvature_radians_per_meter, 0.0, 1e-9);
+
+        // a circle of radius 2 from quarter circle hermite pieces, which is very close to a real circle
+        let circle = (0..=4).map(|i| {
+            let angle = i as f64 * PI / 2.0;
+            Transform2d::new(2.0 * angle.cos(), 2.0 * angle.sin(), angle + PI / 2.0)
+        }).collect::<Vec<_>>();
+        let spline = Spline::quintic_hermite(&circle);
+        assert!((spline.length() - 4.0 * PI).abs() < 0.05 * 4.0 * PI, "{}", spline.length());
+        let samples = spline.sample(0.1);
+        let mean_curvature = samples.iter().map(|sample| sample.curvature_radians_per_meter).sum::<f64>() / samples.len() as f64;
+        assert!((mean_curvature - 0.5).abs() < 0.05, "{}", mean_curvature);
+        // the samples are evenly spaced along the curve
+        for pair in samples.windows(2) {
+            let spacing = (translation(&pair[1].pose) - translation(&pair[0].pose)).norm();
+            assert!((spacing - spline.length() / (samples.len() - 1) as f64).abs() < 1e-3);
+        }
+    }
+}
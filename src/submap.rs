@@ -0,0 +1,212 @@
+//! cartographer style local submaps: a handful of consecutive scans fused into a small probability grid.
+//! new scans get matched against the submap they're being inserted into instead of against the previous scan, which drifts a lot less,
+//! and finished submaps are what new scans get matched against to find loop closures.
+
+use std::collections::BTreeSet;
+
+use nalgebra::{Matrix3, Vector2, Vector3};
+
+use crate::{
+    correlative::{correlative_then_icp, CorrelativeMatcherParams, CorrelativeScanMatcher},
+    geometry::{wrap_angle, Transform2d},
+    icp::{IcpParams, IcpVariant},
+    occupancy_grid::{OccupancyGrid, OccupancyGridConfig, OCCUPIED_THRESHOLD},
+};
+
+#[derive(Debug, Clone)]
+pub struct SubmapParams {
+    /// a submap is finished once this many scans were inserted into it
+    pub scans_per_submap: usize,
+    /// grid of a submap that's still being built, in the submap frame. it gets cropped once the submap is finished.
+    pub grid: OccupancyGridConfig,
+    /// matching new scans against the current submap only has to fix up odometry error, so the window is small
+    pub local_matcher: CorrelativeMatcherParams,
+    /// loop closures have to deal with all the drift since the robot was last here
+    pub loop_closure_matcher: CorrelativeMatcherParams,
+    pub icp: IcpParams,
+    /// only finished submaps whose origin is this close to a new node are searched for loop closures
+    pub loop_closure_search_radius_meters: f64,
+    /// searching for loop closures is slow, so only every n-th node does it
+    pub loop_closure_every_n_nodes: usize,
+}
+
+impl Default for SubmapParams {
+    fn default() -> Self {
+        Self {
+            scans_per_submap: 20,
+            // finer than the global map because the cell centers are what scans get matched against
+            grid: OccupancyGridConfig {
+                resolution_meters: 0.025,
+                origin_x_meters: -8.0,
+                origin_y_meters: -8.0,
+                width_cells: 640,
+                height_cells: 640,
+                ..Default::default()
+            },
+            local_matcher: CorrelativeMatcherParams {
+                linear_window_meters: 0.2,
+                angular_window_radians: 0.15,
+                depth: 3,
+                ..Default::default()
+            },
+            loop_closure_matcher: CorrelativeMatcherParams {
+                min_score: 0.55,
+                ..Default::default()
+            },
+            icp: IcpParams {
+                // the points of a grid aren't in scan order so the normals of point to line don't work
+                variant: IcpVariant::PointToPoint,
+                max_correspondence_distance: 0.1,
+                ..Default::default()
+            },
+            loop_closure_search_radius_meters: 3.0,
+            loop_closure_every_n_nodes: 5,
+        }
+    }
+}
+
+pub struct Submap {
+    /// the pose graph node the submap frame is attached to, so the submap moves along with it when the graph is optimized
+    pub anchor: usize,
+    /// usually zero, unless the node the submap started at got pruned and it had to be attached to another one
+    pub anchor_to_submap: Transform2d,
+    pub grid: OccupancyGrid,
+    pub num_scans: usize,
+    pub finished: bool,
+    /// occupied cells of the grid as (y, x), kept up to date on every insert so the whole grid doesn't have to be scanned
+    occupied: BTreeSet<(i64, i64)>,
+    /// centers of the occupied cells, which is what scans get matched against
+    points: Vec<Vector2<f64>>,
+    /// finished submaps don't change but get searched for loop closures over and over, so their matcher is built once
+    matcher: Option<CorrelativeScanMatcher>,
+}
+
+#[derive(Debug, Clone)]
+pub struct SubmapMatch {
+    /// pose of the scan in the submap frame
+    pub submap_to_robot: Transform2d,
+    pub information: Matrix3<f64>,
+    /// how well the scan lines up with the submap, from the correlative match
+    pub score: f64,
+}
+
+impl Submap {
+    pub fn new(anchor: usize, config: OccupancyGridConfig) -> Self {
+        Self {
+            anchor,
+            anchor_to_submap: Transform2d::ZERO,
+            grid: OccupancyGrid::new(config),
+            num_scans: 0,
+            finished: false,
+            occupied: BTreeSet::new(),
+            points: Vec::new(),
+            matcher: None,
+        }
+    }
+
+    pub fn insert(&mut self, submap_to_robot: &Transform2d, scan: &[Vector2<f64>]) {
+        assert!(!self.finished, "tried to insert a scan into a finished submap");
+        self.grid.integrate_scan(submap_to_robot, scan);
+        self.num_scans += 1;
+        // only the cells the scan hit can have become occupied, and misses can only clear cells that already were
+        self.occupied.extend(scan.iter().map(|point| {
+            let hit = submap_to_robot.clone() + Transform2d::new(point[0], point[1], 0.0);
+            let (x, y) = self.grid.world_to_cell(&Vector2::new(hit.x_meters, hit.y_meters));
+            (y, x)
+        }));
+        self.occupied.retain(|(y, x)| self.grid.probability(*x, *y) > OCCUPIED_THRESHOLD);
+        self.points = self.occupied.iter().map(|(y, x)| self.grid.cell_to_world(*x, *y)).collect();
+    }
+
+    /// freezes the submap. nothing gets inserted after this, so the grid can shrink down to what was actually seen
+    /// and the loop closure matcher only has to be built once.
+    pub fn finish(&mut self, matcher_params: CorrelativeMatcherParams) {
+        self.finished = true;
+        self.grid = self.grid.cropped();
+        // cropping moved the cells, the points stay where they are
+        self.occupied.clear();
+        self.matcher = Some(CorrelativeScanMatcher::new(&self.points, matcher_params));
+    }
+
+    /// the matcher built when the submap finished
+    pub fn matcher(&self) -> Option<&CorrelativeScanMatcher> {
+        self.matcher.as_ref()
+    }
+
+    /// a matcher for the submap as it is now, for matching against the one still being built
+    pub fn build_matcher(&self, params: CorrelativeMatcherParams) -> CorrelativeScanMatcher {
+        CorrelativeScanMatcher::new(&self.points, params)
+    }
+
+    /// matches a robot frame scan against the submap, searching the matcher's window around submap_to_guess.
+    /// None if nothing scored high enough or the geometry doesn't pin down the pose (like in a corridor).
+    pub fn match_scan(&self, scan: &[Vector2<f64>], submap_to_guess: &Transform2d, matcher: &CorrelativeScanMatcher, icp_params: &IcpParams) -> Option<SubmapMatch> {
+        if self.points.len() < 3 {
+            return None;
+        }
+        let (refined, correlative_match) = correlative_then_icp(matcher, &self.points, scan, submap_to_guess, icp_params)?;
+        if refined.degenerate || !refined.converged {
+            return None;
+        }
+        // the points should end up right on the walls, a big residual means it lined up with the wrong ones
+        if refined.residual > matcher.params().sigma_meters {
+            return None;
+        }
+        let mut submap_to_robot = refined.to_transform();
+        // icp should only polish the correlative match, if it wandered off it found some other local minimum
+        let correction = -correlative_match.pose.clone() + submap_to_robot.clone();
+        if correction.norm() > 2.0 * matcher.params().resolution_meters || wrap_angle(correction.theta_radians).abs() > 0.05 {
+            return None;
+        }
+        submap_to_robot.theta_radians = wrap_angle(submap_to_robot.theta_radians);
+        let information = correlative_match.covariance.try_inverse().unwrap_or(Matrix3::from_diagonal(&Vector3::new(1.0, 1.0, 1.0)));
+        Some(SubmapMatch { submap_to_robot, information, score: correlative_match.score })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::sim::{room_walls, simulate_scan};
+
+    #[test]
+    fn test_match_scan_against_submap() {
+        let walls = room_walls();
+        let params = SubmapParams::default();
+        let mut submap = Submap::new(0, params.grid.clone());
+        for i in 0..5 {
+            let pose = Transform2d::new(0.1 * i as f64, 0.05 * i as f64, 0.1 * i as f64);
+            submap.insert(&pose, &simulate_scan(&walls, &pose, 720, 12.0));
+        }
+        submap.finish(params.loop_closure_matcher.clone());
+        assert!(submap.grid.width() < 320 && submap.grid.height() < 240);
+        assert_eq!(submap.points.len(), submap.grid.occupied_points().len());
+
+        let truth = Transform2d::new(0.6, -0.3, 0.4);
+        let scan = simulate_scan(&walls, &truth, 720, 12.0);
+        let guess = truth.clone() + Transform2d::new(0.1, -0.1, 0.08);
+        let result = submap.match_scan(&scan, &guess, &submap.build_matcher(params.local_matcher.clone()), &params.icp).unwrap();
+        let error = -truth + result.submap_to_robot;
+        // scans get matched against cell centers, so this is about as good as it gets
+        assert!(error.norm() < 0.02 && error.theta_radians.abs() < 0.01, "{:?}", error);
+        assert!(result.information.symmetric_eigenvalues().iter().all(|eigenvalue| *eigenvalue > 0.0));
+    }
+
+    #[test]
+    fn test_crop_keeps_cells() {
+        let walls = room_walls();
+        let params = SubmapParams::default();
+        let mut submap = Submap::new(0, params.grid.clone());
+        submap.insert(&Transform2d::ZERO, &simulate_scan(&walls, &Transform2d::ZERO, 360, 12.0));
+        let uncropped = submap.grid.clone();
+        assert_eq!(submap.points, uncropped.occupied_points());
+        submap.finish(params.loop_closure_matcher);
+        assert!(submap.matcher().is_some());
+        for point in [Vector2::new(2.98, 0.0), Vector2::new(0.0, -1.98), Vector2::new(1.0, 1.0)] {
+            let (x, y) = uncropped.world_to_cell(&point);
+            let (cropped_x, cropped_y) = submap.grid.world_to_cell(&point);
+            assert_eq!(uncropped.probability(x, y), submap.grid.probability(cropped_x, cropped_y));
+        }
+        assert_eq!(submap.grid.occupied_points().len(), uncropped.occupied_points().len());
+    }
+}
@@ -0,0 +1,245 @@
+//! time parameterized trajectories, so followers know where the robot should be at a given time and how fast it should be going there.
+//! the parameterizer works like wpilib's: limit the velocity at every sample, then a forward and a backward pass to respect acceleration.
+
+use serde::Serialize;
+
+use crate::{
+    drivetrain::{XAVIERBOT_MAX_SPEED_FEASIBLE, XAVIERBOT_WHEEL_SEPARATION_METERS},
+    geometry::Transform2d,
+    utils::Interpolate,
+};
+
+#[derive(Debug, Clone)]
+pub struct TrajectoryConfig {
+    pub max_velocity_meters_per_second: f64,
+    pub max_acceleration_meters_per_second_squared: f64,
+    /// caps velocity in turns to sqrt(max_centripetal_acceleration / curvature)
+    pub max_centripetal_acceleration_meters_per_second_squared: f64,
+    /// the outer wheel goes faster than the robot in turns, neither wheel may go faster than this
+    pub max_wheel_velocity_meters_per_second: f64,
+    pub wheel_separation_meters: f64,
+    pub start_velocity_meters_per_second: f64,
+    pub end_velocity_meters_per_second: f64,
+}
+
+impl Default for TrajectoryConfig {
+    fn default() -> Self {
+        Self {
+            max_velocity_meters_per_second: 0.3,
+            max_acceleration_meters_per_second_squared: 0.3,
+            max_centripetal_acceleration_meters_per_second_squared: 0.3,
+            max_wheel_velocity_meters_per_second: XAVIERBOT_MAX_SPEED_FEASIBLE,
+            wheel_separation_meters: XAVIERBOT_WHEEL_SEPARATION_METERS,
+            start_velocity_meters_per_second: 0.0,
+            end_velocity_meters_per_second: 0.0,
+        }
+    }
+}
+
+impl TrajectoryConfig {
+    /// the fastest the robot may go at a point with this curvature
+    fn max_velocity_at(&self, curvature: f64) -> f64 {
+        let centripetal = (self.max_centripetal_acceleration_meters_per_second_squared / curvature.abs()).sqrt();
+        // outer wheel speed is v * (1 + |k| * separation / 2)
+        let wheel = self.max_wheel_velocity_meters_per_second / (1.0 + curvature.abs() * self.wheel_separation_meters / 2.0);
+        self.max_velocity_meters_per_second.min(centripetal).min(wheel)
+    }
+}
+
+/// a pose on a path and how curved the path is there (positive is counterclockwise)
+#[derive(Debug, Clone)]
+pub struct PoseWithCurvature {
+    pub pose: Transform2d,
+    pub curvature_radians_per_meter: f64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TrajectoryState {
+    pub time_seconds: f64,
+    pub pose: Transform2d,
+    pub velocity_meters_per_second: f64,
+    /// acceleration from this state to the next one
+    pub acceleration_meters_per_second_squared: f64,
+    pub curvature_radians_per_meter: f64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Trajectory {
+    pub states: Vec<TrajectoryState>,
+}
+
+impl Trajectory {
+    #[cfg(test)]
+    pub fn total_time(&self) -> f64 {
+        self.states.last().map_or(0.0, |state| state.time_seconds)
+    }
+
+    /// where the robot should be t seconds after the start. clamped to the first and last state, None if there are no states.
+    pub fn sample(&self, t: f64) -> Option<TrajectoryState> {
+        let (first, last) = (self.states.first()?, self.states.last()?);
+        if t <= first.time_seconds {
+            return Some(first.clone());
+        }
+        if t >= last.time_seconds {
+            return Some(last.clone());
+        }
+        // first state after t, there has to be one before it because of the checks above
+        let high_index = self.states.partition_point(|state| state.time_seconds <= t);
+        let (low, high) = (&self.states[high_index - 1], &self.states[high_index]);
+        let dt = t - low.time_seconds;
+        let velocity = low.velocity_meters_per_second + low.acceleration_meters_per_second_squared * dt;
+        let distance = low.velocity_meters_per_second * dt + 0.5 * low.acceleration_meters_per_second_squared * dt * dt;
+        let segment_length = (high.pose.x_meters - low.pose.x_meters).hypot(high.pose.y_meters - low.pose.y_meters);
+        let fraction = if segment_length > 0.0 { (distance / segment_length).clamp(0.0, 1.0) } else { (dt / (high.time_seconds - low.time_seconds)).clamp(0.0, 1.0) };
+        Some(TrajectoryState {
+            time_seconds: t,
+            pose: Transform2d::interpolate(&low.pose, &high.pose, fraction),
+            velocity_meters_per_second: velocity,
+            acceleration_meters_per_second_squared: low.acceleration_meters_per_second_squared,
+            curvature_radians_per_meter: low.curvature_radians_per_meter + (high.curvature_radians_per_meter - low.curvature_radians_per_meter) * fraction,
+        })
+    }
+}
+
+/// finds the fastest velocity profile along the samples that respects every limit in config. the robot only drives forwards.
+#[cfg(test)]
+pub fn time_parameterize(samples: &[PoseWithCurvature], config: &TrajectoryConfig) -> Trajectory {
+    time_parameterize_limited(samples, config, |_| f64::INFINITY)
+}
+
+/// like time_parameterize, with an extra velocity limit wherever the path goes, for speed limit zones
+pub fn time_parameterize_limited(samples: &[PoseWithCurvature], config: &TrajectoryConfig, max_velocity_at_pose: impl Fn(&Transform2d) -> f64) -> Trajectory {
+    // (distance along the path, sample, velocity)
+    let mut constrained = Vec::with_capacity(samples.len());
+    let mut distance = 0.0;
+    for sample in samples {
+        if let Some((_, previous, _)) = constrained.last() {
+            let previous: &PoseWithCurvature = previous;
+            let step = (sample.pose.x_meters - previous.pose.x_meters).hypot(sample.pose.y_meters - previous.pose.y_meters);
+            // duplicate samples would need infinite acceleration
+            if step < 1e-9 {
+                continue;
+            }
+            distance += step;
+        }
+        constrained.push((distance, sample.clone(), config.max_velocity_at(sample.curvature_radians_per_meter).min(max_velocity_at_pose(&sample.pose))));
+    }
+    // with nothing between the ends, both would be stuck at the (usually zero) start and end velocities and the robot would never get
+    // anywhere. a sample in the middle gives it something to speed up to.
+    if let [(_, start, _), (length, end, _)] = constrained.as_slice() {
+        let middle = PoseWithCurvature {
+            pose: Transform2d::interpolate(&start.pose, &end.pose, 0.5),
+            curvature_radians_per_meter: (start.curvature_radians_per_meter + end.curvature_radians_per_meter) / 2.0,
+        };
+        let limit = config.max_velocity_at(middle.curvature_radians_per_meter).min(max_velocity_at_pose(&middle.pose));
+        constrained.insert(1, (length / 2.0, middle, limit));
+    }
+    let Some(last) = constrained.len().checked_sub(1) else {
+        return Trajectory { states: Vec::new() };
+    };
+    let max_acceleration = config.max_acceleration_meters_per_second_squared;
+    constrained[0].2 = constrained[0].2.min(config.start_velocity_meters_per_second);
+    constrained[last].2 = constrained[last].2.min(config.end_velocity_meters_per_second);
+    for i in 1..constrained.len() {
+        let ds = constrained[i].0 - constrained[i - 1].0;
+        constrained[i].2 = constrained[i].2.min((constrained[i - 1].2.powi(2) + 2.0 * max_acceleration * ds).sqrt());
+    }
+    for i in (0..last).rev() {
+        let ds = constrained[i + 1].0 - constrained[i].0;
+        constrained[i].2 = constrained[i].2.min((constrained[i + 1].2.powi(2) + 2.0 * max_acceleration * ds).sqrt());
+    }
+
+    let mut states: Vec<TrajectoryState> = Vec::with_capacity(constrained.len());
+    let mut time = 0.0;
+    for i in 0..constrained.len() {
+        let (_, sample, velocity) = &constrained[i];
+        if let Some(previous) = states.last_mut() {
+            let ds = constrained[i].0 - constrained[i - 1].0;
+            let acceleration = (velocity.powi(2) - previous.velocity_meters_per_second.powi(2)) / (2.0 * ds);
+            // there's a middle sample, which can always go faster than zero, so only a zero velocity limit makes this zero
+            time += 2.0 * ds / (velocity + previous.velocity_meters_per_second);
+            previous.acceleration_meters_per_second_squared = acceleration;
+        }
+        states.push(TrajectoryState {
+            time_seconds: time,
+            pose: sample.pose.clone(),
+            velocity_meters_per_second: *velocity,
+            acceleration_meters_per_second_squared: 0.0,
+            curvature_radians_per_meter: sample.curvature_radians_per_meter,
+        });
+    }
+    Trajectory { states }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use assert_approx_eq::assert_approx_eq;
+
+    fn straight_line(length: f64) -> Vec<PoseWithCurvature> {
+        (0..=80).map(|i| PoseWithCurvature { pose: Transform2d::new(length * i as f64 / 80.0, 0.0, 0.0), curvature_radians_per_meter: 0.0 }).collect()
+    }
+
+    #[test]
+    fn test_trapezoidal_profile() {
+        let config = TrajectoryConfig { max_velocity_meters_per_second: 0.5, max_acceleration_meters_per_second_squared: 0.5, ..Default::default() };
+        let trajectory = time_parameterize(&straight_line(2.0), &config);
+        // 1s to speed up, 1s to slow down and 3s at full speed for the 1.5m in between
+        assert_approx_eq!(trajectory.total_time(), 5.0, 1e-6);
+        let state = trajectory.sample(0.5).unwrap();
+        assert_approx_eq!(state.velocity_meters_per_second, 0.25, 1e-6);
+        assert_approx_eq!(state.pose.x_meters, 0.0625, 1e-6);
+        assert_approx_eq!(trajectory.sample(2.5).unwrap().velocity_meters_per_second, 0.5, 1e-6);
+        assert_approx_eq!(trajectory.sample(4.5).unwrap().pose.x_meters, 2.0 - 0.0625, 1e-6);
+        assert_approx_eq!(trajectory.sample(10.0).unwrap().pose.x_meters, 2.0, 1e-9);
+        assert_eq!(trajectory.sample(10.0).unwrap().velocity_meters_per_second, 0.0);
+    }
+
+    #[test]
+    fn test_turn_limits() {
+        let config = TrajectoryConfig { max_velocity_meters_per_second: 1.0, max_acceleration_meters_per_second_squared: 10.0, ..Default::default() };
+        // quarter circle with a radius of 0.5m
+        let arc = (0..=100)
+            .map(|i| {
+                let angle = i as f64 / 100.0 * std::f64::consts::PI / 2.0;
+                PoseWithCurvature { pose: Transform2d::new(0.5 * angle.sin(), 0.5 - 0.5 * angle.cos(), angle), curvature_radians_per_meter: 2.0 }
+            })
+            .collect::<Vec<_>>();
+        let trajectory = time_parameterize(&arc, &config);
+        let limit = (0.3f64 / 2.0).sqrt().min(0.5 / (1.0 + 2.0 * 0.1));
+        let fastest = trajectory.states.iter().map(|state| state.velocity_meters_per_second).fold(0.0, f64::max);
+        assert_approx_eq!(fastest, limit, 1e-9);
+        for state in &trajectory.states {
+            let outer_wheel = state.velocity_meters_per_second * (1.0 + state.curvature_radians_per_meter.abs() * 0.1);
+            assert!(outer_wheel <= config.max_wheel_velocity_meters_per_second + 1e-9);
+        }
+        let json = serde_json::to_string(&trajectory).unwrap();
+        assert!(json.contains("time_seconds"));
+    }
+
+    #[test]
+    fn test_slows_down_in_limited_section() {
+        let config = TrajectoryConfig { max_velocity_meters_per_second: 0.5, max_acceleration_meters_per_second_squared: 0.5, ..Default::default() };
+        let trajectory = time_parameterize_limited(&straight_line(4.0), &config, |pose| if (1.5..2.5).contains(&pose.x_meters) { 0.2 } else { f64::INFINITY });
+        let velocity_at = |x: f64| trajectory.states.iter().find(|state| state.pose.x_meters >= x).unwrap().velocity_meters_per_second;
+        assert_approx_eq!(velocity_at(2.0), 0.2, 1e-9);
+        assert_approx_eq!(velocity_at(0.75), 0.5, 1e-9);
+        // already slowed down by the time it gets there
+        assert!(velocity_at(1.5) <= 0.2 + 1e-9);
+        assert!(trajectory.total_time() > time_parameterize(&straight_line(4.0), &config).total_time());
+    }
+
+    #[test]
+    fn test_shorter_than_sample_spacing() {
+        let config = TrajectoryConfig::default();
+        let ends = vec![
+            PoseWithCurvature { pose: Transform2d::ZERO, curvature_radians_per_meter: 0.0 },
+            PoseWithCurvature { pose: Transform2d::new(0.03, 0.0, 0.0), curvature_radians_per_meter: 0.0 },
+        ];
+        let trajectory = time_parameterize(&ends, &config);
+        // speeding up for half of it and slowing down for the other half
+        assert_approx_eq!(trajectory.total_time(), 2.0 * (0.03 / config.max_acceleration_meters_per_second_squared).sqrt(), 1e-9);
+        assert_approx_eq!(trajectory.sample(trajectory.total_time() / 2.0).unwrap().pose.x_meters, 0.015, 1e-9);
+        assert!(time_parameterize(&[], &config).sample(0.0).is_none());
+    }
+}
@@ -4,7 +4,13 @@ use serde::Serialize;
 use socketioxide::{extract::{Data, SocketRef, State}, SocketIo, SocketIoBuilder};
 use tower_http::services::{ServeDir, ServeFile};
 
-use crate::{geometry::{Transform2d, Twist2d}, paths::Path};
+use crate::{
+    behaviour::DriveRequest,
+    controllers::PathFollower,
+    geometry::{Transform2d, Twist2d},
+    mission::{MissionControl, MissionWaypoint},
+    zones::{Zone, ZoneEdit},
+};
 
 pub async fn start_web_server_thread() -> (WebsocketState, SocketIo) {
     let state = WebsocketState::new();
@@ -21,35 +27,111 @@ pub async fn start_web_server_thread() -> (WebsocketState, SocketIo) {
     (state, io)
 }
 
-pub enum DriveCommand {
-    TeleopVelocity(Twist2d),
-    PathfindToPosition(Transform2d),
-    FollowPath(Path)
-}
-
 #[derive(Serialize)]
 pub struct WsPoseGraphNode {
     pub tf: Transform2d,
     pub scan: Vec<[f64; 2]>
 }
 
+/// occupancy from 0 to 100 (255 for unknown) run length encoded as [value, count] pairs, row major starting at origin
+#[derive(Serialize)]
+pub struct WsOccupancyGrid {
+    pub origin: [f64; 2],
+    pub resolution_meters: f64,
+    pub width: usize,
+    pub height: usize,
+    pub runs: Vec<[u32; 2]>
+}
+
+/// a finished submap. its grid is in the submap frame, which sits at tf in the world.
+#[derive(Serialize)]
+pub struct WsSubmap {
+    pub index: usize,
+    pub tf: Transform2d,
+    pub grid: WsOccupancyGrid
+}
+
 #[derive(Clone)]
 pub struct WebsocketState {
-    pub cmd_vel: Arc<Mutex<DriveCommand>>
+    /// what the dashboard asked for since the last frame, taken by the main loop
+    pub drive_requests: Arc<Mutex<Vec<DriveRequest>>>,
+    /// set by the dashboard, cleared by the main loop once the map is written
+    pub save_map_requested: Arc<Mutex<bool>>,
+    /// what follows the next planned path
+    pub follower: Arc<Mutex<PathFollower>>,
+    /// keep-out and speed limit zone changes since the last frame, taken by the main loop
+    pub zone_edits: Arc<Mutex<Vec<ZoneEdit>>>,
+    /// world frame polygon ([x, y] vertices) to survey, taken by the main loop
+    pub coverage_request: Arc<Mutex<Option<Vec<[f64; 2]>>>>,
+    /// true to start exploring, false to stop, taken by the main loop
+    pub exploration_request: Arc<Mutex<Option<bool>>>,
+    /// set by the dashboard, cleared by the main loop once the e-stop is unlatched
+    pub clear_estop_requested: Arc<Mutex<bool>>,
+    /// set when a dashboard connects, cleared by the main loop once it sent the behaviour state and log
+    pub dashboard_connected: Arc<Mutex<bool>>,
 }
 
 impl WebsocketState {
     pub fn new() -> Self {
-        Self { cmd_vel: Arc::new(Mutex::new(DriveCommand::TeleopVelocity(Twist2d::ZERO))) }
+        Self {
+            drive_requests: Arc::new(Mutex::new(Vec::new())),
+            save_map_requested: Arc::new(Mutex::new(false)),
+            follower: Arc::new(Mutex::new(PathFollower::PurePursuit)),
+            zone_edits: Arc::new(Mutex::new(Vec::new())),
+            coverage_request: Arc::new(Mutex::new(None)),
+            exploration_request: Arc::new(Mutex::new(None)),
+            clear_estop_requested: Arc::new(Mutex::new(false)),
+            dashboard_connected: Arc::new(Mutex::new(false)),
+        }
     }
 }
 
 pub async fn handler(socket: SocketRef, state: State<WebsocketState>) {
     println!("new connection from {}", socket.id);
+    *state.dashboard_connected.lock().unwrap() = true;
     socket.on("driveWithSpeeds", move |socket: SocketRef, state: State<WebsocketState>, Data::<Vec<f64>>(data)| {
-        *state.cmd_vel.lock().unwrap() = DriveCommand::TeleopVelocity(Twist2d::new(data[0], data[1], data[2]));
+        state.drive_requests.lock().unwrap().push(DriveRequest::Teleop(Twist2d::new(data[0], data[1], data[2])));
     });
     socket.on("pathfindToPosition", move |socket: SocketRef, state: State<WebsocketState>, Data::<Transform2d>(data)| {
-        *state.cmd_vel.lock().unwrap() = DriveCommand::PathfindToPosition(data);
+        state.drive_requests.lock().unwrap().push(DriveRequest::PathfindTo(data));
+    });
+    socket.on("saveMap", move |state: State<WebsocketState>| {
+        *state.save_map_requested.lock().unwrap() = true;
+    });
+    socket.on("startMission", move |state: State<WebsocketState>, Data::<Vec<MissionWaypoint>>(data)| {
+        state.drive_requests.lock().unwrap().push(DriveRequest::StartMission(data));
+    });
+    socket.on("startCoverage", move |state: State<WebsocketState>, Data::<Vec<[f64; 2]>>(data)| {
+        *state.coverage_request.lock().unwrap() = Some(data);
+    });
+    socket.on("startExploration", move |state: State<WebsocketState>| {
+        *state.exploration_request.lock().unwrap() = Some(true);
+    });
+    socket.on("stopExploration", move |state: State<WebsocketState>| {
+        *state.exploration_request.lock().unwrap() = Some(false);
+    });
+    socket.on("pauseMission", move |state: State<WebsocketState>| {
+        state.drive_requests.lock().unwrap().push(DriveRequest::Mission(MissionControl::Pause));
+    });
+    socket.on("resumeMission", move |state: State<WebsocketState>| {
+        state.drive_requests.lock().unwrap().push(DriveRequest::Mission(MissionControl::Resume));
+    });
+    socket.on("skipWaypoint", move |state: State<WebsocketState>| {
+        state.drive_requests.lock().unwrap().push(DriveRequest::Mission(MissionControl::Skip));
+    });
+    socket.on("cancel", move |state: State<WebsocketState>| {
+        state.drive_requests.lock().unwrap().push(DriveRequest::Cancel);
+    });
+    socket.on("clearEStop", move |state: State<WebsocketState>| {
+        *state.clear_estop_requested.lock().unwrap() = true;
+    });
+    socket.on("addZone", move |state: State<WebsocketState>, Data::<Zone>(data)| {
+        state.zone_edits.lock().unwrap().push(ZoneEdit::Add(data));
+    });
+    socket.on("removeZone", move |state: State<WebsocketState>, Data::<usize>(data)| {
+        state.zone_edits.lock().unwrap().push(ZoneEdit::Remove(data));
+    });
+    socket.on("setFollower", move |state: State<WebsocketState>, Data::<PathFollower>(data)| {
+        *state.follower.lock().unwrap() = data;
     });
 }
\ No newline at end of file
@@ -0,0 +1,192 @@
+//! polygons in the world frame the robot must stay out of, or slow down in (stairs, doorways).
+//! they get saved next to the map, the planner avoids keep-out zones through the costmap and plans slower through speed zones,
+//! and limit() enforces both on whatever speeds end up getting commanded.
+
+use std::{fs, io, path::Path};
+
+use nalgebra::Vector2;
+use serde::{Deserialize, Serialize};
+
+use crate::geometry::{point_in_polygon, Transform2d, Twist2d};
+
+/// how far ahead limit() looks along the commanded speeds, so the robot slows down or stops before it's in a zone rather than after
+const LOOKAHEAD_SECONDS: f64 = 0.5;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum ZoneKind {
+    KeepOut,
+    SpeedLimit { max_speed_meters_per_second: f64 },
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Zone {
+    pub name: String,
+    pub kind: ZoneKind,
+    /// [x, y] vertices in the world frame
+    pub polygon: Vec<[f64; 2]>,
+}
+
+impl Zone {
+    pub fn vertices(&self) -> Vec<Vector2<f64>> {
+        self.polygon.iter().map(|&[x, y]| Vector2::new(x, y)).collect()
+    }
+
+    pub fn contains(&self, point: &Vector2<f64>) -> bool {
+        point_in_polygon(&self.vertices(), point)
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if self.polygon.len() < 3 {
+            return Err(format!("zone {:?} needs at least 3 vertices, it has {}", self.name, self.polygon.len()));
+        }
+        if self.polygon.iter().flatten().any(|coordinate| !coordinate.is_finite()) {
+            return Err(format!("zone {:?} has a vertex that isn't finite", self.name));
+        }
+        if let ZoneKind::SpeedLimit { max_speed_meters_per_second } = self.kind {
+            // a limit of zero would be a keep-out zone the robot can't even drive out of
+            if max_speed_meters_per_second.is_nan() || max_speed_meters_per_second <= 0.0 {
+                return Err(format!("zone {:?} has a bad speed limit {}", self.name, max_speed_meters_per_second));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// what the dashboard asked to change
+#[derive(Debug, Clone, Deserialize)]
+pub enum ZoneEdit {
+    Add(Zone),
+    /// index into zones
+    Remove(usize),
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Zones {
+    pub zones: Vec<Zone>,
+}
+
+impl Zones {
+    pub fn save(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        fs::write(path, serde_json::to_vec_pretty(self)?)
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        let zones: Self = serde_json::from_slice(&fs::read(path)?)?;
+        for zone in &zones.zones {
+            zone.validate().map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        }
+        Ok(zones)
+    }
+
+    /// returns why the edit was refused, if it was
+    pub fn apply(&mut self, edit: ZoneEdit) -> Result<(), String> {
+        match edit {
+            ZoneEdit::Add(zone) => {
+                zone.validate()?;
+                self.zones.push(zone);
+            }
+            ZoneEdit::Remove(index) => {
+                if index >= self.zones.len() {
+                    return Err(format!("there's no zone {}, only {}", index, self.zones.len()));
+                }
+                self.zones.remove(index);
+            }
+        }
+        Ok(())
+    }
+
+    /// for the costmap's keep-out layer
+    pub fn keep_out_polygons(&self) -> Vec<Vec<Vector2<f64>>> {
+        self.zones.iter().filter(|zone| zone.kind == ZoneKind::KeepOut).map(Zone::vertices).collect()
+    }
+
+    /// the lowest speed limit of the zones this point is in, infinity if it isn't in any
+    pub fn speed_limit_at(&self, point: &Vector2<f64>) -> f64 {
+        self.zones
+            .iter()
+            .filter_map(|zone| match zone.kind {
+                ZoneKind::SpeedLimit { max_speed_meters_per_second } if zone.contains(point) => Some(max_speed_meters_per_second),
+                _ => None,
+            })
+            .fold(f64::INFINITY, f64::min)
+    }
+
+    fn in_keep_out(&self, point: &Vector2<f64>) -> bool {
+        self.zones.iter().any(|zone| zone.kind == ZoneKind::KeepOut && zone.contains(point))
+    }
+
+    /// caps robot frame speeds at the pose the robot is at. turning in place is always allowed, but driving is cut if it would take
+    /// the robot into a keep-out zone. if it's already in one (someone drew a zone on top of it) it's allowed to drive out.
+    /// the turn rate gets scaled along with the speed so the robot still drives the same arc.
+    pub fn limit(&self, pose: &Transform2d, speeds: Twist2d) -> Twist2d {
+        if self.zones.is_empty() {
+            return speeds;
+        }
+        let here = Vector2::new(pose.x_meters, pose.y_meters);
+        let ahead = pose.clone() + Transform2d::from(speeds.clone() * LOOKAHEAD_SECONDS);
+        let ahead = Vector2::new(ahead.x_meters, ahead.y_meters);
+        if self.in_keep_out(&ahead) && !self.in_keep_out(&here) {
+            return Twist2d::new(0.0, 0.0, speeds.dtheta);
+        }
+        let max_speed = self.speed_limit_at(&here).min(self.speed_limit_at(&ahead));
+        let speed = speeds.dx.hypot(speeds.dy);
+        if speed <= max_speed {
+            return speeds;
+        }
+        speeds * (max_speed / speed)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::sim;
+    use assert_approx_eq::assert_approx_eq;
+
+    fn square(name: &str, kind: ZoneKind, min: f64, max: f64) -> Zone {
+        Zone { name: name.to_string(), kind, polygon: sim::square(min, max).iter().map(|vertex| [vertex[0], vertex[1]]).collect() }
+    }
+
+    #[test]
+    fn test_limit() {
+        let mut zones = Zones::default();
+        zones.apply(ZoneEdit::Add(square("stairs", ZoneKind::KeepOut, 1.0, 2.0))).unwrap();
+        zones.apply(ZoneEdit::Add(square("door", ZoneKind::SpeedLimit { max_speed_meters_per_second: 0.2 }, -2.0, -1.0))).unwrap();
+
+        // the stairs are 0.2m ahead, so only turning is left
+        let limited = zones.limit(&Transform2d::new(0.8, 1.5, 0.0), Twist2d::new(0.5, 0.0, 0.3));
+        assert_eq!((limited.dx, limited.dy, limited.dtheta), (0.0, 0.0, 0.3));
+        // driving away from them is fine
+        let limited = zones.limit(&Transform2d::new(0.75, 1.5, 0.0), Twist2d::new(-0.5, 0.0, 0.0));
+        assert_eq!(limited.dx, -0.5);
+        // and so is driving out of them
+        let limited = zones.limit(&Transform2d::new(1.5, 1.5, 0.0), Twist2d::new(0.5, 0.0, 0.0));
+        assert_eq!(limited.dx, 0.5);
+
+        // slowing down before the door, keeping the same arc
+        let limited = zones.limit(&Transform2d::new(-0.9, -1.5, std::f64::consts::PI), Twist2d::new(0.4, 0.0, 0.4));
+        assert_approx_eq!(limited.dx, 0.2, 1e-9);
+        assert_approx_eq!(limited.dtheta, 0.2, 1e-9);
+        assert_eq!(zones.limit(&Transform2d::new(-1.5, -1.5, 0.0), Twist2d::new(0.1, 0.0, 0.0)).dx, 0.1);
+
+        zones.apply(ZoneEdit::Remove(0)).unwrap();
+        assert_eq!(zones.limit(&Transform2d::new(0.75, 1.5, 0.0), Twist2d::new(0.5, 0.0, 0.0)).dx, 0.5);
+        assert!(zones.apply(ZoneEdit::Remove(1)).is_err());
+        assert!(zones.apply(ZoneEdit::Add(Zone { name: "line".to_string(), kind: ZoneKind::KeepOut, polygon: vec![[0.0, 0.0], [1.0, 0.0]] })).is_err());
+        assert!(zones.apply(ZoneEdit::Add(square("parked", ZoneKind::SpeedLimit { max_speed_meters_per_second: 0.0 }, 3.0, 4.0))).is_err());
+        assert!(zones.apply(ZoneEdit::Add(square("backwards", ZoneKind::SpeedLimit { max_speed_meters_per_second: -0.1 }, 3.0, 4.0))).is_err());
+    }
+
+    #[test]
+    fn test_save_and_load_zones() {
+        let zones = Zones { zones: vec![square("stairs", ZoneKind::KeepOut, 1.0, 2.0), square("door", ZoneKind::SpeedLimit { max_speed_meters_per_second: 0.2 }, -2.0, -1.0)] };
+        let path = std::env::temp_dir().join(format!("xavier-zones-{}.json", std::process::id()));
+        zones.save(&path).unwrap();
+        assert_eq!(Zones::load(&path).unwrap(), zones);
+        assert_eq!(zones.keep_out_polygons().len(), 1);
+
+        fs::write(&path, r#"{"zones": [{"name": "fast", "kind": {"SpeedLimit": {"max_speed_meters_per_second": -1.0}}, "polygon": [[0, 0], [1, 0], [1, 1]]}]}"#).unwrap();
+        assert_eq!(Zones::load(&path).err().map(|e| e.kind()), Some(io::ErrorKind::InvalidData));
+        fs::remove_file(&path).unwrap();
+    }
+}